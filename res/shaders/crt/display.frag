@@ -0,0 +1,71 @@
+#version 430
+precision mediump float;
+
+#define PI 3.14159265
+
+#define NO_MASK 0
+#define SHADOW_MASK 1
+#define APERTURE_GRILLE 2
+
+in layout(location = 2) vec2 uv;
+
+uniform vec2 content_size;
+uniform float curvature;
+uniform float scanlines;
+uniform float scanline_intensity;
+uniform int mask;
+uniform float mask_intensity;
+uniform float chromatic_aberration;
+uniform float vignette;
+
+uniform layout(binding = 0) sampler2D content_sampler;
+
+out vec4 color;
+
+// Push uvs outwards the further they are from the center, like a bulging tube
+vec2 barrel(vec2 p) {
+    vec2 centered = p * 2. - 1.;
+    centered *= 1. + curvature * dot(centered, centered);
+    return centered * .5 + .5;
+}
+
+vec3 phosphor_mask(vec2 pixel) {
+    if (mask == APERTURE_GRILLE) {
+        int stripe = int(mod(pixel.x * 3., 3.));
+        return vec3(stripe == 0, stripe == 1, stripe == 2);
+    } else if (mask == SHADOW_MASK) {
+        // Every other row of triads is shifted half a triad over
+        float row_offset = mod(floor(pixel.y * 2.), 2.) * 1.5;
+        int dot_index = int(mod(pixel.x * 3. + row_offset, 3.));
+        return vec3(dot_index == 0, dot_index == 1, dot_index == 2);
+    }
+    return vec3(1.);
+}
+
+void main() {
+    vec2 p = barrel(uv);
+    if (p.x < 0. || p.x > 1. || p.y < 0. || p.y > 1.) {
+        // Outside the curved picture area
+        color = vec4(0., 0., 0., 1.);
+        return;
+    }
+
+    // Red and blue land slightly off target, more so towards the edges
+    vec2 offset = (p - .5) * chromatic_aberration / content_size;
+    vec3 c = vec3(
+        texture(content_sampler, p + offset).r,
+        texture(content_sampler, p).g,
+        texture(content_sampler, p - offset).b
+    );
+
+    float scanline = sin(p.y * scanlines * PI);
+    c *= 1. - scanline_intensity * (1. - scanline * scanline);
+
+    vec2 pixel = p * vec2(scanlines * content_size.x / content_size.y, scanlines);
+    c *= mix(vec3(1.), phosphor_mask(pixel), mask_intensity);
+
+    vec2 centered = p * 2. - 1.;
+    c *= 1. - vignette * dot(centered, centered) * .5;
+
+    color = vec4(c, 1.);
+}
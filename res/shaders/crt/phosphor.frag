@@ -0,0 +1,18 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float persistence;
+
+uniform layout(binding = 0) sampler2D content_sampler;
+uniform layout(binding = 1) sampler2D previous_sampler;
+
+out vec4 color;
+
+void main() {
+    vec3 current = texture(content_sampler, uv).rgb;
+    vec3 previous = texture(previous_sampler, uv).rgb;
+    // Phosphors light up instantly but take a while to fade
+    color = vec4(max(current, previous * persistence), 1.);
+}
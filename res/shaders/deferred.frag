@@ -0,0 +1,66 @@
+#version 430
+precision highp float;
+
+#define AMBIENT_FACTOR 0.08
+
+#define NORMALS_MODE 2
+#define REFLECTION_VECTORS_MODE 3
+
+#include "lights.glsl"
+#include "environment.glsl"
+
+in layout(location = 2) vec2 uv;
+
+uniform mat4 inverse_view_projection;
+uniform vec3 camera_position;
+uniform int mode;
+uniform int use_ssao;
+
+uniform layout(binding = 0) sampler2D albedo_sampler;
+uniform layout(binding = 1) sampler2D normal_sampler;
+uniform layout(binding = 2) sampler2D material_sampler;
+uniform layout(binding = 3) sampler2D depth_sampler;
+uniform layout(binding = 6) sampler2D ao_sampler;
+
+out vec4 color;
+
+void main() {
+    float depth = texture(depth_sampler, uv).r;
+    if (depth >= 1.) {
+        color = vec4(0., 0., 0., 1.);
+        return;
+    }
+    // Reconstruct world position from depth
+    vec4 world = inverse_view_projection * vec4(uv * 2. - 1., depth * 2. - 1., 1.);
+    vec3 position = world.xyz / world.w;
+
+    vec3 diffuse_reflection = texture(albedo_sampler, uv).rgb;
+    vec3 normal = normalize(texture(normal_sampler, uv).xyz);
+    vec4 material = texture(material_sampler, uv);
+    float roughness = material.r;
+    vec3 cam_dir = normalize(camera_position - position);
+
+    float ambient_occlusion = 1.;
+    if (use_ssao == 1) {
+        ambient_occlusion = texture(ao_sampler, uv).r;
+    }
+    vec3 lighting = AMBIENT_FACTOR * ambient_occlusion * diffuse_reflection;
+    if (use_environment == 1) {
+        lighting = ambient_occlusion * environment_light(normal, cam_dir, diffuse_reflection, roughness);
+    }
+
+    // Same Phong model as world.frag, but for every light in the buffer
+    for (int i = 0; i < light_sources.length(); i++) {
+        lighting += shade_light(light_sources[i], position, normal, cam_dir, diffuse_reflection, roughness);
+    }
+    // The G-buffer only has room for the strength of the emission, so it takes the surface color
+    lighting += material.b * diffuse_reflection;
+
+    if (mode == NORMALS_MODE) {
+        color = vec4(normal*.5+.5, 1.);
+    } else if (mode == REFLECTION_VECTORS_MODE) {
+        color = vec4(reflect(-cam_dir, normal)*.5+.5, 1.);
+    } else {
+        color = vec4(lighting, 1.);
+    }
+}
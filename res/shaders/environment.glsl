@@ -0,0 +1,31 @@
+// Image-based lighting shared by the forward and deferred renderers
+
+// Must match the mip levels of the prefiltered map in environment.rs
+#define PREFILTERED_MIPS 5
+// Reflectance of non-metals at normal incidence
+#define DIELECTRIC_F0 0.04
+
+uniform int use_environment;
+uniform float environment_intensity;
+
+uniform layout(binding = 7) samplerCube irradiance_sampler;
+uniform layout(binding = 8) samplerCube prefiltered_sampler;
+uniform layout(binding = 9) sampler2D brdf_sampler;
+
+// Diffuse and glossy light from the environment,
+// using the split-sum approximation for the specular part
+vec3 environment_light(vec3 normal, vec3 cam_dir, vec3 albedo, float roughness) {
+    float n_dot_v = max(dot(normal, cam_dir), 0.);
+    float f0 = DIELECTRIC_F0;
+    // Fresnel with roughness, so rough surfaces at grazing angles don't turn into mirrors
+    float fresnel = f0 + (max(1. - roughness, f0) - f0) * pow(1. - n_dot_v, 5.);
+
+    vec3 diffuse = texture(irradiance_sampler, normal).rgb * albedo;
+
+    vec3 reflection_dir = reflect(-cam_dir, normal);
+    vec3 prefiltered = textureLod(prefiltered_sampler, reflection_dir, roughness * (PREFILTERED_MIPS - 1)).rgb;
+    vec2 brdf = texture(brdf_sampler, vec2(n_dot_v, roughness)).rg;
+    vec3 specular = prefiltered * (fresnel * brdf.x + brdf.y);
+
+    return environment_intensity * ((1. - fresnel) * diffuse + specular);
+}
@@ -0,0 +1,66 @@
+#version 430
+precision mediump float;
+
+// Default shininess of 32 expressed as roughness (shininess = 5 / roughness^2)
+#define DEFAULT_ROUGHNESS 0.395
+
+in layout(location = 0) vec3 position;
+in layout(location = 1) vec3 normal_in;
+in layout(location = 2) vec2 uv;
+in layout(location = 3) vec4 color_in;
+in layout(location = 4) mat3 TBN;
+
+uniform int use_texture;
+uniform int use_reflection;
+uniform int use_normals;
+uniform int use_roughness;
+uniform int use_opacity;
+uniform int blend_mode;
+uniform float alpha_cutoff;
+uniform vec3 emission;
+
+uniform layout(binding = 0) sampler2D texture_sampler;
+uniform layout(binding = 2) sampler2D normal_sampler;
+uniform layout(binding = 3) sampler2D roughness_sampler;
+uniform layout(binding = 4) sampler2D opacity_sampler;
+
+layout(location = 0) out vec4 albedo;
+layout(location = 1) out vec4 normal_out;
+layout(location = 2) out vec4 material;
+
+void main() {
+    if (use_reflection == 1) {
+        // Screens need their own reflection textures, so they are drawn in a forward pass afterwards
+        discard;
+    }
+    // Same flip as in world.frag
+    vec2 flipped_uv = vec2(uv.x, 2. - uv.y);
+
+    float opacity = 1.;
+    if (use_opacity == 1) {
+        opacity = texture(opacity_sampler, flipped_uv).r;
+    }
+    // Only opaque and cutout geometry ends up here (see BlendMode in graph.rs)
+    if (blend_mode == 1 && opacity < alpha_cutoff) {
+        discard;
+    }
+
+    if (use_texture == 1) {
+        albedo = vec4(texture(texture_sampler, flipped_uv).rgb, 1.);
+    } else {
+        albedo = vec4(color_in.rgb, 1.);
+    }
+
+    vec3 normal = normalize(normal_in);
+    if (use_normals == 1) {
+        normal = normalize(TBN * (2*vec3(texture(normal_sampler, flipped_uv)) - 1));
+    }
+    float roughness = DEFAULT_ROUGHNESS;
+    if (use_roughness == 1) {
+        roughness = texture(roughness_sampler, flipped_uv).r;
+    }
+    // Roughness goes with the normals too, for screen-space reflections
+    normal_out = vec4(normal, roughness);
+    // Nothing in the scene is metallic, and emission is only kept as a strength
+    material = vec4(roughness, 0., max(emission.r, max(emission.g, emission.b)), 1.);
+}
@@ -0,0 +1,43 @@
+#version 430
+precision highp float;
+
+#include "common.glsl"
+
+#define SAMPLE_COUNT 1024u
+
+in layout(location = 2) vec2 uv;
+
+out vec4 color;
+
+float geometry_schlick_ggx(float n_dot_v, float roughness) {
+    // Remapped for image-based lighting
+    float k = roughness * roughness / 2.;
+    return n_dot_v / (n_dot_v * (1. - k) + k);
+}
+
+// Scale and bias to F0 for the split-sum approximation,
+// with the angle between normal and view along u and roughness along v
+void main() {
+    float n_dot_v = max(uv.x, .001);
+    float roughness = uv.y;
+    vec3 view = vec3(sqrt(1. - n_dot_v * n_dot_v), 0., n_dot_v);
+    vec3 normal = vec3(0., 0., 1.);
+
+    float scale = 0.;
+    float bias = 0.;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 h = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), normal, roughness);
+        vec3 l = normalize(2. * dot(view, h) * h - view);
+        float n_dot_l = max(l.z, 0.);
+        float n_dot_h = max(h.z, 0.);
+        float v_dot_h = max(dot(view, h), 0.);
+        if (n_dot_l > 0.) {
+            float g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            float g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            float fresnel = pow(1. - v_dot_h, 5.);
+            scale += (1. - fresnel) * g_vis;
+            bias += fresnel * g_vis;
+        }
+    }
+    color = vec4(scale / float(SAMPLE_COUNT), bias / float(SAMPLE_COUNT), 0., 1.);
+}
@@ -0,0 +1,46 @@
+#define PI 3.14159265
+
+// Direction through a texel of a cubemap face, with uv in 0..1 over the face
+vec3 cube_direction(int face, vec2 uv) {
+    vec2 st = uv * 2. - 1.;
+    vec3 directions[6] = vec3[](
+        vec3(1., -st.y, -st.x),
+        vec3(-1., -st.y, st.x),
+        vec3(st.x, 1., st.y),
+        vec3(st.x, -1., -st.y),
+        vec3(st.x, -st.y, 1.),
+        vec3(-st.x, -st.y, -1.)
+    );
+    return normalize(directions[face]);
+}
+
+// Low-discrepancy points for sampling
+vec2 hammersley(uint i, uint n) {
+    uint bits = i;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return vec2(float(i) / float(n), float(bits) * 2.3283064365386963e-10);
+}
+
+// Half vector around the normal, distributed like the GGX lobe for the given roughness
+vec3 importance_sample_ggx(vec2 xi, vec3 normal, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2. * PI * xi.x;
+    float cos_theta = sqrt((1. - xi.y) / (1. + (a*a - 1.) * xi.y));
+    float sin_theta = sqrt(1. - cos_theta * cos_theta);
+    vec3 h = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    vec3 up = abs(normal.z) < .999 ? vec3(0., 0., 1.) : vec3(1., 0., 0.);
+    vec3 tangent = normalize(cross(up, normal));
+    vec3 bitangent = cross(normal, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float d = n_dot_h * n_dot_h * (a*a - 1.) + 1.;
+    return a*a / (PI * d * d);
+}
@@ -0,0 +1,19 @@
+#version 430
+precision highp float;
+
+#include "common.glsl"
+
+in layout(location = 2) vec2 uv;
+
+uniform int face;
+
+uniform layout(binding = 0) sampler2D equirectangular_sampler;
+
+out vec4 color;
+
+void main() {
+    vec3 direction = cube_direction(face, uv);
+    // Image rows go from the top down
+    vec2 map_uv = vec2(atan(direction.z, direction.x) / (2. * PI) + .5, .5 - asin(direction.y) / PI);
+    color = vec4(texture(equirectangular_sampler, map_uv).rgb, 1.);
+}
@@ -0,0 +1,35 @@
+#version 430
+precision highp float;
+
+#include "common.glsl"
+
+#define SAMPLE_DELTA 0.025
+
+in layout(location = 2) vec2 uv;
+
+uniform int face;
+
+uniform layout(binding = 0) samplerCube environment_sampler;
+
+out vec4 color;
+
+// Cosine-weighted average of the environment over the hemisphere around each direction
+void main() {
+    vec3 normal = cube_direction(face, uv);
+    vec3 up = abs(normal.y) < .999 ? vec3(0., 1., 0.) : vec3(0., 0., 1.);
+    vec3 right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    vec3 irradiance = vec3(0);
+    float samples = 0.;
+    for (float phi = 0.; phi < 2. * PI; phi += SAMPLE_DELTA) {
+        for (float theta = 0.; theta < .5 * PI; theta += SAMPLE_DELTA) {
+            vec3 tangent_sample = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 direction = tangent_sample.x * right + tangent_sample.y * up + tangent_sample.z * normal;
+            // Sample a blurrier mip to avoid missing small bright spots
+            irradiance += textureLod(environment_sampler, direction, 3.).rgb * cos(theta) * sin(theta);
+            samples++;
+        }
+    }
+    color = vec4(PI * irradiance / samples, 1.);
+}
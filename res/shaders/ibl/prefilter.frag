@@ -0,0 +1,43 @@
+#version 430
+precision highp float;
+
+#include "common.glsl"
+
+#define SAMPLE_COUNT 256u
+
+in layout(location = 2) vec2 uv;
+
+uniform int face;
+uniform float roughness;
+// Size of one face of the source environment
+uniform float resolution;
+
+uniform layout(binding = 0) samplerCube environment_sampler;
+
+out vec4 color;
+
+// Environment convolved with the GGX lobe, assuming the view direction equals the normal
+void main() {
+    vec3 normal = cube_direction(face, uv);
+    vec3 view = normal;
+
+    vec3 prefiltered = vec3(0);
+    float total_weight = 0.;
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec3 h = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), normal, roughness);
+        vec3 l = normalize(2. * dot(view, h) * h - view);
+        float n_dot_l = dot(normal, l);
+        if (n_dot_l > 0.) {
+            // Pick a mip matching the area covered by the sample, against fireflies
+            float n_dot_h = max(dot(normal, h), 0.);
+            float pdf = distribution_ggx(n_dot_h, roughness) / 4. + .0001;
+            float texel_angle = 4. * PI / (6. * resolution * resolution);
+            float sample_angle = 1. / (float(SAMPLE_COUNT) * pdf + .0001);
+            float level = roughness == 0. ? 0. : .5 * log2(sample_angle / texel_angle);
+
+            prefiltered += textureLod(environment_sampler, l, level).rgb * n_dot_l;
+            total_weight += n_dot_l;
+        }
+    }
+    color = vec4(prefiltered / total_weight, 1.);
+}
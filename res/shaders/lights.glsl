@@ -0,0 +1,146 @@
+// Light sources shared by the forward and deferred renderers
+
+#define POINT_LIGHT 0
+#define SPOT_LIGHT 1
+#define DIRECTIONAL_LIGHT 2
+#define AREA_LIGHT 3
+
+#define PI 3.14159265
+
+// Must match GpuLight in light.rs
+struct LightSource {
+    vec4 position;  // w is the range, 0 meaning unlimited
+    vec4 color;     // already multiplied by intensity, w is the kind of light
+    vec4 direction; // w is the cosine of the outer spot angle
+    vec4 tangent;   // w is the cosine of the inner spot angle
+    vec4 size;      // half width and height of area lights
+};
+
+layout(std430, binding = 0) buffer LightBuffer {
+    LightSource light_sources[];
+};
+
+// Must match LTC_SIZE in ltc.rs
+#define LTC_SIZE 64.
+
+// Fitted by ltc.rs: the inverse transformation matrices, and the magnitudes and Fresnel terms
+uniform layout(binding = 10) sampler2D ltc_matrix_sampler;
+uniform layout(binding = 11) sampler2D ltc_amplitude_sampler;
+
+// Smoothly brings the light to zero at its range, so it can be culled there
+float range_window(float d, float range) {
+    if (range <= 0.) {
+        return 1.;
+    }
+    float window = clamp(1. - pow(d / range, 4.), 0., 1.);
+    return window * window;
+}
+
+// Contribution of one polygon edge to the vector irradiance
+vec3 edge_integral(vec3 v1, vec3 v2) {
+    float c = clamp(dot(v1, v2), -0.9999, 0.9999);
+    float theta = acos(c);
+    return cross(v1, v2) * theta / sin(theta);
+}
+
+// Integral of a clamped cosine over a quad given relative to the shading point,
+// in a frame where the normal is +z, after transforming the quad by `m`.
+// The quad is clipped to the upper hemisphere first, where the cosine is not zero.
+float ltc_integral(mat3 m, vec3 quad[4]) {
+    // Clipping a convex quad by a plane leaves at most five corners
+    vec3 clipped[5];
+    int count = 0;
+    for (int i = 0; i < 4; i++) {
+        vec3 a = m * quad[i];
+        vec3 b = m * quad[(i + 1) % 4];
+        if (a.z > 0.) {
+            clipped[count++] = a;
+        }
+        if ((a.z > 0.) != (b.z > 0.)) {
+            clipped[count++] = mix(a, b, a.z / (a.z - b.z));
+        }
+    }
+    float sum = 0.;
+    for (int i = 0; i < count; i++) {
+        sum += edge_integral(normalize(clipped[i]), normalize(clipped[(i + 1) % count])).z;
+    }
+    // The winding depends on which side the quad is seen from
+    return abs(sum) / (2. * PI);
+}
+
+// Phong lighting from a single light of any kind.
+// Area lights are integrated exactly over the rectangle, using a clamped cosine for the
+// diffuse part and linearly transformed cosines fitted to GGX for the specular part.
+vec3 shade_light(LightSource light, vec3 position, vec3 normal, vec3 cam_dir, vec3 albedo, float roughness) {
+    int kind = int(light.color.w);
+    vec3 light_color = light.color.rgb;
+    // Specular reflection is independent of surface color!
+    vec3 specular_reflection = vec3(1.0, 1.0, 1.0);
+
+    if (kind == AREA_LIGHT) {
+        vec3 center = light.position.xyz;
+        vec3 facing = light.direction.xyz;
+        // Only the front of the rectangle emits
+        if (dot(position - center, facing) <= 0.) {
+            return vec3(0);
+        }
+        vec3 right = light.tangent.xyz * light.size.x;
+        vec3 up = normalize(cross(facing, light.tangent.xyz)) * light.size.y;
+
+        // Frame around the normal with the view direction in the xz-plane, as the tables expect.
+        // Looking straight down the normal the lobe is round, so any tangent will do.
+        vec3 tangent = cam_dir - normal * dot(cam_dir, normal);
+        if (dot(tangent, tangent) < 1e-8) {
+            tangent = cross(normal, abs(normal.x) < 0.9 ? vec3(1, 0, 0) : vec3(0, 1, 0));
+        }
+        tangent = normalize(tangent);
+        mat3 to_frame = transpose(mat3(tangent, cross(normal, tangent), normal));
+        vec3 quad[4] = vec3[](
+            to_frame * (center - right - up - position),
+            to_frame * (center + right - up - position),
+            to_frame * (center + right + up - position),
+            to_frame * (center - right + up - position)
+        );
+
+        float n_dot_v = clamp(dot(normal, cam_dir), 0., 1.);
+        vec2 ltc_uv = (vec2(roughness, sqrt(1. - n_dot_v)) * (LTC_SIZE - 1.) + 0.5) / LTC_SIZE;
+        vec4 inverse_terms = texture(ltc_matrix_sampler, ltc_uv);
+        vec4 amplitudes = texture(ltc_amplitude_sampler, ltc_uv);
+        mat3 inverse_matrix = mat3(
+            vec3(inverse_terms.x, 0, inverse_terms.y),
+            vec3(0, 1, 0),
+            vec3(inverse_terms.z, 0, inverse_terms.w)
+        );
+
+        float diffuse = ltc_integral(mat3(1), quad);
+        // Scaled by the albedo of the BRDF and split by Fresnel
+        vec3 specular = ltc_integral(inverse_matrix, quad)
+            * (specular_reflection * amplitudes.x + (1. - specular_reflection) * amplitudes.y);
+
+        float window = range_window(length(center - position), light.position.w);
+        return window * (diffuse * albedo + specular) * light_color;
+    }
+
+    float shininess = 5. / (roughness * roughness);
+    vec3 light_dir;
+    float L;
+    if (kind == DIRECTIONAL_LIGHT) {
+        light_dir = -light.direction.xyz;
+        L = 1.;
+    } else {
+        vec3 to_light = light.position.xyz - position;
+        float d = length(to_light);
+        light_dir = to_light / d;
+        // Inverse square falloff, cut off at the range
+        L = range_window(d, light.position.w) / max(d*d, 0.01);
+        if (kind == SPOT_LIGHT) {
+            L *= smoothstep(light.direction.w, light.tangent.w, dot(-light_dir, light.direction.xyz));
+        }
+    }
+
+    // Phong model
+    vec3 reflection_dir = reflect(-light_dir, normal);
+    float diffuse_factor = L * max(0, dot(light_dir, normal));
+    float specular_factor = L * pow(max(0, dot(reflection_dir, cam_dir)), shininess);
+    return diffuse_factor * albedo * light_color + specular_factor * specular_reflection * light_color;
+}
@@ -0,0 +1,21 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform layout(binding = 0) sampler2D accumulation_sampler;
+uniform layout(binding = 1) sampler2D revealage_sampler;
+
+out vec4 color;
+
+// Resolve weighted blended transparency over the opaque image
+void main() {
+    float revealage = texture(revealage_sampler, uv).r;
+    if (revealage == 1.) {
+        // Nothing transparent here
+        discard;
+    }
+    vec4 accumulation = texture(accumulation_sampler, uv);
+    vec3 average = accumulation.rgb / max(accumulation.a, 1e-5);
+    color = vec4(average, 1. - revealage);
+}
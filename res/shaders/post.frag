@@ -7,6 +7,7 @@ in layout(location = 2) vec2 uv;
 uniform vec3 camera_position;
 
 uniform int mode;
+uniform float crt_strength;
 
 #define STANDARD_MODE 0
 
@@ -24,7 +25,7 @@ void main() {
     float crt_depth = texture(crt_depth_sampler, uv).r;
     // Perform manual depth test to see if crt should contribute to color
     if (crt_depth <= depth && mode == STANDARD_MODE) {
-        pre_color = pre_color + 0.5 * crt;
+        pre_color = pre_color + crt_strength * crt;
     }
     color = vec4(pre_color, 1.);
 }
@@ -0,0 +1,17 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float intensity;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+uniform layout(binding = 1) sampler2D bloom_sampler;
+
+out vec4 color;
+
+void main() {
+    vec3 scene = texture(color_sampler, uv).rgb;
+    vec3 bloom = texture(bloom_sampler, uv).rgb;
+    color = vec4(scene + intensity * bloom, 1.);
+}
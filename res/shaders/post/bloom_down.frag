@@ -0,0 +1,52 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform vec2 source_size;
+uniform int prefilter;
+uniform float threshold;
+uniform float knee;
+
+uniform layout(binding = 0) sampler2D source_sampler;
+
+out vec4 color;
+
+// Quadratic curve around the threshold to avoid a hard cutoff
+vec3 soft_threshold(vec3 c) {
+    float brightness = max(c.r, max(c.g, c.b));
+    float soft = clamp(brightness - threshold + knee, 0., 2. * knee);
+    soft = soft * soft / (4. * knee + 1e-4);
+    return c * max(soft, brightness - threshold) / max(brightness, 1e-4);
+}
+
+vec3 tap(vec2 offset) {
+    return texture(source_sampler, uv + offset / source_size).rgb;
+}
+
+void main() {
+    // 13-tap filter from Jimenez' "Next Generation Post Processing in Call of Duty"
+    vec3 a = tap(vec2(-2., 2.));
+    vec3 b = tap(vec2(0., 2.));
+    vec3 c = tap(vec2(2., 2.));
+    vec3 d = tap(vec2(-2., 0.));
+    vec3 e = tap(vec2(0., 0.));
+    vec3 f = tap(vec2(2., 0.));
+    vec3 g = tap(vec2(-2., -2.));
+    vec3 h = tap(vec2(0., -2.));
+    vec3 i = tap(vec2(2., -2.));
+    vec3 j = tap(vec2(-1., 1.));
+    vec3 k = tap(vec2(1., 1.));
+    vec3 l = tap(vec2(-1., -1.));
+    vec3 m = tap(vec2(1., -1.));
+
+    vec3 result = e * .125
+        + (a + c + g + i) * .03125
+        + (b + d + f + h) * .0625
+        + (j + k + l + m) * .125;
+
+    if (prefilter == 1) {
+        result = soft_threshold(result);
+    }
+    color = vec4(result, 1.);
+}
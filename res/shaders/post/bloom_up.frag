@@ -0,0 +1,23 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform vec2 source_size;
+uniform float radius;
+
+uniform layout(binding = 0) sampler2D source_sampler;
+
+out vec4 color;
+
+vec3 tap(vec2 offset) {
+    return texture(source_sampler, uv + radius * offset / source_size).rgb;
+}
+
+void main() {
+    // 3x3 tent filter
+    vec3 result = tap(vec2(0., 0.)) * 4.
+        + (tap(vec2(-1., 0.)) + tap(vec2(1., 0.)) + tap(vec2(0., -1.)) + tap(vec2(0., 1.))) * 2.
+        + (tap(vec2(-1., -1.)) + tap(vec2(1., -1.)) + tap(vec2(-1., 1.)) + tap(vec2(1., 1.)));
+    color = vec4(result / 16., 1.);
+}
@@ -0,0 +1,12 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+void main() {
+    color = vec4(texture(color_sampler, uv).rgb, 1.);
+}
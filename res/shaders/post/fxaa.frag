@@ -0,0 +1,61 @@
+#version 430
+precision mediump float;
+
+// Fast approximate anti-aliasing, after Timothy Lottes' FXAA
+
+#define REDUCE_MIN (1. / 128.)
+#define REDUCE_MUL (1. / 8.)
+
+in layout(location = 2) vec2 uv;
+
+uniform vec2 screen_size;
+uniform float span_max;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+const vec3 LUMA = vec3(0.299, 0.587, 0.114);
+
+void main() {
+    vec2 texel = 1. / screen_size;
+    vec3 rgb_nw = texture(color_sampler, uv + vec2(-1., -1.) * texel).rgb;
+    vec3 rgb_ne = texture(color_sampler, uv + vec2(1., -1.) * texel).rgb;
+    vec3 rgb_sw = texture(color_sampler, uv + vec2(-1., 1.) * texel).rgb;
+    vec3 rgb_se = texture(color_sampler, uv + vec2(1., 1.) * texel).rgb;
+    vec3 rgb_m = texture(color_sampler, uv).rgb;
+
+    float luma_nw = dot(rgb_nw, LUMA);
+    float luma_ne = dot(rgb_ne, LUMA);
+    float luma_sw = dot(rgb_sw, LUMA);
+    float luma_se = dot(rgb_se, LUMA);
+    float luma_m = dot(rgb_m, LUMA);
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Blur along the edge, which runs perpendicular to the luma gradient
+    vec2 dir = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * .25 * REDUCE_MUL, REDUCE_MIN);
+    float inverse_dir_min = 1. / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * inverse_dir_min, -span_max, span_max) * texel;
+
+    vec3 rgb_a = .5 * (
+        texture(color_sampler, uv + dir * (1. / 3. - .5)).rgb
+        + texture(color_sampler, uv + dir * (2. / 3. - .5)).rgb
+    );
+    vec3 rgb_b = rgb_a * .5 + .25 * (
+        texture(color_sampler, uv - dir * .5).rgb
+        + texture(color_sampler, uv + dir * .5).rgb
+    );
+    float luma_b = dot(rgb_b, LUMA);
+
+    // The wider blur went past the edge if it leaves the local luma range
+    if (luma_b < luma_min || luma_b > luma_max) {
+        color = vec4(rgb_a, 1.);
+    } else {
+        color = vec4(rgb_b, 1.);
+    }
+}
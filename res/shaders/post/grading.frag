@@ -0,0 +1,22 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float contrast;
+uniform float saturation;
+uniform vec3 tint;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+void main() {
+    vec3 c = texture(color_sampler, uv).rgb * tint;
+    // Pivot contrast around middle grey
+    c = (c - .5) * contrast + .5;
+    // Mix towards or away from luminance
+    float luminance = dot(c, vec3(0.2126, 0.7152, 0.0722));
+    c = mix(vec3(luminance), c, saturation);
+    color = vec4(max(c, 0.), 1.);
+}
@@ -0,0 +1,23 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float amount;
+uniform float time;
+uniform vec2 screen_size;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+float hash(vec2 p) {
+    return fract(sin(dot(p, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+void main() {
+    vec3 c = texture(color_sampler, uv).rgb;
+    // New noise every frame, one sample per pixel
+    float noise = hash(floor(uv * screen_size) + fract(time) * 100.) - .5;
+    color = vec4(c + noise * amount, 1.);
+}
@@ -0,0 +1,59 @@
+#version 430
+precision highp float;
+
+#define KERNEL_SIZE 32
+
+in layout(location = 2) vec2 uv;
+
+uniform mat4 projection;
+uniform mat4 inverse_projection;
+uniform mat4 view;
+uniform vec3 kernel[KERNEL_SIZE];
+uniform float radius;
+uniform float bias;
+uniform float power;
+uniform vec2 noise_scale;
+
+uniform layout(binding = 0) sampler2D depth_sampler;
+uniform layout(binding = 1) sampler2D normal_sampler;
+uniform layout(binding = 2) sampler2D noise_sampler;
+
+out vec4 color;
+
+// Reconstruct view-space position from the depth buffer
+vec3 view_position(vec2 p) {
+    float depth = texture(depth_sampler, p).r;
+    vec4 ndc = vec4(p * 2. - 1., depth * 2. - 1., 1.);
+    vec4 position = inverse_projection * ndc;
+    return position.xyz / position.w;
+}
+
+void main() {
+    float depth = texture(depth_sampler, uv).r;
+    if (depth >= 1.) {
+        // Nothing here but the void
+        color = vec4(1.);
+        return;
+    }
+    vec3 position = view_position(uv);
+    vec3 normal = normalize(mat3(view) * texture(normal_sampler, uv).xyz);
+
+    // Tilt the kernel hemisphere along the normal, rotated randomly around it
+    vec3 random = vec3(texture(noise_sampler, uv * noise_scale).xy, 0.);
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    vec3 bitangent = cross(normal, tangent);
+    mat3 TBN = mat3(tangent, bitangent, normal);
+
+    float occlusion = 0.;
+    for (int i = 0; i < KERNEL_SIZE; i++) {
+        vec3 sample_position = position + TBN * kernel[i] * radius;
+        vec4 offset = projection * vec4(sample_position, 1.);
+        vec2 sample_uv = offset.xy / offset.w * .5 + .5;
+        float sample_depth = view_position(sample_uv).z;
+        // Fade out occluders that are far away from the point in question
+        float range = smoothstep(0., 1., radius / abs(position.z - sample_depth));
+        occlusion += (sample_depth >= sample_position.z + bias ? 1. : 0.) * range;
+    }
+    float ao = pow(1. - occlusion / KERNEL_SIZE, power);
+    color = vec4(ao, ao, ao, 1.);
+}
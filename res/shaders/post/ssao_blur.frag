@@ -0,0 +1,23 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform vec2 screen_size;
+
+uniform layout(binding = 0) sampler2D occlusion_sampler;
+
+out vec4 color;
+
+void main() {
+    // Box blur the size of the 4x4 noise tile
+    vec2 texel = 1. / screen_size;
+    float result = 0.;
+    for (int x = -2; x < 2; x++) {
+        for (int y = -2; y < 2; y++) {
+            result += texture(occlusion_sampler, uv + vec2(x, y) * texel).r;
+        }
+    }
+    result /= 16.;
+    color = vec4(result, result, result, 1.);
+}
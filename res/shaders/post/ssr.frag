@@ -0,0 +1,152 @@
+#version 430
+precision highp float;
+
+#define MAX_STEPS 64
+#define REFINEMENT_STEPS 6
+// Must match MAX_PROBES in ssr.rs
+#define MAX_PROBES 4
+// Reflectance of non-metals at normal incidence
+#define DIELECTRIC_F0 0.04
+
+in layout(location = 2) vec2 uv;
+
+uniform mat4 projection;
+uniform mat4 inverse_projection;
+uniform mat4 view;
+uniform mat4 inverse_view;
+
+uniform float max_distance;
+uniform float thickness;
+uniform float max_roughness;
+uniform float strength;
+
+// Reflection probes near the camera, sorted by distance to it
+uniform int probe_count;
+uniform vec3 probe_positions[MAX_PROBES];
+uniform float probe_range;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+uniform layout(binding = 1) sampler2D depth_sampler;
+// World-space normals, with roughness in alpha
+uniform layout(binding = 2) sampler2D normal_sampler;
+// Units 3 to 6, below the ones that stay bound for the lighting from 7 on
+uniform layout(binding = 3) samplerCube probe_samplers[MAX_PROBES];
+
+out vec4 color;
+
+vec3 view_position(vec2 p) {
+    float depth = texture(depth_sampler, p).r;
+    vec4 ndc = vec4(p * 2. - 1., depth * 2. - 1., 1.);
+    vec4 position = inverse_projection * ndc;
+    return position.xyz / position.w;
+}
+
+vec2 project(vec3 position) {
+    vec4 clip = projection * vec4(position, 1.);
+    return clip.xy / clip.w * .5 + .5;
+}
+
+void main() {
+    vec3 scene = texture(color_sampler, uv).rgb;
+    vec4 normal_roughness = texture(normal_sampler, uv);
+    float roughness = normal_roughness.a;
+    // (no normal means the G-buffer has nothing there, like behind the screens)
+    bool no_normal = dot(normal_roughness.xyz, normal_roughness.xyz) < .25;
+    if (texture(depth_sampler, uv).r >= 1. || no_normal || roughness > max_roughness) {
+        color = vec4(scene, 1.);
+        return;
+    }
+
+    vec3 position = view_position(uv);
+    vec3 normal = normalize(mat3(view) * normal_roughness.xyz);
+    vec3 view_dir = normalize(position);
+    vec3 ray = normalize(reflect(view_dir, normal));
+
+    // March along the reflected ray until it passes behind something in the depth buffer,
+    // then narrow down where exactly it crossed
+    float step_size = max_distance / MAX_STEPS;
+    vec3 sample_position = position + normal * .01;
+    vec2 hit_uv = vec2(-1.);
+    for (int i = 0; i < MAX_STEPS; i++) {
+        sample_position += ray * step_size;
+        vec2 sample_uv = project(sample_position);
+        if (any(lessThan(sample_uv, vec2(0.))) || any(greaterThan(sample_uv, vec2(1.)))) {
+            break;
+        }
+        float difference = view_position(sample_uv).z - sample_position.z;
+        if (difference > 0. && difference < thickness) {
+            vec3 step = ray * step_size;
+            for (int j = 0; j < REFINEMENT_STEPS; j++) {
+                step *= .5;
+                sample_uv = project(sample_position);
+                if (view_position(sample_uv).z - sample_position.z > 0.) {
+                    sample_position -= step;
+                } else {
+                    sample_position += step;
+                }
+            }
+            hit_uv = project(sample_position);
+            break;
+        }
+    }
+
+    vec3 reflection = vec3(0.);
+    float confidence = 0.;
+    if (hit_uv.x >= 0.) {
+        reflection = texture(color_sampler, hit_uv).rgb;
+        // Fade near the edges of the screen, where the information runs out,
+        // and for rays coming back towards the camera, which rarely hit anything visible
+        vec2 edge = smoothstep(0., .1, hit_uv) * smoothstep(0., .1, 1. - hit_uv);
+        confidence = edge.x * edge.y
+            * (1. - smoothstep(.5, 1., ray.z))
+            * (1. - length(sample_position - position) / max_distance);
+        confidence = clamp(confidence, 0., 1.);
+    }
+    if (probe_count > 0) {
+        // Make up for misses with the two probes nearest this point, blended by distance,
+        // and trusted less the further away they are
+        vec3 world_position = (inverse_view * vec4(position, 1.)).xyz;
+        int nearest = 0;
+        int second = -1;
+        for (int i = 1; i < probe_count; i++) {
+            float d = distance(world_position, probe_positions[i]);
+            if (d < distance(world_position, probe_positions[nearest])) {
+                second = nearest;
+                nearest = i;
+            } else if (second < 0 || d < distance(world_position, probe_positions[second])) {
+                second = i;
+            }
+        }
+        float nearest_weight = max(1. - distance(world_position, probe_positions[nearest]) / probe_range, 0.);
+        float second_weight = 0.;
+        if (second >= 0) {
+            second_weight = max(1. - distance(world_position, probe_positions[second]) / probe_range, 0.);
+        }
+
+        vec3 world_ray = mat3(inverse_view) * ray;
+        vec3 probe_reflection = vec3(0.);
+        // Sampler arrays can only be indexed by values shared by all pixels, like the loop counter
+        for (int i = 0; i < probe_count; i++) {
+            float weight = i == nearest ? nearest_weight : i == second ? second_weight : 0.;
+            if (weight > 0.) {
+                probe_reflection += weight * textureLod(probe_samplers[i], world_ray, 0.).rgb;
+            }
+        }
+        float probe_weight = nearest_weight;
+        if (nearest_weight + second_weight > 0.) {
+            probe_reflection /= nearest_weight + second_weight;
+        }
+
+        // The probes fill in whatever the ray march is unsure about
+        float combined = confidence + (1. - confidence) * probe_weight;
+        if (combined > 0.) {
+            reflection = (reflection * confidence + probe_reflection * (1. - confidence) * probe_weight) / combined;
+        }
+        confidence = combined;
+    }
+
+    float n_dot_v = max(dot(normal, -view_dir), 0.);
+    float fresnel = DIELECTRIC_F0 + (1. - DIELECTRIC_F0) * pow(1. - n_dot_v, 5.);
+    float glossiness = 1. - smoothstep(0., max_roughness, roughness);
+    color = vec4(scene + reflection * confidence * fresnel * glossiness * strength, 1.);
+}
@@ -0,0 +1,21 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float exposure;
+uniform float gamma;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+// Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0., 1.);
+}
+
+void main() {
+    vec3 hdr = texture(color_sampler, uv).rgb * exposure;
+    color = vec4(pow(aces(hdr), vec3(1. / gamma)), 1.);
+}
@@ -0,0 +1,20 @@
+#version 430
+precision mediump float;
+
+in layout(location = 2) vec2 uv;
+
+uniform float strength;
+uniform float radius;
+uniform vec2 screen_size;
+
+uniform layout(binding = 0) sampler2D color_sampler;
+
+out vec4 color;
+
+void main() {
+    vec3 c = texture(color_sampler, uv).rgb;
+    // Correct for aspect ratio so the vignette stays round
+    vec2 p = (uv - .5) * vec2(screen_size.x / screen_size.y, 1.);
+    float falloff = smoothstep(radius, radius * .4, length(p));
+    color = vec4(c * mix(1. - strength, 1., falloff), 1.);
+}
@@ -0,0 +1,38 @@
+#version 430
+precision mediump float;
+
+// Same as in gbuffer.frag
+#define DEFAULT_ROUGHNESS 0.395
+
+in layout(location = 1) vec3 normal;
+in layout(location = 2) vec2 uv;
+
+uniform int use_reflection;
+uniform int use_roughness;
+uniform int use_opacity;
+uniform int blend_mode;
+uniform float alpha_cutoff;
+
+uniform layout(binding = 3) sampler2D roughness_sampler;
+uniform layout(binding = 4) sampler2D opacity_sampler;
+
+layout(location = 0) out vec4 color;
+layout(location = 1) out vec4 normal_out;
+
+// Depth, world-space normals and roughness only, for screen-space effects
+void main() {
+    // Same cutouts as in the main pass
+    if (blend_mode == 1 && use_opacity == 1 && texture(opacity_sampler, vec2(uv.x, 2. - uv.y)).r < alpha_cutoff) {
+        discard;
+    }
+    float roughness = DEFAULT_ROUGHNESS;
+    if (use_roughness == 1) {
+        roughness = texture(roughness_sampler, vec2(uv.x, 2. - uv.y)).r;
+    }
+    if (use_reflection == 1) {
+        // The screens have proper reflections of their own
+        roughness = 1.;
+    }
+    color = vec4(0., 0., 0., 1.);
+    normal_out = vec4(normalize(normal), roughness);
+}
@@ -6,13 +6,18 @@ precision mediump float;
 #define FRESNEL_SCALE 0.60
 
 #define EMMISSIVE_FACTOR 0.3
+#define AMBIENT_FACTOR 0.08
 
-#define MAX_LIGHT_SOURCES 32
+// Must match the cluster grid in cluster.rs
+#define CLUSTERS_X 16
+#define CLUSTERS_Y 9
+#define CLUSTERS_Z 24
 
-struct LightSource {
-    vec3 position;
-    vec3 color;
-};
+// Default shininess of 32 expressed as roughness (shininess = 5 / roughness^2)
+#define DEFAULT_ROUGHNESS 0.395
+
+#include "lights.glsl"
+#include "environment.glsl"
 
 in layout(location = 0) vec3 position;
 in layout(location = 1) vec3 normal_in;
@@ -27,6 +32,13 @@ uniform int use_cubemaps;
 uniform int use_normals;
 uniform int use_roughness;
 uniform int use_opacity;
+uniform int use_ssao;
+uniform int reflective_only;
+uniform int use_clusters;
+uniform int blend_mode;
+uniform float alpha_cutoff;
+// Write to the accumulation and revealage targets of transparency.rs instead of blending directly
+uniform int weighted_oit;
 
 uniform int mode;
 
@@ -35,11 +47,37 @@ uniform int mode;
 #define NORMALS_MODE 2
 #define REFLECTION_VECTORS_MODE 3
 
+// See BlendMode in graph.rs
+#define OPAQUE 0
+#define CUTOUT 1
+#define TRANSPARENT 2
+
+#define NO_PROXY 0
+#define BOX_PROXY 1
+#define SPHERE_PROXY 2
+
 uniform float shininess;
+uniform vec3 emission;
 uniform vec3 camera_position;
-
-uniform uint num_light_sources;
-uniform LightSource light_sources[MAX_LIGHT_SOURCES];
+uniform vec2 screen_size;
+
+// Shape of the surroundings of the cubemap, see ProxyVolume in texture.rs
+uniform int probe_proxy;
+uniform vec3 probe_position;
+uniform vec3 probe_box_min;
+uniform vec3 probe_box_max;
+uniform float probe_radius;
+
+// Offset and count into cluster_lights for every cluster
+layout(std430, binding = 1) buffer ClusterBuffer {
+    uvec2 clusters[];
+};
+layout(std430, binding = 2) buffer ClusterLightBuffer {
+    uint cluster_lights[];
+};
+uniform mat4 cluster_view;
+uniform float cluster_near;
+uniform float cluster_far;
 
 uniform layout(binding = 0) sampler2D texture_sampler;
 uniform layout(binding = 1) sampler2D reflection_sampler;
@@ -47,10 +85,45 @@ uniform layout(binding = 2) sampler2D normal_sampler;
 uniform layout(binding = 3) sampler2D roughness_sampler;
 uniform layout(binding = 4) sampler2D opacity_sampler;
 uniform layout(binding = 5) samplerCube cubemap_sampler;
+uniform layout(binding = 6) sampler2D ao_sampler;
+
+layout(location = 0) out vec4 color;
+layout(location = 1) out vec4 revealage;
+
+// Find where the reflected ray hits the proxy volume,
+// and look up that point as seen from where the cubemap was captured
+vec3 parallax_correct(vec3 direction) {
+    if (probe_proxy == BOX_PROXY) {
+        vec3 first = (probe_box_max - position) / direction;
+        vec3 second = (probe_box_min - position) / direction;
+        vec3 furthest = max(first, second);
+        float t = min(min(furthest.x, furthest.y), furthest.z);
+        return position + direction * t - probe_position;
+    } else if (probe_proxy == SPHERE_PROXY) {
+        vec3 offset = position - probe_position;
+        float b = dot(offset, direction);
+        float c = dot(offset, offset) - probe_radius * probe_radius;
+        float t = -b + sqrt(max(b*b - c, 0.));
+        return offset + direction * t;
+    }
+    return direction;
+}
 
-out vec4 color;
+// Index of the cluster this fragment is in
+uint cluster_index() {
+    float depth = -(cluster_view * vec4(position, 1.)).z;
+    uint z = uint(max(0., log(depth / cluster_near) / log(cluster_far / cluster_near) * CLUSTERS_Z));
+    uvec2 xy = uvec2(gl_FragCoord.xy / screen_size * vec2(CLUSTERS_X, CLUSTERS_Y));
+    xy = min(xy, uvec2(CLUSTERS_X - 1, CLUSTERS_Y - 1));
+    z = min(z, uint(CLUSTERS_Z - 1));
+    return z * uint(CLUSTERS_X * CLUSTERS_Y) + xy.y * uint(CLUSTERS_X) + xy.x;
+}
 
 void main() {
+    if (reflective_only == 1 && use_reflection == 0) {
+        // Already shaded by the deferred renderer
+        discard;
+    }
     vec3 cam_dir = normalize(camera_position - position);
     // Since image files are in opposite order of OpenGL's uvs,
     // use flipped uvs for textures loaded from image files.
@@ -74,63 +147,63 @@ void main() {
         normal = normalize(TBN * (2*vec3(texture(normal_sampler, flipped_uv)) - 1));
     }
 
-    float shininess = 32;
+    float roughness = DEFAULT_ROUGHNESS;
     if (use_roughness == 1) {
-        float roughness = texture(roughness_sampler, flipped_uv).r;
-        shininess = 5. / (roughness * roughness);
+        roughness = texture(roughness_sampler, flipped_uv).r;
     }
 
     float opacity = 1.;
     if (use_opacity == 1) {
         opacity = texture(opacity_sampler, flipped_uv).r;
     }
+    if (blend_mode == CUTOUT) {
+        if (opacity < alpha_cutoff) {
+            discard;
+        }
+        opacity = 1.;
+    } else if (blend_mode == OPAQUE) {
+        opacity = 1.;
+    }
 
     vec3 reflection = vec3(0);
     if (use_reflection == 1) {
         if (use_cubemaps == 1) {
-            reflection = texture(cubemap_sampler, reflect(-cam_dir, normal)).rgb;
+            reflection = texture(cubemap_sampler, parallax_correct(reflect(-cam_dir, normal))).rgb;
         } else {
-            // Transform the reflection vector into tangent space
-            vec3 r = inverse(TBN) * reflect(-cam_dir, normal);
-            // Then combine the uv coords with the reflection vector's xy values
-            // (ignoring depth since distance to point should contribute to the reflection anyway)
-            vec2 reflection_uv = mix(uv.xy - .5, r.xy*.5, .75);
-            reflection_uv = reflection_uv*.5 + .5;
-            // Need to flip u direction on one particular monitor
-            if (abs(max(0., dot(normalize(normal.xz), vec2(0, -1))) - 1.) < .0001)
-                reflection_uv.x = 1.-reflection_uv.x;
+            // The planar reflection was rendered by mirroring this very view,
+            // so it lines up with the pixels on screen
+            vec2 reflection_uv = gl_FragCoord.xy / screen_size;
             reflection = texture(reflection_sampler, reflection_uv).rgb;
         }
     }
 
-    vec3 lighting = vec3(0);
-
-    for (int i = 0; i < num_light_sources; i++) {
-        vec3 light = light_sources[i].position;
-        vec3 light_color = light_sources[i].color;
-
-        // Attenuation (reduces reach of lightsource)
-        float la = 0.01;
-        float lb = 0.02;
-        float lc = 0.005;
-        float d = length(light - position);
-        float L = 1 / (la + d*lb + d*d*lc);
-        L = 1;
-
-        // Phong model
-        // Parameters – note that specular reflection is independent of surface color!
-        vec3 specular_reflection = vec3(1.0, 1.0, 1.0);
-        // Vectors
-        vec3 light_dir = normalize(light - position);
-        vec3 reflection_dir = reflect(-light_dir, normal);
-
-        // Calculation
-        float diffuse_factor = L * max(0, dot(light_dir, normal));
-        float specular_factor = L * pow(max(0, dot(reflection_dir, cam_dir)), shininess);
-        lighting += diffuse_factor * diffuse_reflection * light_color + specular_factor * specular_reflection * light_color;
+    // Ambient light, from the environment or just a faint constant,
+    // darkened in crevices by the occlusion pass
+    float ambient_occlusion = 1.;
+    if (use_ssao == 1) {
+        ambient_occlusion = texture(ao_sampler, gl_FragCoord.xy / screen_size).r;
+    }
+    vec3 lighting = AMBIENT_FACTOR * ambient_occlusion * diffuse_reflection;
+    if (use_environment == 1 && use_reflection == 0) {
+        // (the screens have reflections of their own)
+        lighting = ambient_occlusion * environment_light(normal, cam_dir, diffuse_reflection, roughness);
+    }
 
+    // Only the lights reaching this cluster, or all of them when there are no clusters
+    uint first_light = 0u;
+    uint num_lights = uint(light_sources.length());
+    if (use_clusters == 1) {
+        uvec2 cluster = clusters[cluster_index()];
+        first_light = cluster.x;
+        num_lights = cluster.y;
+    }
+    for (uint j = 0u; j < num_lights; j++) {
+        uint i = use_clusters == 1 ? cluster_lights[first_light + j] : j;
+        lighting += shade_light(light_sources[i], position, normal, cam_dir, diffuse_reflection, roughness);
     }
 
+    lighting += emission;
+
     if (use_reflection == 1 && use_texture == 1) {
         // This is noe of the CRT screens, boost the light from its contents
         lighting += diffuse_reflection * EMMISSIVE_FACTOR;
@@ -150,4 +223,13 @@ void main() {
     } else {
         color = vec4(lighting, opacity);
     }
+
+    if (weighted_oit == 1) {
+        // Weighted blended order-independent transparency (McGuire and Bavoil),
+        // favouring surfaces that are close and opaque
+        float alpha = color.a;
+        float weight = clamp(pow(min(1., alpha * 10.) + .01, 3.) * 1e8 * pow(1. - gl_FragCoord.z * .9, 3.), 1e-2, 3e3);
+        color = vec4(color.rgb * alpha, alpha) * weight;
+        revealage = vec4(alpha);
+    }
 }
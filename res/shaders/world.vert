@@ -5,13 +5,20 @@ in layout(location = 0) vec3 position_in;
 in layout(location = 1) vec3 normal_in;
 in layout(location = 2) vec2 textureCoordinates_in;
 in layout(location = 3) vec4 color_in;
-in layout(location = 4) vec3 tangent;
-in layout(location = 5) vec3 bitangent;
+// Handedness of the bitangent in w
+in layout(location = 4) vec4 tangent;
+// Transforms of each instance, used instead of the uniforms below when drawing a batch
+in layout(location = 5) mat4 instance_model;
+in layout(location = 9) mat3 instance_normal;
 
 uniform mat4 view_transform;
 uniform mat4 model_transform;
 uniform mat3 normal_transform;
 
+uniform bool instanced;
+// Projection and view only, since the model transform differs per instance
+uniform mat4 view_projection;
+
 out layout(location = 0) vec3 position;
 out layout(location = 1) vec3 normal;
 out layout(location = 2) vec2 textureCoordinates;
@@ -22,13 +29,21 @@ void main() {
     textureCoordinates = textureCoordinates_in;
     color = color_in;
 
-    normal = normalize(normal_transform * normal_in);
+    mat4 model = instanced ? instance_model : model_transform;
+    mat3 normal_matrix = instanced ? instance_normal : normal_transform;
+
+    normal = normalize(normal_matrix * normal_in);
     // Create TBN matrix for transforming normals from normal maps
-    TBN[0] = normalize(normal_transform * tangent);
-    TBN[1] = normalize(normal_transform * bitangent);
+    vec3 bitangent = cross(normal_in, tangent.xyz) * tangent.w;
+    TBN[0] = normalize(normal_matrix * tangent.xyz);
+    TBN[1] = normalize(normal_matrix * bitangent);
     TBN[2] = normal;
     TBN = TBN;
 
-    position = vec3(model_transform * vec4(position_in, 1.));
-    gl_Position = view_transform * vec4(position_in, 1.0);
+    position = vec3(model * vec4(position_in, 1.));
+    if (instanced) {
+        gl_Position = view_projection * vec4(position, 1.0);
+    } else {
+        gl_Position = view_transform * vec4(position_in, 1.0);
+    }
 }
@@ -1,4 +1,5 @@
 extern crate nalgebra_glm as glm;
+mod post;
 mod scene;
 mod shader;
 use glow::*;
@@ -10,11 +11,17 @@ use glutin::event::{
     WindowEvent,
 };
 use glutin::event_loop::ControlFlow;
+use post::{ssao::Ssao, ssr, FrameInputs, PostProcessChain, Target};
 use scene::setup::create_scene;
 use scene::{
     camera::{Camera, FirstPersonCamera, RevolvingCamera},
+    deferred::DeferredRenderer,
+    environment::Environment,
+    export,
+    ltc::{LtcTables, LTC_TABLES},
+    probes::{ProbeSettings, ReflectionProbes},
     texture,
-    vao::VAO,
+    transparency::WeightedBlendedOit,
 };
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
@@ -24,6 +31,9 @@ const WINDOW_HEIGHT: u32 = 720;
 const LOOK_SPEED: f32 = 0.005;
 const MOVE_SPEED: f32 = 20.0;
 const CAPTURE_MOUSE: bool = true;
+const MSAA_SAMPLES: i32 = 4;
+// Optional equirectangular image for image-based lighting (.hdr or .exr)
+const ENVIRONMENT_MAP: &str = "res/textures/environment.hdr";
 
 #[derive(PartialEq, Copy, Clone)]
 enum Mode {
@@ -33,10 +43,31 @@ enum Mode {
     ReflectionVectors,
 }
 
+#[derive(PartialEq, Copy, Clone)]
+enum AntiAliasing {
+    None,
+    Multisampling,
+    Fxaa,
+}
+
+impl AntiAliasing {
+    fn next(&self) -> AntiAliasing {
+        match self {
+            AntiAliasing::None => AntiAliasing::Multisampling,
+            AntiAliasing::Multisampling => AntiAliasing::Fxaa,
+            AntiAliasing::Fxaa => AntiAliasing::None,
+        }
+    }
+}
+
 struct State {
     mode: Mode,
     use_cubemaps: bool,
     free_look: bool,
+    anti_aliasing: AntiAliasing,
+    deferred: bool,
+    use_environment: bool,
+    weighted_oit: bool,
 }
 
 impl State {
@@ -45,6 +76,10 @@ impl State {
             mode: Mode::Standard,
             use_cubemaps: true,
             free_look: false,
+            anti_aliasing: AntiAliasing::Multisampling,
+            deferred: false,
+            use_environment: true,
+            weighted_oit: false,
         }
     }
 
@@ -85,6 +120,22 @@ pub fn debug_callback(source: u32, e_type: u32, id: u32, severity: u32, error_me
 }
 
 fn main() {
+    // `build-cache` prepares the mesh cache without opening a window
+    if std::env::args().nth(1).as_deref() == Some("build-cache") {
+        scene::cache::build_all("res/models");
+        return;
+    }
+    // `fit-ltc` refits the lookup tables for the area lights
+    if std::env::args().nth(1).as_deref() == Some("fit-ltc") {
+        scene::ltc::write_tables(scene::ltc::LTC_TABLES);
+        return;
+    }
+    // `check-ltc` compares those tables with the BRDF they approximate
+    if std::env::args().nth(1).as_deref() == Some("check-ltc") {
+        scene::ltc::check_tables(scene::ltc::LTC_TABLES);
+        return;
+    }
+
     ///// This is from gloom-rs as well /////
 
     // Set up the necessary objects to deal with windows and event handling
@@ -93,8 +144,8 @@ fn main() {
         .with_title("Gloom-rs")
         .with_resizable(false)
         .with_inner_size(glutin::dpi::LogicalSize::new(WINDOW_WIDTH, WINDOW_HEIGHT));
+    // (multisampling happens in the offscreen buffers, not the window)
     let cb = glutin::ContextBuilder::new().with_vsync(true);
-    //.with_multisampling(4);
     let windowed_context = cb.build_windowed(wb, &el).unwrap();
     // Use mouse controls with invisible mouse confined to the screen.
     if CAPTURE_MOUSE {
@@ -133,7 +184,7 @@ fn main() {
             gl.enable(glow::DEPTH_TEST);
             gl.depth_func(glow::LESS);
             gl.enable(glow::CULL_FACE);
-            //gl.enable(glow::MULTISAMPLE);
+            gl.enable(glow::MULTISAMPLE);
             gl.enable(glow::BLEND);
             gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
             gl.enable(glow::DEBUG_OUTPUT_SYNCHRONOUS);
@@ -144,18 +195,78 @@ fn main() {
         // Create a shader program from source
         let shader =
             unsafe { shader::Shader::new(&gl, "res/shaders/world.vert", "res/shaders/world.frag") };
-        let post_shader =
-            unsafe { shader::Shader::new(&gl, "res/shaders/post.vert", "res/shaders/post.frag") };
+        let prepass_shader = unsafe {
+            shader::Shader::new(&gl, "res/shaders/world.vert", "res/shaders/prepass.frag")
+        };
+        // Depth and normals for screen-space effects, rendered before the scene itself
+        let geometry_buffer = unsafe {
+            texture::PostProcessingTexture::with_normals(
+                &gl,
+                WINDOW_WIDTH as i32,
+                WINDOW_HEIGHT as i32,
+            )
+        };
+        let environment = if std::path::Path::new(ENVIRONMENT_MAP).exists() {
+            let before = std::time::Instant::now();
+            let environment = unsafe { Environment::from_file(&gl, ENVIRONMENT_MAP) };
+            unsafe { environment.bind(&gl) };
+            println!(
+                "Precomputing environment lighting took {} seconds",
+                before.elapsed().as_secs_f32(),
+            );
+            Some(environment)
+        } else {
+            println!(
+                "No environment map at {}, using flat ambient light",
+                ENVIRONMENT_MAP
+            );
+            None
+        };
+        let ltc_tables = unsafe { LtcTables::from_file(&gl, LTC_TABLES) };
+        unsafe { ltc_tables.bind(&gl) };
+        let mut deferred_renderer =
+            unsafe { DeferredRenderer::new(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32) };
+        let mut ssao = unsafe { Ssao::new(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32) };
+        let weighted_oit =
+            unsafe { WeightedBlendedOit::new(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32) };
         let post_buffer = unsafe {
-            texture::PostProcessingTexture::new(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32)
+            texture::PostProcessingTexture::hdr(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32)
         };
         let crt_buffer = unsafe {
             texture::PostProcessingTexture::new(&gl, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32)
         };
+        // Multisampled versions of the above, resolved into them after rendering
+        let multisampled_post_buffer = unsafe {
+            texture::MultisampleFramebuffer::new(
+                &gl,
+                WINDOW_WIDTH as i32,
+                WINDOW_HEIGHT as i32,
+                MSAA_SAMPLES,
+                glow::RGBA16F,
+            )
+        };
+        let multisampled_crt_buffer = unsafe {
+            texture::MultisampleFramebuffer::new(
+                &gl,
+                WINDOW_WIDTH as i32,
+                WINDOW_HEIGHT as i32,
+                MSAA_SAMPLES,
+                glow::RGBA8,
+            )
+        };
 
-        let canvas = unsafe { VAO::square(&gl) };
+        let mut post_chain = unsafe {
+            PostProcessChain::from_file(
+                &gl,
+                WINDOW_WIDTH as i32,
+                WINDOW_HEIGHT as i32,
+                "res/postprocessing.txt",
+            )
+        };
 
-        let mut scene_graph = create_scene(&gl);
+        let probe_settings = ProbeSettings::default();
+        let mut scene_graph = create_scene(&gl, &probe_settings);
+        let mut probes = ReflectionProbes::new(probe_settings);
         scene_graph.final_shader = Some(shader.program);
 
         scene_graph.update_transformations(scene_graph.root, &glm::identity(), &glm::zero());
@@ -185,12 +296,13 @@ fn main() {
         fpcam.y += 3.;
 
         let mut state = State::new();
+        if let Some(fxaa) = post_chain.get_mut("fxaa") {
+            fxaa.set_enabled(state.anti_aliasing == AntiAliasing::Fxaa);
+        }
 
-        // Render reflections once since there's nothing dynamic in the scene
-        // other than the contents of the screens
+        // Render all reflections once up front, later they're refreshed a few at a time
         scene_graph.update(&gl);
         unsafe {
-            scene_graph.render_reflections(&gl);
             scene_graph.render_cubemap_reflections(&gl);
         }
 
@@ -221,6 +333,59 @@ fn main() {
                         VirtualKeyCode::F => {
                             state.free_look = !state.free_look;
                         }
+                        VirtualKeyCode::X => {
+                            state.anti_aliasing = state.anti_aliasing.next();
+                            if let Some(fxaa) = post_chain.get_mut("fxaa") {
+                                fxaa.set_enabled(state.anti_aliasing == AntiAliasing::Fxaa);
+                            }
+                        }
+                        VirtualKeyCode::G => {
+                            state.deferred = !state.deferred;
+                        }
+                        VirtualKeyCode::I => {
+                            state.use_environment = !state.use_environment;
+                        }
+                        VirtualKeyCode::T => {
+                            state.weighted_oit = !state.weighted_oit;
+                        }
+                        VirtualKeyCode::H => {
+                            scene_graph.instancing = !scene_graph.instancing;
+                        }
+                        VirtualKeyCode::L => {
+                            scene_graph.use_lod = !scene_graph.use_lod;
+                        }
+                        VirtualKeyCode::P => {
+                            // Each format gets its own folder, as textures may be converted differently
+                            let before = std::time::Instant::now();
+                            let directory = std::path::Path::new(export::EXPORT_DIRECTORY);
+                            unsafe {
+                                export::export_obj(
+                                    &gl,
+                                    &scene_graph,
+                                    &directory.join("obj").join("scene.obj"),
+                                );
+                                export::export_gltf(
+                                    &gl,
+                                    &scene_graph,
+                                    &directory.join("gltf").join("scene.gltf"),
+                                );
+                            }
+                            println!(
+                                "Exported the scene to {} in {} seconds",
+                                directory.display(),
+                                before.elapsed().as_secs_f32()
+                            );
+                        }
+                        VirtualKeyCode::O => {
+                            ssao.enabled = !ssao.enabled;
+                        }
+                        VirtualKeyCode::B => {
+                            post_chain.toggle("bloom");
+                        }
+                        VirtualKeyCode::V => {
+                            post_chain.toggle("vignette");
+                            post_chain.toggle("grain");
+                        }
                         _ => {
                             // This camera handles preses only
                             if !state.free_look {
@@ -254,8 +419,12 @@ fn main() {
             }
 
             unsafe {
+                // Update screen contents first, since they decide the color of the screen lights
+                scene_graph.update_screen_contents(&gl, time);
                 // Update transformations
                 scene_graph.update(&gl);
+                // Refresh some of the reflections
+                probes.update(&gl, &scene_graph);
                 let view_transform = if state.free_look {
                     fpcam.create_transformation(time, delta_time)
                 } else {
@@ -267,56 +436,226 @@ fn main() {
                     rotcam.get_position(time)
                 };
 
+                let projection = if state.free_look {
+                    fpcam.projection()
+                } else {
+                    rotcam.projection()
+                };
+
+                let view = glm::inverse(&projection) * view_transform;
+
+                let environment = environment.filter(|_| state.use_environment);
+
+                // The G-buffer doubles as the prepass when shading is deferred
+                deferred_renderer.environment = environment;
+                if state.deferred {
+                    deferred_renderer.render_geometry(
+                        &gl,
+                        &scene_graph,
+                        &view_transform,
+                        &camera_position,
+                    );
+                }
+
+                // Depth and normal prepass for ambient occlusion and reflections
+                let mut occlusion = None;
+                let mut normals = None;
+                if ssao.enabled || post_chain.is_enabled("ssr") {
+                    let (depth, normal_texture) = if state.deferred {
+                        (
+                            deferred_renderer.gbuffer.depth_texture,
+                            deferred_renderer.gbuffer.normal_texture,
+                        )
+                    } else {
+                        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(geometry_buffer.framebuffer));
+                        gl.viewport(0, 0, geometry_buffer.width, geometry_buffer.height);
+                        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+                        prepass_shader.activate(&gl);
+                        // With reflections, so the screens are marked as rough and skipped by SSR
+                        scene_graph.render_opaque_with_shader(
+                            &gl,
+                            prepass_shader.program,
+                            scene_graph.root,
+                            &view_transform,
+                            &camera_position,
+                            true,
+                        );
+                        (
+                            geometry_buffer.depth_buffer_texture,
+                            geometry_buffer.normal_buffer_texture.unwrap(),
+                        )
+                    };
+                    normals = Some(normal_texture);
+                    if ssao.enabled {
+                        let texture = ssao.render(&gl, depth, normal_texture, &projection, &view);
+                        gl.active_texture(glow::TEXTURE6);
+                        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                        occlusion = Some(texture);
+                    }
+                }
+
+                // Planar reflections follow the viewer, so they're redone every frame
+                if !state.use_cubemaps {
+                    scene_graph.render_planar_reflections(
+                        &gl,
+                        &projection,
+                        &view,
+                        &camera_position,
+                    );
+                }
+
+                // Only the forward renderer uses the light clusters
+                if !state.deferred {
+                    scene_graph.cull_lights(&gl, &projection, &view);
+                }
+
                 // Render content
-                gl.bind_framebuffer(glow::FRAMEBUFFER, Some(crt_buffer.framebuffer));
+                // (the G-buffer can't be multisampled, so deferred shading goes without)
+                let multisampling =
+                    state.anti_aliasing == AntiAliasing::Multisampling && !state.deferred;
+                if multisampling {
+                    gl.bind_framebuffer(
+                        glow::FRAMEBUFFER,
+                        Some(multisampled_crt_buffer.framebuffer),
+                    );
+                } else {
+                    gl.bind_framebuffer(glow::FRAMEBUFFER, Some(crt_buffer.framebuffer));
+                }
                 gl.viewport(0, 0, crt_buffer.width, crt_buffer.height);
                 gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
                 scene_graph.render_screens(&gl, time, &view_transform);
+                if multisampling {
+                    multisampled_crt_buffer.resolve(&gl, &crt_buffer);
+                }
+
+                // Light the G-buffer, leaving its depth behind for the screens
+                if state.deferred {
+                    deferred_renderer.render_lighting(
+                        &gl,
+                        &post_buffer,
+                        &view_transform,
+                        &camera_position,
+                        occlusion,
+                        state.encode(),
+                    );
+                }
 
                 // Reset framebuffer and render scene
-                gl.bind_framebuffer(glow::FRAMEBUFFER, Some(post_buffer.framebuffer));
+                if state.deferred {
+                    gl.bind_framebuffer(glow::FRAMEBUFFER, Some(post_buffer.framebuffer));
+                } else if multisampling {
+                    gl.bind_framebuffer(
+                        glow::FRAMEBUFFER,
+                        Some(multisampled_post_buffer.framebuffer),
+                    );
+                } else {
+                    gl.bind_framebuffer(glow::FRAMEBUFFER, Some(post_buffer.framebuffer));
+                }
                 gl.viewport(0, 0, WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32);
-                gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+                if !state.deferred {
+                    gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+                }
                 shader.activate(&gl);
                 gl.uniform_1_i32(
                     gl.get_uniform_location(shader.program, "mode").as_ref(),
                     state.encode(),
                 );
+                gl.uniform_1_i32(
+                    gl.get_uniform_location(shader.program, "use_ssao").as_ref(),
+                    ssao.enabled as i32,
+                );
+                gl.uniform_1_i32(
+                    gl.get_uniform_location(shader.program, "use_environment")
+                        .as_ref(),
+                    environment.is_some() as i32,
+                );
+                if let Some(environment) = &environment {
+                    gl.uniform_1_f32(
+                        gl.get_uniform_location(shader.program, "environment_intensity")
+                            .as_ref(),
+                        environment.intensity,
+                    );
+                }
+                gl.uniform_1_i32(
+                    gl.get_uniform_location(shader.program, "use_clusters")
+                        .as_ref(),
+                    !state.deferred as i32,
+                );
+                // Only the screens are left to draw after deferred lighting
+                gl.uniform_1_i32(
+                    gl.get_uniform_location(shader.program, "reflective_only")
+                        .as_ref(),
+                    state.deferred as i32,
+                );
+                gl.uniform_2_f32(
+                    gl.get_uniform_location(shader.program, "screen_size")
+                        .as_ref(),
+                    WINDOW_WIDTH as f32,
+                    WINDOW_HEIGHT as f32,
+                );
                 gl.uniform_1_i32(
                     gl.get_uniform_location(shader.program, "use_cubemaps")
                         .as_ref(),
                     state.use_cubemaps as i32,
                 );
-                scene_graph.render(
+                scene_graph.render_opaque_with_shader(
                     &gl,
+                    shader.program,
                     scene_graph.root,
                     &view_transform,
                     &camera_position,
                     true,
                 );
-                // Post-processing
-                gl.bind_framebuffer(glow::FRAMEBUFFER, None);
-                gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
-                gl.use_program(Some(post_shader.program));
+                // Transparent nodes go last, on top of everything opaque, deferred or not
                 gl.uniform_1_i32(
-                    gl.get_uniform_location(post_shader.program, "mode")
+                    gl.get_uniform_location(shader.program, "reflective_only")
                         .as_ref(),
-                    state.encode(),
+                    0,
                 );
-                gl.uniform_3_f32_slice(
-                    gl.get_uniform_location(post_shader.program, "camera_position")
-                        .as_ref(),
-                    camera_position.as_ref(),
+                if state.weighted_oit {
+                    let target = if multisampling {
+                        multisampled_post_buffer.framebuffer
+                    } else {
+                        post_buffer.framebuffer
+                    };
+                    weighted_oit.render(
+                        &gl,
+                        &scene_graph,
+                        target,
+                        &view_transform,
+                        &camera_position,
+                    );
+                } else {
+                    scene_graph.render_transparent_with_shader(
+                        &gl,
+                        shader.program,
+                        scene_graph.root,
+                        &view_transform,
+                        &camera_position,
+                        true,
+                    );
+                }
+                if multisampling {
+                    multisampled_post_buffer.resolve(&gl, &post_buffer);
+                }
+                // Post-processing
+                post_chain.run(
+                    &gl,
+                    post_buffer.color_buffer_texture,
+                    &FrameInputs {
+                        depth: post_buffer.depth_buffer_texture,
+                        crt: crt_buffer.color_buffer_texture,
+                        crt_depth: crt_buffer.depth_buffer_texture,
+                        normals,
+                        probes: scene_graph.nearest_probes(&camera_position, ssr::MAX_PROBES),
+                        projection,
+                        view,
+                        camera_position,
+                        time,
+                        mode: state.encode(),
+                    },
+                    &Target::screen(WINDOW_WIDTH as i32, WINDOW_HEIGHT as i32),
                 );
-                gl.active_texture(glow::TEXTURE0);
-                gl.bind_texture(glow::TEXTURE_2D, Some(post_buffer.color_buffer_texture));
-                gl.active_texture(glow::TEXTURE1);
-                gl.bind_texture(glow::TEXTURE_2D, Some(post_buffer.depth_buffer_texture));
-                gl.active_texture(glow::TEXTURE2);
-                gl.bind_texture(glow::TEXTURE_2D, Some(crt_buffer.color_buffer_texture));
-                gl.active_texture(glow::TEXTURE3);
-                gl.bind_texture(glow::TEXTURE_2D, Some(crt_buffer.depth_buffer_texture));
-                canvas.draw(&gl);
                 // Swap which color buffer is displayed
                 context.swap_buffers().unwrap();
             }
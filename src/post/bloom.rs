@@ -0,0 +1,168 @@
+use glow::*;
+
+use super::{FrameInputs, Parameter, PostEffect, Target};
+use crate::scene::{texture::PostProcessingTexture, vao::VAO};
+use crate::shader::Shader;
+
+const MAX_MIPS: usize = 6;
+const MIN_MIP_SIZE: i32 = 8;
+
+/// Bloom in the style of Jimenez' mip chain:
+/// bright parts are downsampled into successively smaller buffers
+/// and then blurred back up, each level adding to the one above it
+pub struct Bloom {
+    enabled: bool,
+    threshold: f32,
+    knee: f32,
+    intensity: f32,
+    radius: f32,
+    mips: Vec<PostProcessingTexture>,
+    downsample: Shader,
+    upsample: Shader,
+    composite: Shader,
+}
+
+impl Bloom {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> Bloom {
+        let mut mips = vec![];
+        let (mut mip_width, mut mip_height) = (width / 2, height / 2);
+        while mips.len() < MAX_MIPS && mip_width >= MIN_MIP_SIZE && mip_height >= MIN_MIP_SIZE {
+            mips.push(PostProcessingTexture::hdr(gl, mip_width, mip_height));
+            mip_width /= 2;
+            mip_height /= 2;
+        }
+        Bloom {
+            enabled: true,
+            threshold: 1.,
+            knee: 0.5,
+            intensity: 0.6,
+            radius: 1.,
+            mips,
+            downsample: Shader::new(
+                gl,
+                "res/shaders/post.vert",
+                "res/shaders/post/bloom_down.frag",
+            ),
+            upsample: Shader::new(
+                gl,
+                "res/shaders/post.vert",
+                "res/shaders/post/bloom_up.frag",
+            ),
+            composite: Shader::new(
+                gl,
+                "res/shaders/post.vert",
+                "res/shaders/post/bloom_composite.frag",
+            ),
+        }
+    }
+}
+
+impl PostEffect for Bloom {
+    fn name(&self) -> &str {
+        "bloom"
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Parameter) {
+        let value = match value {
+            Parameter::Float(value) => value,
+            _ => panic!("Bloom parameter {} must be a single number", name),
+        };
+        match name {
+            "threshold" => self.threshold = value,
+            "knee" => self.knee = value,
+            "intensity" => self.intensity = value,
+            "radius" => self.radius = value,
+            _ => panic!("Unknown bloom parameter {}", name),
+        }
+    }
+
+    unsafe fn apply(
+        &self,
+        gl: &glow::Context,
+        canvas: &VAO,
+        input: NativeTexture,
+        _frame: &FrameInputs,
+        target: &Target,
+    ) {
+        // The mips are drawn on top of each other, so depth is only in the way
+        gl.disable(glow::DEPTH_TEST);
+        let program = self.downsample.program;
+        self.downsample.activate(gl);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "threshold").as_ref(),
+            self.threshold,
+        );
+        gl.uniform_1_f32(gl.get_uniform_location(program, "knee").as_ref(), self.knee);
+        gl.active_texture(glow::TEXTURE0);
+
+        // Downsample from the scene through the whole chain,
+        // only letting bright enough colors through on the first step
+        let mut source = input;
+        let (mut source_width, mut source_height) = (target.width, target.height);
+        for (i, mip) in self.mips.iter().enumerate() {
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(mip.framebuffer));
+            gl.viewport(0, 0, mip.width, mip.height);
+            gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+            gl.uniform_1_i32(
+                gl.get_uniform_location(program, "prefilter").as_ref(),
+                (i == 0) as i32,
+            );
+            gl.uniform_2_f32(
+                gl.get_uniform_location(program, "source_size").as_ref(),
+                source_width as f32,
+                source_height as f32,
+            );
+            gl.bind_texture(glow::TEXTURE_2D, Some(source));
+            canvas.draw(gl);
+            source = mip.color_buffer_texture;
+            source_width = mip.width;
+            source_height = mip.height;
+        }
+
+        // Then blur back up, adding each level onto the larger one
+        let program = self.upsample.program;
+        self.upsample.activate(gl);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "radius").as_ref(),
+            self.radius,
+        );
+        gl.blend_func(glow::ONE, glow::ONE);
+        for i in (1..self.mips.len()).rev() {
+            let (smaller, larger) = (&self.mips[i], &self.mips[i - 1]);
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(larger.framebuffer));
+            gl.viewport(0, 0, larger.width, larger.height);
+            gl.uniform_2_f32(
+                gl.get_uniform_location(program, "source_size").as_ref(),
+                smaller.width as f32,
+                smaller.height as f32,
+            );
+            gl.bind_texture(glow::TEXTURE_2D, Some(smaller.color_buffer_texture));
+            canvas.draw(gl);
+        }
+        gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+
+        // Finally add the blurred highlights to the scene
+        target.bind(gl);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        let program = self.composite.program;
+        self.composite.activate(gl);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "intensity").as_ref(),
+            self.intensity,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(input));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.mips[0].color_buffer_texture));
+        canvas.draw(gl);
+        gl.enable(glow::DEPTH_TEST);
+    }
+}
@@ -0,0 +1,342 @@
+pub mod bloom;
+pub mod ssao;
+pub mod ssr;
+
+use std::path::Path;
+
+use glow::*;
+
+use crate::scene::{texture::PostProcessingTexture, vao::VAO};
+use crate::shader::Shader;
+
+/// A uniform value handed to a post-processing pass
+#[derive(Clone, Copy)]
+pub enum Parameter {
+    Float(f32),
+    Vec2(glm::Vec2),
+    Vec3(glm::Vec3),
+}
+
+impl Parameter {
+    /// Parse a parameter from the config file, where vectors are comma-separated.
+    /// Everything is parsed as floats since that's what the effect shaders expect.
+    pub fn parse(value: &str) -> Option<Parameter> {
+        let parts: Vec<f32> = value
+            .split(',')
+            .map(|part| part.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .ok()?;
+        match parts.len() {
+            1 => Some(Parameter::Float(parts[0])),
+            2 => Some(Parameter::Vec2(glm::vec2(parts[0], parts[1]))),
+            3 => Some(Parameter::Vec3(glm::vec3(parts[0], parts[1], parts[2]))),
+            _ => None,
+        }
+    }
+
+    unsafe fn upload(&self, gl: &glow::Context, program: NativeProgram, name: &str) {
+        let location = gl.get_uniform_location(program, name);
+        match self {
+            Parameter::Float(value) => gl.uniform_1_f32(location.as_ref(), *value),
+            Parameter::Vec2(value) => gl.uniform_2_f32(location.as_ref(), value.x, value.y),
+            Parameter::Vec3(value) => {
+                gl.uniform_3_f32(location.as_ref(), value.x, value.y, value.z)
+            }
+        }
+    }
+}
+
+/// Where a pass should put its result
+#[derive(Clone, Copy)]
+pub struct Target {
+    pub framebuffer: Option<NativeFramebuffer>,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Target {
+    /// The default framebuffer, i.e. the window
+    pub fn screen(width: i32, height: i32) -> Target {
+        Target {
+            framebuffer: None,
+            width,
+            height,
+        }
+    }
+
+    pub unsafe fn bind(&self, gl: &glow::Context) {
+        gl.bind_framebuffer(glow::FRAMEBUFFER, self.framebuffer);
+        gl.viewport(0, 0, self.width, self.height);
+    }
+}
+
+/// Everything about the current frame that a pass might want to look at
+pub struct FrameInputs {
+    pub depth: NativeTexture,
+    pub crt: NativeTexture,
+    pub crt_depth: NativeTexture,
+    /// World-space normals with roughness in alpha, if a prepass or G-buffer produced them
+    pub normals: Option<NativeTexture>,
+    /// World positions and cubemaps of the reflection probes closest to the camera
+    pub probes: Vec<(glm::Vec3, NativeTexture)>,
+    pub projection: glm::Mat4,
+    pub view: glm::Mat4,
+    pub camera_position: glm::Vec3,
+    pub time: f32,
+    pub mode: i32,
+}
+
+/// One step in the post-processing chain
+pub trait PostEffect {
+    fn name(&self) -> &str;
+    fn enabled(&self) -> bool;
+    fn set_enabled(&mut self, enabled: bool);
+    fn set_parameter(&mut self, name: &str, value: Parameter);
+    /// Read from the input texture and write to the target
+    unsafe fn apply(
+        &self,
+        gl: &glow::Context,
+        canvas: &VAO,
+        input: NativeTexture,
+        frame: &FrameInputs,
+        target: &Target,
+    );
+}
+
+/// A single full-screen shader with a set of parameters
+pub struct ShaderPass {
+    name: String,
+    shader: Shader,
+    enabled: bool,
+    parameters: Vec<(String, Parameter)>,
+}
+
+impl ShaderPass {
+    pub unsafe fn new(gl: &glow::Context, name: &str, fragment_shader_path: &str) -> ShaderPass {
+        ShaderPass {
+            name: name.to_string(),
+            shader: Shader::new(gl, "res/shaders/post.vert", fragment_shader_path),
+            enabled: true,
+            parameters: vec![],
+        }
+    }
+
+    /// Builder-style variant of set_parameter
+    pub fn with(mut self, name: &str, value: Parameter) -> ShaderPass {
+        self.set_parameter(name, value);
+        self
+    }
+}
+
+impl PostEffect for ShaderPass {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Parameter) {
+        if let Some(parameter) = self.parameters.iter_mut().find(|(n, _)| n == name) {
+            parameter.1 = value;
+        } else {
+            self.parameters.push((name.to_string(), value));
+        }
+    }
+
+    unsafe fn apply(
+        &self,
+        gl: &glow::Context,
+        canvas: &VAO,
+        input: NativeTexture,
+        frame: &FrameInputs,
+        target: &Target,
+    ) {
+        target.bind(gl);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        let program = self.shader.program;
+        self.shader.activate(gl);
+        // Common uniforms every pass gets, whether it uses them or not
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "mode").as_ref(),
+            frame.mode,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "time").as_ref(),
+            frame.time,
+        );
+        gl.uniform_2_f32(
+            gl.get_uniform_location(program, "screen_size").as_ref(),
+            target.width as f32,
+            target.height as f32,
+        );
+        gl.uniform_3_f32_slice(
+            gl.get_uniform_location(program, "camera_position").as_ref(),
+            frame.camera_position.as_ref(),
+        );
+        for (name, value) in self.parameters.iter() {
+            value.upload(gl, program, name);
+        }
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(input));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(frame.depth));
+        gl.active_texture(glow::TEXTURE2);
+        gl.bind_texture(glow::TEXTURE_2D, Some(frame.crt));
+        gl.active_texture(glow::TEXTURE3);
+        gl.bind_texture(glow::TEXTURE_2D, Some(frame.crt_depth));
+        canvas.draw(gl);
+    }
+}
+
+/// Create one of the built-in effects by name, with default parameters
+pub unsafe fn preset(
+    gl: &glow::Context,
+    name: &str,
+    width: i32,
+    height: i32,
+) -> Option<Box<dyn PostEffect>> {
+    let pass = match name {
+        "bloom" => return Some(Box::new(bloom::Bloom::new(gl, width, height))),
+        "ssr" => return Some(Box::new(ssr::Ssr::new(gl))),
+        // Adds the CRT contents on top of the scene
+        "composite" => ShaderPass::new(gl, name, "res/shaders/post.frag")
+            .with("crt_strength", Parameter::Float(0.5)),
+        "tonemap" => ShaderPass::new(gl, name, "res/shaders/post/tonemap.frag")
+            .with("exposure", Parameter::Float(1.))
+            .with("gamma", Parameter::Float(1.)),
+        "grading" => ShaderPass::new(gl, name, "res/shaders/post/grading.frag")
+            .with("contrast", Parameter::Float(1.))
+            .with("saturation", Parameter::Float(1.))
+            .with("tint", Parameter::Vec3(glm::vec3(1., 1., 1.))),
+        "fxaa" => ShaderPass::new(gl, name, "res/shaders/post/fxaa.frag")
+            .with("span_max", Parameter::Float(8.)),
+        "vignette" => ShaderPass::new(gl, name, "res/shaders/post/vignette.frag")
+            .with("strength", Parameter::Float(0.3))
+            .with("radius", Parameter::Float(0.8)),
+        "grain" => ShaderPass::new(gl, name, "res/shaders/post/grain.frag")
+            .with("amount", Parameter::Float(0.03)),
+        _ => return None,
+    };
+    Some(Box::new(pass))
+}
+
+/// A list of effects applied one after another,
+/// bouncing between two offscreen buffers until the last one renders to the target
+pub struct PostProcessChain {
+    pub passes: Vec<Box<dyn PostEffect>>,
+    buffers: [PostProcessingTexture; 2],
+    copy: ShaderPass,
+    canvas: VAO,
+}
+
+impl PostProcessChain {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> PostProcessChain {
+        PostProcessChain {
+            passes: vec![],
+            buffers: [
+                PostProcessingTexture::hdr(gl, width, height),
+                PostProcessingTexture::hdr(gl, width, height),
+            ],
+            copy: ShaderPass::new(gl, "copy", "res/shaders/post/copy.frag"),
+            canvas: VAO::square(gl),
+        }
+    }
+
+    /// Build a chain from a config file with one effect per line,
+    /// each followed by optional parameter=value pairs
+    pub unsafe fn from_file(
+        gl: &glow::Context,
+        width: i32,
+        height: i32,
+        path: &str,
+    ) -> PostProcessChain {
+        let mut chain = PostProcessChain::new(gl, width, height);
+        let config = std::fs::read_to_string(Path::new(path))
+            .unwrap_or_else(|_| panic!("No post-processing config at {}", path));
+        for (line_number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap();
+            let mut effect = preset(gl, name, width, height)
+                .unwrap_or_else(|| panic!("{}:{}: Unknown effect {}", path, line_number + 1, name));
+            for word in words {
+                let (key, value) = word.split_once('=').unwrap_or_else(|| {
+                    panic!("{}:{}: Expected parameter=value", path, line_number + 1)
+                });
+                let value = Parameter::parse(value).unwrap_or_else(|| {
+                    panic!("{}:{}: Invalid value for {}", path, line_number + 1, key)
+                });
+                effect.set_parameter(key, value);
+            }
+            chain.add(effect);
+        }
+        chain
+    }
+
+    pub fn add(&mut self, effect: Box<dyn PostEffect>) {
+        self.passes.push(effect);
+    }
+
+    /// Whether an effect is in the chain and turned on
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.passes
+            .iter()
+            .any(|pass| pass.name() == name && pass.enabled())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Box<dyn PostEffect>> {
+        self.passes.iter_mut().find(|pass| pass.name() == name)
+    }
+
+    /// Flip an effect on or off, if it is in the chain
+    pub fn toggle(&mut self, name: &str) {
+        if let Some(pass) = self.get_mut(name) {
+            let enabled = pass.enabled();
+            pass.set_enabled(!enabled);
+        }
+    }
+
+    /// Run every enabled effect on the input, ending up in the output target
+    pub unsafe fn run(
+        &self,
+        gl: &glow::Context,
+        input: NativeTexture,
+        frame: &FrameInputs,
+        output: &Target,
+    ) {
+        let passes: Vec<&dyn PostEffect> = self
+            .passes
+            .iter()
+            .filter(|pass| pass.enabled())
+            .map(|pass| pass.as_ref())
+            .collect();
+        if passes.is_empty() {
+            self.copy.apply(gl, &self.canvas, input, frame, output);
+            return;
+        }
+        let mut source = input;
+        for (i, pass) in passes.iter().enumerate() {
+            if i == passes.len() - 1 {
+                pass.apply(gl, &self.canvas, source, frame, output);
+            } else {
+                let buffer = &self.buffers[i % 2];
+                let target = Target {
+                    framebuffer: Some(buffer.framebuffer),
+                    width: buffer.width,
+                    height: buffer.height,
+                };
+                pass.apply(gl, &self.canvas, source, frame, &target);
+                source = buffer.color_buffer_texture;
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use glow::*;
+
+use crate::scene::{
+    texture::PostProcessingTexture,
+    vao::{to_u8_slice, VAO},
+};
+use crate::shader::Shader;
+
+const KERNEL_SIZE: usize = 32;
+const NOISE_SIZE: i32 = 4;
+
+/// Tiny xorshift generator, so the kernel is the same every run
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32
+    }
+}
+
+/// Screen-space ambient occlusion from a depth and normal prepass,
+/// sampling a hemisphere around each point and blurring the noisy result
+pub struct Ssao {
+    pub enabled: bool,
+    pub radius: f32,
+    pub bias: f32,
+    pub power: f32,
+    kernel: Vec<glm::Vec3>,
+    noise: NativeTexture,
+    occlusion: PostProcessingTexture,
+    blurred: PostProcessingTexture,
+    ssao_shader: Shader,
+    blur_shader: Shader,
+    canvas: VAO,
+}
+
+impl Ssao {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> Ssao {
+        let mut random = Random(0x2545f491);
+
+        // Samples in a hemisphere along +z, clustered towards the center
+        let kernel = (0..KERNEL_SIZE)
+            .map(|i| {
+                let sample = glm::normalize(&glm::vec3(
+                    random.next() * 2. - 1.,
+                    random.next() * 2. - 1.,
+                    random.next(),
+                )) * random.next();
+                let scale = i as f32 / KERNEL_SIZE as f32;
+                sample * glm::lerp_scalar(0.1, 1., scale * scale)
+            })
+            .collect();
+
+        // Random rotations around z, tiled over the screen
+        let noise_data: Vec<f32> = (0..NOISE_SIZE * NOISE_SIZE)
+            .flat_map(|_| [random.next() * 2. - 1., random.next() * 2. - 1., 0., 0.])
+            .collect();
+        let noise = gl.create_texture().expect("Could not create texture");
+        gl.bind_texture(glow::TEXTURE_2D, Some(noise));
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA16F as i32,
+            NOISE_SIZE,
+            NOISE_SIZE,
+            0,
+            glow::RGBA,
+            glow::FLOAT,
+            Some(to_u8_slice(&noise_data)),
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::NEAREST as i32,
+        );
+
+        Ssao {
+            enabled: true,
+            radius: 0.5,
+            bias: 0.025,
+            power: 1.5,
+            kernel,
+            noise,
+            occlusion: PostProcessingTexture::new(gl, width, height),
+            blurred: PostProcessingTexture::new(gl, width, height),
+            ssao_shader: Shader::new(gl, "res/shaders/post.vert", "res/shaders/post/ssao.frag"),
+            blur_shader: Shader::new(
+                gl,
+                "res/shaders/post.vert",
+                "res/shaders/post/ssao_blur.frag",
+            ),
+            canvas: VAO::square(gl),
+        }
+    }
+
+    /// Compute occlusion from a depth buffer and world-space normals,
+    /// either from the prepass or the G-buffer.
+    /// Returns the blurred occlusion texture, with 1 meaning no occlusion.
+    pub unsafe fn render(
+        &self,
+        gl: &glow::Context,
+        depth: NativeTexture,
+        normals: NativeTexture,
+        projection: &glm::Mat4,
+        view: &glm::Mat4,
+    ) -> NativeTexture {
+        gl.disable(glow::DEPTH_TEST);
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.occlusion.framebuffer));
+        gl.viewport(0, 0, self.occlusion.width, self.occlusion.height);
+        gl.clear(glow::COLOR_BUFFER_BIT);
+        let program = self.ssao_shader.program;
+        self.ssao_shader.activate(gl);
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "projection").as_ref(),
+            false,
+            projection.as_slice(),
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "inverse_projection")
+                .as_ref(),
+            false,
+            glm::inverse(projection).as_slice(),
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "view").as_ref(),
+            false,
+            view.as_slice(),
+        );
+        for (i, sample) in self.kernel.iter().enumerate() {
+            gl.uniform_3_f32_slice(
+                gl.get_uniform_location(program, &format!("kernel[{}]", i))
+                    .as_ref(),
+                sample.as_slice(),
+            );
+        }
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "radius").as_ref(),
+            self.radius,
+        );
+        gl.uniform_1_f32(gl.get_uniform_location(program, "bias").as_ref(), self.bias);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "power").as_ref(),
+            self.power,
+        );
+        gl.uniform_2_f32(
+            gl.get_uniform_location(program, "noise_scale").as_ref(),
+            (self.occlusion.width / NOISE_SIZE) as f32,
+            (self.occlusion.height / NOISE_SIZE) as f32,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(depth));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(normals));
+        gl.active_texture(glow::TEXTURE2);
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.noise));
+        self.canvas.draw(gl);
+
+        // Blur over the size of the noise tile to hide the pattern
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.blurred.framebuffer));
+        gl.clear(glow::COLOR_BUFFER_BIT);
+        let program = self.blur_shader.program;
+        self.blur_shader.activate(gl);
+        gl.uniform_2_f32(
+            gl.get_uniform_location(program, "screen_size").as_ref(),
+            self.blurred.width as f32,
+            self.blurred.height as f32,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.occlusion.color_buffer_texture));
+        self.canvas.draw(gl);
+
+        gl.enable(glow::DEPTH_TEST);
+        self.blurred.color_buffer_texture
+    }
+}
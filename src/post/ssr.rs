@@ -0,0 +1,151 @@
+use glow::*;
+
+use super::{FrameInputs, Parameter, PostEffect, Target};
+use crate::scene::vao::VAO;
+use crate::shader::Shader;
+
+/// Reflection probes available to each pixel, must match MAX_PROBES in ssr.frag
+pub const MAX_PROBES: usize = 4;
+/// Texture unit of the first probe, must match the binding of probe_samplers in ssr.frag
+const FIRST_PROBE_UNIT: u32 = 3;
+
+/// Screen-space reflections for glossy surfaces, marching along reflected rays
+/// through the depth buffer and falling back to the reflection probes nearest each pixel
+pub struct Ssr {
+    enabled: bool,
+    max_distance: f32,
+    thickness: f32,
+    max_roughness: f32,
+    strength: f32,
+    /// Distance at which a probe no longer stands in for missed rays
+    probe_range: f32,
+    /// Probes that fit in the texture units of the driver
+    max_probes: usize,
+    shader: Shader,
+}
+
+impl Ssr {
+    pub unsafe fn new(gl: &glow::Context) -> Ssr {
+        Ssr {
+            enabled: true,
+            max_distance: 15.,
+            thickness: 0.5,
+            max_roughness: 0.6,
+            strength: 1.,
+            probe_range: 12.,
+            max_probes: (gl.get_parameter_i32(glow::MAX_TEXTURE_IMAGE_UNITS) as usize)
+                .saturating_sub(FIRST_PROBE_UNIT as usize)
+                .min(MAX_PROBES),
+            shader: Shader::new(gl, "res/shaders/post.vert", "res/shaders/post/ssr.frag"),
+        }
+    }
+}
+
+impl PostEffect for Ssr {
+    fn name(&self) -> &str {
+        "ssr"
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn set_parameter(&mut self, name: &str, value: Parameter) {
+        let value = match value {
+            Parameter::Float(value) => value,
+            _ => panic!("SSR parameter {} must be a single number", name),
+        };
+        match name {
+            "max_distance" => self.max_distance = value,
+            "thickness" => self.thickness = value,
+            "max_roughness" => self.max_roughness = value,
+            "strength" => self.strength = value,
+            "probe_range" => self.probe_range = value,
+            _ => panic!("Unknown SSR parameter {}", name),
+        }
+    }
+
+    unsafe fn apply(
+        &self,
+        gl: &glow::Context,
+        canvas: &VAO,
+        input: NativeTexture,
+        frame: &FrameInputs,
+        target: &Target,
+    ) {
+        target.bind(gl);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        let program = self.shader.program;
+        self.shader.activate(gl);
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "projection").as_ref(),
+            false,
+            frame.projection.as_slice(),
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "inverse_projection")
+                .as_ref(),
+            false,
+            glm::inverse(&frame.projection).as_slice(),
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "view").as_ref(),
+            false,
+            frame.view.as_slice(),
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "max_distance").as_ref(),
+            self.max_distance,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "thickness").as_ref(),
+            self.thickness,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "max_roughness").as_ref(),
+            self.max_roughness,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "strength").as_ref(),
+            self.strength,
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "inverse_view").as_ref(),
+            false,
+            glm::inverse(&frame.view).as_slice(),
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "probe_range").as_ref(),
+            self.probe_range,
+        );
+        let probes = &frame.probes[..frame.probes.len().min(self.max_probes)];
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "probe_count").as_ref(),
+            probes.len() as i32,
+        );
+        let positions: Vec<f32> = probes
+            .iter()
+            .flat_map(|(position, _)| position.iter().copied())
+            .collect();
+        gl.uniform_3_f32_slice(
+            gl.get_uniform_location(program, "probe_positions").as_ref(),
+            &positions,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(input));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(frame.depth));
+        gl.active_texture(glow::TEXTURE2);
+        gl.bind_texture(glow::TEXTURE_2D, frame.normals);
+        for (i, (_, cubemap)) in probes.iter().enumerate() {
+            gl.active_texture(glow::TEXTURE0 + FIRST_PROBE_UNIT + i as u32);
+            gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(*cubemap));
+        }
+        gl.active_texture(glow::TEXTURE0);
+        canvas.draw(gl);
+    }
+}
@@ -0,0 +1,340 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::lod::simplify_levels;
+use super::mesh::Mesh;
+use super::vao::load_obj;
+
+/// Processed meshes are kept here, one file per OBJ file
+pub const CACHE_DIRECTORY: &str = "res/cache";
+
+/// Bump when the layout or the processing of the meshes changes, so old caches are rebuilt
+const CACHE_VERSION: u32 = 4;
+const MAGIC: &[u8; 4] = b"MESH";
+
+/// A mesh of an OBJ file with the index of its material and its simplified levels of detail
+pub struct CachedMesh {
+    pub mesh: Mesh,
+    pub material_id: usize,
+    pub levels: Vec<Mesh>,
+}
+
+pub type ObjMeshes = Vec<CachedMesh>;
+
+/// 64-bit FNV-1a
+fn fnv_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Hash of the contents of the OBJ file and its material libraries, eight bytes at a time
+/// since the OBJ files run to many megabytes
+fn content_hash(obj_path: &Path, mtl_paths: &[PathBuf]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for path in std::iter::once(obj_path).chain(mtl_paths.iter().map(PathBuf::as_path)) {
+        // Missing files count as empty, like in the stamp
+        let bytes = std::fs::read(path).unwrap_or_default();
+        let words = bytes.chunks_exact(8);
+        let rest = words.remainder();
+        for word in words.map(|word| u64::from_le_bytes(word.try_into().unwrap())) {
+            hash = (hash ^ word).wrapping_mul(0x100000001b3);
+        }
+        hash = (hash ^ fnv_hash(rest)).wrapping_mul(0x100000001b3);
+        hash = (hash ^ bytes.len() as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Material libraries the OBJ file refers to, relative to its own folder
+fn material_paths(obj_path: &Path) -> Vec<PathBuf> {
+    let source =
+        std::fs::read(obj_path).unwrap_or_else(|_| panic!("Could not read {}", obj_path.display()));
+    let folder = obj_path.parent().unwrap_or_else(|| Path::new(""));
+    String::from_utf8_lossy(&source)
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("mtllib "))
+        .map(|name| folder.join(name.trim()))
+        .collect()
+}
+
+/// Hash of the sizes and modification times of the OBJ file and its material libraries,
+/// which give the vertex colors. Cheap enough to check on every launch, unlike the contents,
+/// which are only hashed when it changes.
+fn source_stamp(obj_path: &Path, mtl_paths: &[PathBuf]) -> u64 {
+    let mut stamps = vec![];
+    for path in std::iter::once(obj_path).chain(mtl_paths.iter().map(PathBuf::as_path)) {
+        // Missing files count as empty, so the cache is rebuilt when they show up
+        let (length, modified) = std::fs::metadata(path)
+            .map(|metadata| {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                (metadata.len(), modified.as_nanos())
+            })
+            .unwrap_or_default();
+        stamps.extend_from_slice(&length.to_le_bytes());
+        stamps.extend_from_slice(&modified.to_le_bytes());
+    }
+    fnv_hash(&stamps)
+}
+
+/// Cache file of an OBJ file, named after the file and a hash of its folder so that
+/// files with the same name in different folders get their own caches
+pub fn cache_path(obj_path: &Path) -> PathBuf {
+    let name = obj_path
+        .file_stem()
+        .unwrap_or_else(|| panic!("No file name in {}", obj_path.display()));
+    let folder = obj_path.parent().unwrap_or_else(|| Path::new(""));
+    let folder_hash = fnv_hash(folder.to_string_lossy().as_bytes());
+    Path::new(CACHE_DIRECTORY).join(format!(
+        "{}-{:016x}.mesh",
+        name.to_string_lossy(),
+        folder_hash
+    ))
+}
+
+/// Attribute and index lengths of a mesh, followed by their contents
+fn write_mesh(bytes: &mut Vec<u8>, mesh: &Mesh) {
+    let attributes = [
+        &mesh.positions,
+        &mesh.normals,
+        &mesh.uvs,
+        &mesh.colors,
+        &mesh.tangents,
+    ];
+    for attribute in attributes {
+        bytes.extend_from_slice(&(attribute.len() as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(&(mesh.indices.len() as u32).to_le_bytes());
+    for value in attributes.iter().flat_map(|attribute| attribute.iter()) {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for index in &mesh.indices {
+        bytes.extend_from_slice(&index.to_le_bytes());
+    }
+}
+
+/// Where the stamp of the sources sits in a cache file, after the magic and the version
+const STAMP_OFFSET: u64 = 8;
+
+/// Write a header with the stamp and hash of the sources and the paths of the material libraries,
+/// then the material, level count, mesh and levels of each mesh, all little-endian
+fn write_cache(
+    path: &Path,
+    obj_path: &Path,
+    mtl_paths: &[PathBuf],
+    meshes: &[CachedMesh],
+) -> std::io::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&source_stamp(obj_path, mtl_paths).to_le_bytes());
+    bytes.extend_from_slice(&content_hash(obj_path, mtl_paths).to_le_bytes());
+    bytes.extend_from_slice(&(mtl_paths.len() as u32).to_le_bytes());
+    for mtl_path in mtl_paths {
+        let name = mtl_path.to_string_lossy();
+        bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes.extend_from_slice(&(meshes.len() as u32).to_le_bytes());
+    for cached in meshes {
+        bytes.extend_from_slice(&(cached.material_id as u32).to_le_bytes());
+        bytes.extend_from_slice(&(cached.levels.len() as u32).to_le_bytes());
+        write_mesh(&mut bytes, &cached.mesh);
+        for level in &cached.levels {
+            write_mesh(&mut bytes, level);
+        }
+    }
+    std::fs::create_dir_all(CACHE_DIRECTORY)?;
+    std::fs::write(path, bytes)
+}
+
+/// Reads little-endian values from the start of a byte slice
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if length > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn words(&mut self, count: usize) -> Option<impl Iterator<Item = [u8; 4]> + 'a> {
+        let words = self.take(count.checked_mul(4)?)?;
+        Some(words.chunks_exact(4).map(|word| word.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.words(1)?.next().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn mesh(&mut self) -> Option<Mesh> {
+        let mut lengths = [0; 6];
+        for length in lengths.iter_mut() {
+            *length = self.u32()? as usize;
+        }
+        let mut floats = |length| -> Option<Vec<f32>> {
+            Some(self.words(length)?.map(f32::from_le_bytes).collect())
+        };
+        let mut mesh = Mesh {
+            positions: floats(lengths[0])?,
+            normals: floats(lengths[1])?,
+            uvs: floats(lengths[2])?,
+            colors: floats(lengths[3])?,
+            tangents: floats(lengths[4])?,
+            indices: vec![],
+        };
+        mesh.indices = self.words(lengths[5])?.map(u32::from_le_bytes).collect();
+        mesh.validate().ok()?;
+        Some(mesh)
+    }
+}
+
+/// Store a new stamp in a cache file whose sources were touched but not changed,
+/// so their contents are not hashed again on the next launch
+fn refresh_stamp(path: &Path, stamp: u64) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(STAMP_OFFSET))?;
+    file.write_all(&stamp.to_le_bytes())
+}
+
+/// Meshes from a cache file together with the material libraries it lists,
+/// unless it is missing, outdated or broken
+fn read_cache(path: &Path, obj_path: &Path) -> Option<(ObjMeshes, Vec<PathBuf>)> {
+    // Rather than being memory-mapped, the file is read in one go, since every mesh is
+    // copied into owned buffers for the simplifier and the upload to the GPU anyway
+    let bytes = std::fs::read(path).ok()?;
+    let mut reader = Reader { bytes: &bytes[..] };
+    if reader.take(4)? != MAGIC || reader.u32()? != CACHE_VERSION {
+        return None;
+    }
+    let stamp = reader.u64()?;
+    let hash = reader.u64()?;
+    let mut mtl_paths = vec![];
+    for _ in 0..reader.u32()? {
+        let length = reader.u32()? as usize;
+        let name = std::str::from_utf8(reader.take(length)?).ok()?;
+        mtl_paths.push(PathBuf::from(name));
+    }
+    // Sizes and modification times decide when they match, the contents when they don't
+    let current_stamp = source_stamp(obj_path, &mtl_paths);
+    if stamp != current_stamp {
+        if hash != content_hash(obj_path, &mtl_paths) {
+            return None;
+        }
+        if let Err(error) = refresh_stamp(path, current_stamp) {
+            println!("Could not update {}: {}", path.display(), error);
+        }
+    }
+    let count = reader.u32()?;
+    let mut meshes = vec![];
+    for _ in 0..count {
+        let material_id = reader.u32()? as usize;
+        let level_count = reader.u32()?;
+        let mesh = reader.mesh()?;
+        let levels = (0..level_count)
+            .map(|_| reader.mesh())
+            .collect::<Option<Vec<Mesh>>>()?;
+        meshes.push(CachedMesh {
+            mesh,
+            material_id,
+            levels,
+        });
+    }
+    Some((meshes, mtl_paths))
+}
+
+/// Parse the OBJ file and process its meshes the slow way, simplifying them as well
+fn process_obj(path: &str) -> (ObjMeshes, Vec<tobj::Material>) {
+    let (models, materials) = load_obj(path);
+    let meshes = models
+        .iter()
+        .map(|model| {
+            let material_id = model
+                .mesh
+                .material_id
+                .expect("No material in texture; abort!");
+            let mesh = Mesh::from_obj(model, &materials);
+            let levels = simplify_levels(&mesh);
+            CachedMesh {
+                mesh,
+                material_id,
+                levels,
+            }
+        })
+        .collect();
+    (meshes, materials)
+}
+
+/// Process an OBJ file and write its cache, whether or not there already is one
+pub fn build_cache(path: &str) -> ObjMeshes {
+    let (meshes, _) = process_obj(path);
+    let cache = cache_path(Path::new(path));
+    write_cache(
+        &cache,
+        Path::new(path),
+        &material_paths(Path::new(path)),
+        &meshes,
+    )
+    .unwrap_or_else(|error| panic!("Could not write {}: {}", cache.display(), error));
+    meshes
+}
+
+/// Meshes and materials of an OBJ file, taken from the cache when it matches the file,
+/// otherwise processed and cached for next time
+pub fn load_obj_cached(path: &str) -> (ObjMeshes, Vec<tobj::Material>) {
+    let cache = cache_path(Path::new(path));
+    if let Some((meshes, mtl_paths)) = read_cache(&cache, Path::new(path)) {
+        let materials = mtl_paths
+            .iter()
+            .flat_map(|mtl_path| {
+                tobj::load_mtl(mtl_path)
+                    .unwrap_or_else(|_| panic!("Failed to load {}", mtl_path.display()))
+                    .0
+            })
+            .collect();
+        return (meshes, materials);
+    }
+    let (meshes, materials) = process_obj(path);
+    let mtl_paths = material_paths(Path::new(path));
+    if let Err(error) = write_cache(&cache, Path::new(path), &mtl_paths, &meshes) {
+        println!("Could not cache {}: {}", path, error);
+    }
+    (meshes, materials)
+}
+
+/// Rebuild the caches of all OBJ files in a folder, for the `build-cache` subcommand
+pub fn build_all(directory: &str) {
+    let mut paths: Vec<_> = std::fs::read_dir(directory)
+        .unwrap_or_else(|_| panic!("Could not list {}", directory))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|extension| extension.to_str()) == Some("obj"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let before = std::time::Instant::now();
+        let meshes = build_cache(&path.to_string_lossy());
+        println!(
+            "Cached {} meshes from {} in {} seconds",
+            meshes.len(),
+            path.display(),
+            before.elapsed().as_secs_f32()
+        );
+    }
+}
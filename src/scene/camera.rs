@@ -7,6 +7,8 @@ const FAR: f32 = 100.;
 
 pub trait Camera {
     fn get_position(&self, time: f32) -> glm::Vec3;
+    /// Just the perspective part of the transformation
+    fn projection(&self) -> glm::Mat4;
     fn create_transformation(&mut self, time: f32, delta_time: f32) -> glm::Mat4;
     fn handle_keys(&mut self, keycode: &VirtualKeyCode, time: f32, delta_time: f32);
     fn handle_mouse(&mut self, delta_xy: (f32, f32));
@@ -92,6 +94,10 @@ impl RevolvingCamera {
 }
 
 impl Camera for RevolvingCamera {
+    fn projection(&self) -> glm::Mat4 {
+        self.perspective
+    }
+
     fn get_position(&self, time: f32) -> glm::Vec3 {
         let start = glm::vec3(
             self.radius * self.angle.cos(),
@@ -248,6 +254,10 @@ impl FirstPersonCamera {
 }
 
 impl Camera for FirstPersonCamera {
+    fn projection(&self) -> glm::Mat4 {
+        self.perspective
+    }
+
     fn get_position(&self, _time: f32) -> glm::Vec3 {
         glm::vec3(self.x, self.y, self.z)
     }
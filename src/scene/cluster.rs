@@ -0,0 +1,154 @@
+use glow::*;
+
+use super::{light::GpuLight, vao::to_u8_slice};
+
+/// Number of clusters along each axis of the view frustum.
+/// Must match the defines in world.frag.
+pub const CLUSTERS_X: usize = 16;
+pub const CLUSTERS_Y: usize = 9;
+pub const CLUSTERS_Z: usize = 24;
+const CLUSTER_COUNT: usize = CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z;
+
+/// The view frustum cut into screen tiles and exponential depth slices,
+/// with a list of the lights reaching into each of them,
+/// so fragments only have to consider lights that can actually affect them
+pub struct LightClusters {
+    /// Offset into the index list and number of lights, per cluster
+    grid_buffer: NativeBuffer,
+    /// Light indices for all clusters, one after another
+    index_buffer: NativeBuffer,
+    pub near: f32,
+    pub far: f32,
+    /// View space bounding boxes of the clusters, as (min, max)
+    bounds: Vec<(glm::Vec3, glm::Vec3)>,
+    projection: glm::Mat4,
+}
+
+impl LightClusters {
+    pub unsafe fn new(gl: &glow::Context) -> LightClusters {
+        LightClusters {
+            grid_buffer: gl.create_buffer().expect("Unable to create cluster buffer"),
+            index_buffer: gl.create_buffer().expect("Unable to create cluster buffer"),
+            near: 0.,
+            far: 0.,
+            bounds: vec![],
+            projection: glm::zero(),
+        }
+    }
+
+    /// Recompute the cluster bounds, which only depend on the projection
+    fn build_bounds(&mut self, projection: &glm::Mat4) {
+        // Read near and far back out of the perspective matrix
+        let (a, b) = (projection[(2, 2)], projection[(2, 3)]);
+        self.near = b / (a - 1.);
+        self.far = b / (a + 1.);
+        self.projection = *projection;
+
+        let slice_depth =
+            |k: usize| self.near * (self.far / self.near).powf(k as f32 / CLUSTERS_Z as f32);
+        let mut bounds = Vec::with_capacity(CLUSTER_COUNT);
+        for z in 0..CLUSTERS_Z {
+            let depths = [slice_depth(z), slice_depth(z + 1)];
+            for y in 0..CLUSTERS_Y {
+                for x in 0..CLUSTERS_X {
+                    let ndc_x = [x, x + 1].map(|i| i as f32 / CLUSTERS_X as f32 * 2. - 1.);
+                    let ndc_y = [y, y + 1].map(|i| i as f32 / CLUSTERS_Y as f32 * 2. - 1.);
+                    let mut min = glm::vec3(f32::MAX, f32::MAX, f32::MAX);
+                    let mut max = -min;
+                    // The tile's corners at both ends of the slice
+                    for depth in depths {
+                        for nx in ndc_x {
+                            for ny in ndc_y {
+                                let corner = glm::vec3(
+                                    nx * depth / projection[(0, 0)],
+                                    ny * depth / projection[(1, 1)],
+                                    -depth,
+                                );
+                                min = glm::min2(&min, &corner);
+                                max = glm::max2(&max, &corner);
+                            }
+                        }
+                    }
+                    bounds.push((min, max));
+                }
+            }
+        }
+        self.bounds = bounds;
+    }
+
+    /// Depth slice containing the given distance from the camera, clamped to the frustum
+    fn slice(&self, depth: f32) -> usize {
+        let slice = (depth.max(self.near) / self.near).ln() / (self.far / self.near).ln()
+            * CLUSTERS_Z as f32;
+        (slice.max(0.) as usize).min(CLUSTERS_Z - 1)
+    }
+
+    /// Bin the lights into clusters for this frame's camera and upload the result
+    pub unsafe fn update(
+        &mut self,
+        gl: &glow::Context,
+        lights: &[GpuLight],
+        projection: &glm::Mat4,
+        view: &glm::Mat4,
+    ) {
+        if self.bounds.is_empty() || self.projection != *projection {
+            self.build_bounds(projection);
+        }
+
+        let mut cluster_lights: Vec<Vec<u32>> = vec![vec![]; CLUSTER_COUNT];
+        for (i, light) in lights.iter().enumerate() {
+            let range = light.position[3];
+            if range <= 0. {
+                // Lights without a range reach every cluster
+                for list in cluster_lights.iter_mut() {
+                    list.push(i as u32);
+                }
+                continue;
+            }
+            let center = glm::vec4_to_vec3(
+                &(view * glm::vec4(light.position[0], light.position[1], light.position[2], 1.)),
+            );
+            let depth = -center.z;
+            if depth + range < self.near || depth - range > self.far {
+                continue;
+            }
+            for z in self.slice(depth - range)..=self.slice(depth + range) {
+                for xy in 0..CLUSTERS_X * CLUSTERS_Y {
+                    let index = z * CLUSTERS_X * CLUSTERS_Y + xy;
+                    // Sphere against box: distance to the closest point in the box
+                    let (min, max) = &self.bounds[index];
+                    let closest = glm::clamp_vec(&center, min, max);
+                    if glm::distance2(&closest, &center) <= range * range {
+                        cluster_lights[index].push(i as u32);
+                    }
+                }
+            }
+        }
+
+        let mut grid: Vec<[u32; 2]> = Vec::with_capacity(CLUSTER_COUNT);
+        let mut indices: Vec<u32> = vec![];
+        for list in cluster_lights {
+            grid.push([indices.len() as u32, list.len() as u32]);
+            indices.extend(list);
+        }
+        if indices.is_empty() {
+            // Empty storage buffers can't be bound
+            indices.push(0);
+        }
+
+        for (buffer, data) in [
+            (self.grid_buffer, to_u8_slice(&grid)),
+            (self.index_buffer, to_u8_slice(&indices)),
+        ] {
+            gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, Some(buffer));
+            gl.buffer_data_u8_slice(glow::SHADER_STORAGE_BUFFER, data, glow::DYNAMIC_DRAW);
+        }
+        gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, None);
+    }
+
+    /// Make the clusters available to shaders, next to the lights at binding 0
+    pub unsafe fn bind(&self, gl: &glow::Context) {
+        gl.bind_buffer_base(glow::SHADER_STORAGE_BUFFER, 1, Some(self.grid_buffer));
+        gl.bind_buffer_base(glow::SHADER_STORAGE_BUFFER, 2, Some(self.index_buffer));
+    }
+}
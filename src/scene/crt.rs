@@ -0,0 +1,267 @@
+use glow::*;
+
+use super::{texture::FrameBufferTexture, vao::VAO};
+use crate::shader::Shader;
+
+/// Resolution the screen shaders are rendered at before the CRT filter is applied
+pub const CONTENT_WIDTH: i32 = 768;
+pub const CONTENT_HEIGHT: i32 = 576;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PhosphorMask {
+    None,
+    /// Staggered dot triads, like most consumer TVs
+    ShadowMask,
+    /// Continuous vertical stripes, like a Trinitron
+    ApertureGrille,
+}
+
+/// How the CRT filter should look on one particular screen
+#[derive(Clone, Copy)]
+pub struct CrtSettings {
+    /// Amount of barrel distortion, matching the bulge of the glass
+    pub curvature: f32,
+    /// Number of visible scanlines and how dark the gaps between them are
+    pub scanlines: f32,
+    pub scanline_intensity: f32,
+    pub mask: PhosphorMask,
+    pub mask_intensity: f32,
+    /// How much of the previous frame lingers on the phosphors (0 to 1)
+    pub persistence: f32,
+    /// Offset between the red and blue channels, in content pixels
+    pub chromatic_aberration: f32,
+    pub vignette: f32,
+}
+
+impl Default for CrtSettings {
+    fn default() -> CrtSettings {
+        CrtSettings {
+            curvature: 0.08,
+            scanlines: 240.,
+            scanline_intensity: 0.35,
+            mask: PhosphorMask::ApertureGrille,
+            mask_intensity: 0.25,
+            persistence: 0.6,
+            chromatic_aberration: 1.5,
+            vignette: 0.4,
+        }
+    }
+}
+
+/// Offscreen buffers for one screen: the raw shader output,
+/// and two phosphor buffers that take turns holding the current and previous frame
+pub struct CrtScreen {
+    pub settings: CrtSettings,
+    content: FrameBufferTexture,
+    phosphors: [FrameBufferTexture; 2],
+    current: usize,
+    /// Pixel buffer the average color is copied into without waiting for the GPU,
+    /// and the fence telling when the copy is done
+    readback: NativeBuffer,
+    readback_fence: Option<NativeFence>,
+    average: glm::Vec3,
+}
+
+impl CrtScreen {
+    pub unsafe fn new(gl: &glow::Context, settings: CrtSettings) -> CrtScreen {
+        CrtScreen {
+            settings,
+            content: FrameBufferTexture::new(gl, CONTENT_WIDTH, CONTENT_HEIGHT),
+            phosphors: [
+                FrameBufferTexture::new(gl, CONTENT_WIDTH, CONTENT_HEIGHT),
+                FrameBufferTexture::new(gl, CONTENT_WIDTH, CONTENT_HEIGHT),
+            ],
+            current: 0,
+            readback: create_readback_buffer(gl),
+            readback_fence: None,
+            average: glm::zero(),
+        }
+    }
+
+    /// The phosphor state after the latest frame
+    pub fn output(&self) -> NativeTexture {
+        self.phosphors[self.current].texture
+    }
+
+    /// Reduce the phosphors down to a single pixel and start copying it into the pixel buffer,
+    /// unless the previous copy is still on its way
+    pub unsafe fn request_average_color(&mut self, gl: &glow::Context) {
+        if self.readback_fence.is_some() {
+            return;
+        }
+        let smallest_mip = (CONTENT_WIDTH.max(CONTENT_HEIGHT) as f32).log2() as i32;
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.output()));
+        gl.generate_mipmap(glow::TEXTURE_2D);
+        gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(self.readback));
+        gl.get_tex_image(
+            glow::TEXTURE_2D,
+            smallest_mip,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            PixelPackData::BufferOffset(0),
+        );
+        gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+        self.readback_fence = Some(
+            gl.fence_sync(glow::SYNC_GPU_COMMANDS_COMPLETE, 0)
+                .expect("Could not create fence"),
+        );
+    }
+
+    /// Average color of what the screen is showing, as of the latest copy that has finished.
+    /// This lags a frame or so behind, but never stalls waiting for the GPU.
+    pub unsafe fn average_color(&mut self, gl: &glow::Context) -> glm::Vec3 {
+        if let Some(fence) = self.readback_fence {
+            let status = gl.client_wait_sync(fence, 0, 0);
+            if status == glow::ALREADY_SIGNALED || status == glow::CONDITION_SATISFIED {
+                gl.delete_sync(fence);
+                self.readback_fence = None;
+                let mut pixel = [0u8; 4];
+                gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(self.readback));
+                gl.get_buffer_sub_data(glow::PIXEL_PACK_BUFFER, 0, &mut pixel);
+                gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+                self.average = glm::vec3(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.;
+            }
+        }
+        self.average
+    }
+}
+
+/// Room for one RGBA pixel, to be read back by the CPU
+unsafe fn create_readback_buffer(gl: &glow::Context) -> NativeBuffer {
+    let buffer = gl.create_buffer().expect("Could not create buffer");
+    gl.bind_buffer(glow::PIXEL_PACK_BUFFER, Some(buffer));
+    gl.buffer_data_size(glow::PIXEL_PACK_BUFFER, 4, glow::STREAM_READ);
+    gl.bind_buffer(glow::PIXEL_PACK_BUFFER, None);
+    buffer
+}
+
+/// Shaders shared by all screens for turning raw content into something CRT-like
+pub struct CrtFilter {
+    phosphor_shader: Shader,
+    display_shader: Shader,
+    canvas: VAO,
+}
+
+impl CrtFilter {
+    pub unsafe fn new(gl: &glow::Context) -> CrtFilter {
+        CrtFilter {
+            phosphor_shader: Shader::new(
+                gl,
+                "res/shaders/post.vert",
+                "res/shaders/crt/phosphor.frag",
+            ),
+            display_shader: Shader::new(
+                gl,
+                "res/shaders/screen.vert",
+                "res/shaders/crt/display.frag",
+            ),
+            canvas: VAO::square(gl),
+        }
+    }
+
+    /// Run the screen's own shader over its whole content buffer,
+    /// then let the phosphors fade from the previous frame towards the new one
+    pub unsafe fn render_content(
+        &self,
+        gl: &glow::Context,
+        screen: &mut CrtScreen,
+        content_shader: NativeProgram,
+        time: f32,
+    ) {
+        gl.bind_framebuffer(glow::FRAMEBUFFER, screen.content.framebuffer);
+        gl.viewport(0, 0, CONTENT_WIDTH, CONTENT_HEIGHT);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        gl.use_program(Some(content_shader));
+        gl.uniform_1_f32(
+            gl.get_uniform_location(content_shader, "time").as_ref(),
+            time,
+        );
+        gl.uniform_2_f32(
+            gl.get_uniform_location(content_shader, "screen_size")
+                .as_ref(),
+            CONTENT_WIDTH as f32,
+            CONTENT_HEIGHT as f32,
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(content_shader, "view_transform")
+                .as_ref(),
+            false,
+            glm::identity::<f32, 4>().as_slice(),
+        );
+        self.canvas.draw(gl);
+
+        let previous = screen.phosphors[screen.current].texture;
+        screen.current = 1 - screen.current;
+        let phosphor = screen.phosphors[screen.current];
+        gl.bind_framebuffer(glow::FRAMEBUFFER, phosphor.framebuffer);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        let program = self.phosphor_shader.program;
+        self.phosphor_shader.activate(gl);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "persistence").as_ref(),
+            screen.settings.persistence,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(screen.content.texture));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(previous));
+        self.canvas.draw(gl);
+    }
+
+    /// Draw the screen mesh with its phosphor buffer seen through curved, masked glass
+    /// (to whichever framebuffer is bound outside this code)
+    pub unsafe fn display(
+        &self,
+        gl: &glow::Context,
+        screen: &CrtScreen,
+        vao: &VAO,
+        view_transform: &glm::Mat4,
+    ) {
+        let program = self.display_shader.program;
+        let settings = &screen.settings;
+        self.display_shader.activate(gl);
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "view_transform").as_ref(),
+            false,
+            view_transform.as_slice(),
+        );
+        gl.uniform_2_f32(
+            gl.get_uniform_location(program, "content_size").as_ref(),
+            CONTENT_WIDTH as f32,
+            CONTENT_HEIGHT as f32,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "curvature").as_ref(),
+            settings.curvature,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "scanlines").as_ref(),
+            settings.scanlines,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "scanline_intensity")
+                .as_ref(),
+            settings.scanline_intensity,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "mask").as_ref(),
+            settings.mask as i32,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "mask_intensity").as_ref(),
+            settings.mask_intensity,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "chromatic_aberration")
+                .as_ref(),
+            settings.chromatic_aberration,
+        );
+        gl.uniform_1_f32(
+            gl.get_uniform_location(program, "vignette").as_ref(),
+            settings.vignette,
+        );
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(screen.output()));
+        vao.draw(gl);
+    }
+}
@@ -0,0 +1,133 @@
+use glow::*;
+
+use super::{
+    environment::Environment,
+    graph::SceneGraph,
+    texture::{GBuffer, PostProcessingTexture},
+    vao::VAO,
+};
+use crate::shader::Shader;
+
+/// Alternative to the forward renderer: surface properties are written to a G-buffer first,
+/// then lighting is computed once per pixel in a full-screen pass
+pub struct DeferredRenderer {
+    pub gbuffer: GBuffer,
+    /// Image-based lighting to use instead of the flat ambient term, if any
+    pub environment: Option<Environment>,
+    geometry_shader: Shader,
+    lighting_shader: Shader,
+    canvas: VAO,
+}
+
+impl DeferredRenderer {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> DeferredRenderer {
+        DeferredRenderer {
+            gbuffer: GBuffer::new(gl, width, height),
+            environment: None,
+            geometry_shader: Shader::new(gl, "res/shaders/world.vert", "res/shaders/gbuffer.frag"),
+            lighting_shader: Shader::new(gl, "res/shaders/post.vert", "res/shaders/deferred.frag"),
+            canvas: VAO::square(gl),
+        }
+    }
+
+    /// Fill the G-buffer with everything except the screens and transparent nodes
+    pub unsafe fn render_geometry(
+        &self,
+        gl: &glow::Context,
+        scene_graph: &SceneGraph,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+    ) {
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.gbuffer.framebuffer));
+        gl.viewport(0, 0, self.gbuffer.width, self.gbuffer.height);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        // Blending would mix surface properties, which makes no sense
+        gl.disable(glow::BLEND);
+        self.geometry_shader.activate(gl);
+        scene_graph.render_opaque_with_shader(
+            gl,
+            self.geometry_shader.program,
+            scene_graph.root,
+            view_transform,
+            camera_position,
+            true,
+        );
+        gl.enable(glow::BLEND);
+    }
+
+    /// Light the G-buffer into the target, and copy the depth over
+    /// so forward-rendered geometry can be drawn on top afterwards.
+    /// Lights are read from the buffer bound by the scene graph.
+    pub unsafe fn render_lighting(
+        &self,
+        gl: &glow::Context,
+        target: &PostProcessingTexture,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        ambient_occlusion: Option<NativeTexture>,
+        mode: i32,
+    ) {
+        gl.bind_framebuffer(glow::READ_FRAMEBUFFER, Some(self.gbuffer.framebuffer));
+        gl.bind_framebuffer(glow::DRAW_FRAMEBUFFER, Some(target.framebuffer));
+        gl.blit_framebuffer(
+            0,
+            0,
+            self.gbuffer.width,
+            self.gbuffer.height,
+            0,
+            0,
+            target.width,
+            target.height,
+            glow::DEPTH_BUFFER_BIT,
+            glow::NEAREST,
+        );
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(target.framebuffer));
+        gl.viewport(0, 0, target.width, target.height);
+        gl.clear(glow::COLOR_BUFFER_BIT);
+        gl.disable(glow::DEPTH_TEST);
+        let program = self.lighting_shader.program;
+        self.lighting_shader.activate(gl);
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "inverse_view_projection")
+                .as_ref(),
+            false,
+            glm::inverse(view_transform).as_slice(),
+        );
+        gl.uniform_3_f32_slice(
+            gl.get_uniform_location(program, "camera_position").as_ref(),
+            camera_position.as_slice(),
+        );
+        gl.uniform_1_i32(gl.get_uniform_location(program, "mode").as_ref(), mode);
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "use_ssao").as_ref(),
+            ambient_occlusion.is_some() as i32,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "use_environment").as_ref(),
+            self.environment.is_some() as i32,
+        );
+        if let Some(environment) = &self.environment {
+            gl.uniform_1_f32(
+                gl.get_uniform_location(program, "environment_intensity")
+                    .as_ref(),
+                environment.intensity,
+            );
+        }
+        for (unit, texture) in [
+            (glow::TEXTURE0, self.gbuffer.albedo_texture),
+            (glow::TEXTURE1, self.gbuffer.normal_texture),
+            (glow::TEXTURE2, self.gbuffer.material_texture),
+            (glow::TEXTURE3, self.gbuffer.depth_texture),
+        ] {
+            gl.active_texture(unit);
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        }
+        if let Some(texture) = ambient_occlusion {
+            gl.active_texture(glow::TEXTURE6);
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        }
+        self.canvas.draw(gl);
+        gl.enable(glow::DEPTH_TEST);
+    }
+}
@@ -0,0 +1,258 @@
+use glow::*;
+
+use super::vao::{to_u8_slice, VAO};
+use crate::shader::Shader;
+
+const CUBEMAP_SIZE: i32 = 512;
+const IRRADIANCE_SIZE: i32 = 32;
+const PREFILTERED_SIZE: i32 = 128;
+/// Must match PREFILTERED_MIPS in environment.glsl
+const PREFILTERED_MIPS: i32 = 5;
+const BRDF_LUT_SIZE: i32 = 512;
+
+/// Image-based lighting from an equirectangular HDR image,
+/// precomputed into the maps the split-sum approximation needs
+#[derive(Clone, Copy)]
+pub struct Environment {
+    /// Cosine-weighted hemisphere average for diffuse lighting
+    pub irradiance: NativeTexture,
+    /// Environment blurred by increasing roughness down the mip chain, for glossy reflections
+    pub prefiltered: NativeTexture,
+    /// Scale and bias to the Fresnel term by angle and roughness
+    pub brdf_lut: NativeTexture,
+    pub intensity: f32,
+}
+
+/// Empty half-float cubemap, optionally with room for mipmaps
+unsafe fn create_cubemap(gl: &glow::Context, size: i32, mipmapped: bool) -> NativeTexture {
+    let texture = gl.create_texture().expect("Could not create texture");
+    gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(texture));
+    for face in 0..6 {
+        gl.tex_image_2d(
+            glow::TEXTURE_CUBE_MAP_POSITIVE_X + face,
+            0,
+            glow::RGBA16F as i32,
+            size,
+            size,
+            0,
+            glow::RGBA,
+            glow::FLOAT,
+            None,
+        );
+    }
+    gl.tex_parameter_i32(
+        glow::TEXTURE_CUBE_MAP,
+        glow::TEXTURE_MAG_FILTER,
+        glow::LINEAR as i32,
+    );
+    gl.tex_parameter_i32(
+        glow::TEXTURE_CUBE_MAP,
+        glow::TEXTURE_MIN_FILTER,
+        if mipmapped {
+            glow::LINEAR_MIPMAP_LINEAR
+        } else {
+            glow::LINEAR
+        } as i32,
+    );
+    for wrap in [
+        glow::TEXTURE_WRAP_R,
+        glow::TEXTURE_WRAP_S,
+        glow::TEXTURE_WRAP_T,
+    ] {
+        gl.tex_parameter_i32(glow::TEXTURE_CUBE_MAP, wrap, glow::CLAMP_TO_EDGE as i32);
+    }
+    if mipmapped {
+        gl.generate_mipmap(glow::TEXTURE_CUBE_MAP);
+    }
+    texture
+}
+
+impl Environment {
+    /// Load an equirectangular `.hdr` or `.exr` image and precompute everything from it
+    pub unsafe fn from_file(gl: &glow::Context, path: &str) -> Environment {
+        let image = image::open(path)
+            .unwrap_or_else(|error| panic!("Could not load environment map {}: {}", path, error))
+            .into_rgb32f();
+        let equirectangular = gl.create_texture().expect("Could not create texture");
+        gl.bind_texture(glow::TEXTURE_2D, Some(equirectangular));
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGB16F as i32,
+            image.width() as i32,
+            image.height() as i32,
+            0,
+            glow::RGB,
+            glow::FLOAT,
+            Some(to_u8_slice(image.as_raw())),
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::LINEAR as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::LINEAR as i32,
+        );
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::REPEAT as i32);
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+
+        let canvas = VAO::square(gl);
+        let framebuffer = gl
+            .create_framebuffer()
+            .expect("Could not create framebuffer");
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+        gl.disable(glow::DEPTH_TEST);
+        gl.disable(glow::BLEND);
+
+        // Draw a full-screen pass into every face of a cubemap level
+        let render_faces = |shader: &Shader, target: NativeTexture, size: i32, level: i32| {
+            gl.viewport(0, 0, size, size);
+            for face in 0..6 {
+                gl.framebuffer_texture_2d(
+                    glow::FRAMEBUFFER,
+                    glow::COLOR_ATTACHMENT0,
+                    glow::TEXTURE_CUBE_MAP_POSITIVE_X + face,
+                    Some(target),
+                    level,
+                );
+                gl.uniform_1_i32(
+                    gl.get_uniform_location(shader.program, "face").as_ref(),
+                    face as i32,
+                );
+                canvas.draw(gl);
+            }
+        };
+
+        // Reproject onto a cubemap, with mips for the convolutions below
+        let cubemap = create_cubemap(gl, CUBEMAP_SIZE, true);
+        let shader = Shader::new(
+            gl,
+            "res/shaders/post.vert",
+            "res/shaders/ibl/equirectangular.frag",
+        );
+        shader.activate(gl);
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(equirectangular));
+        render_faces(&shader, cubemap, CUBEMAP_SIZE, 0);
+        gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(cubemap));
+        gl.generate_mipmap(glow::TEXTURE_CUBE_MAP);
+        gl.delete_texture(equirectangular);
+        gl.delete_program(shader.program);
+
+        let irradiance = create_cubemap(gl, IRRADIANCE_SIZE, false);
+        let shader = Shader::new(
+            gl,
+            "res/shaders/post.vert",
+            "res/shaders/ibl/irradiance.frag",
+        );
+        shader.activate(gl);
+        gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(cubemap));
+        render_faces(&shader, irradiance, IRRADIANCE_SIZE, 0);
+        gl.delete_program(shader.program);
+
+        // One roughness per mip, from mirror-like to fully rough
+        let prefiltered = create_cubemap(gl, PREFILTERED_SIZE, true);
+        let shader = Shader::new(
+            gl,
+            "res/shaders/post.vert",
+            "res/shaders/ibl/prefilter.frag",
+        );
+        shader.activate(gl);
+        gl.uniform_1_f32(
+            gl.get_uniform_location(shader.program, "resolution")
+                .as_ref(),
+            CUBEMAP_SIZE as f32,
+        );
+        gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(cubemap));
+        for level in 0..PREFILTERED_MIPS {
+            gl.uniform_1_f32(
+                gl.get_uniform_location(shader.program, "roughness")
+                    .as_ref(),
+                level as f32 / (PREFILTERED_MIPS - 1) as f32,
+            );
+            render_faces(&shader, prefiltered, PREFILTERED_SIZE >> level, level);
+        }
+        gl.delete_program(shader.program);
+
+        let brdf_lut = gl.create_texture().expect("Could not create texture");
+        gl.bind_texture(glow::TEXTURE_2D, Some(brdf_lut));
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RG16F as i32,
+            BRDF_LUT_SIZE,
+            BRDF_LUT_SIZE,
+            0,
+            glow::RG,
+            glow::FLOAT,
+            None,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::LINEAR as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::LINEAR as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        let shader = Shader::new(gl, "res/shaders/post.vert", "res/shaders/ibl/brdf.frag");
+        shader.activate(gl);
+        gl.framebuffer_texture_2d(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::TEXTURE_2D,
+            Some(brdf_lut),
+            0,
+        );
+        gl.viewport(0, 0, BRDF_LUT_SIZE, BRDF_LUT_SIZE);
+        canvas.draw(gl);
+        gl.delete_program(shader.program);
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+        gl.delete_framebuffer(framebuffer);
+        gl.enable(glow::DEPTH_TEST);
+        gl.enable(glow::BLEND);
+
+        // Only the convolved maps are needed from here on
+        gl.delete_texture(cubemap);
+
+        Environment {
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            intensity: 1.,
+        }
+    }
+
+    /// Bind the precomputed maps where environment.glsl expects them
+    pub unsafe fn bind(&self, gl: &glow::Context) {
+        for (unit, target, texture) in [
+            (glow::TEXTURE7, glow::TEXTURE_CUBE_MAP, self.irradiance),
+            (glow::TEXTURE8, glow::TEXTURE_CUBE_MAP, self.prefiltered),
+            (glow::TEXTURE9, glow::TEXTURE_2D, self.brdf_lut),
+        ] {
+            gl.active_texture(unit);
+            gl.bind_texture(target, Some(texture));
+        }
+        gl.active_texture(glow::TEXTURE0);
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glow::*;
+use image::RgbaImage;
+
+use super::graph::{BlendMode, SceneGraph};
+use super::mesh::Mesh;
+use super::texture::FrameBufferTexture;
+
+/// Where the scene is written when exporting from the running program
+pub const EXPORT_DIRECTORY: &str = "export";
+
+/// Image files glTF viewers are required to read, the rest are converted to PNG
+const PORTABLE_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+/// Surface of one or more exported meshes, as the nodes are drawn
+#[derive(Clone, Copy)]
+struct ExportMaterial {
+    /// Average vertex color, which is the diffuse color for meshes from OBJ files
+    color: glm::Vec4,
+    texture: Option<FrameBufferTexture>,
+    normal_map: Option<FrameBufferTexture>,
+    roughness_map: Option<FrameBufferTexture>,
+    opacity_map: Option<FrameBufferTexture>,
+    blend_mode: BlendMode,
+    emission: glm::Vec3,
+}
+
+impl ExportMaterial {
+    fn textures(&self) -> [Option<NativeTexture>; 4] {
+        [
+            self.texture,
+            self.normal_map,
+            self.roughness_map,
+            self.opacity_map,
+        ]
+        .map(|texture| texture.map(|texture| texture.texture))
+    }
+
+    fn same_as(&self, other: &ExportMaterial) -> bool {
+        self.color == other.color
+            && self.textures() == other.textures()
+            && self.blend_mode == other.blend_mode
+            && self.emission == other.emission
+    }
+}
+
+/// Geometry of the whole scene in world space, one mesh per node, with its material index
+struct SceneExport {
+    meshes: Vec<(Mesh, usize)>,
+    materials: Vec<ExportMaterial>,
+}
+
+/// Move a mesh into world space. Mirroring transforms flip the winding and the tangent handedness back.
+fn transform_mesh(mesh: &Mesh, model_matrix: &glm::Mat4) -> Mesh {
+    let linear = glm::mat4_to_mat3(model_matrix);
+    let normal_matrix = glm::transpose(&glm::inverse(&linear));
+    let handedness = linear.determinant().signum();
+    let mut world = mesh.clone();
+    for position in world.positions.chunks_mut(3) {
+        let moved = model_matrix * glm::vec4(position[0], position[1], position[2], 1.);
+        position.copy_from_slice(moved.xyz().as_slice());
+    }
+    for normal in world.normals.chunks_mut(3) {
+        let turned = glm::normalize(&(normal_matrix * glm::make_vec3(normal)));
+        normal.copy_from_slice(turned.as_slice());
+    }
+    for tangent in world.tangents.chunks_mut(4) {
+        let turned = glm::normalize(&(linear * glm::make_vec3(&tangent[..3])));
+        tangent[..3].copy_from_slice(turned.as_slice());
+        tangent[3] *= handedness;
+    }
+    if handedness < 0. {
+        for triangle in world.indices.chunks_mut(3) {
+            triangle.swap(1, 2);
+        }
+    }
+    world
+}
+
+impl SceneExport {
+    /// Every node with a mesh kept on the CPU side, as it is currently placed
+    fn new(scene_graph: &SceneGraph) -> SceneExport {
+        let mut export = SceneExport {
+            meshes: vec![],
+            materials: vec![],
+        };
+        for node in &scene_graph.nodes {
+            let mesh = match (&node.mesh, node.vao) {
+                (Some(mesh), Some(_)) if !mesh.indices.is_empty() => mesh,
+                _ => continue,
+            };
+            let vertex_count = mesh.vertex_count().max(1) as f32;
+            let color = mesh
+                .colors
+                .chunks(4)
+                .fold(glm::Vec4::zeros(), |sum, color| sum + glm::make_vec4(color))
+                / vertex_count;
+            let material = ExportMaterial {
+                color,
+                texture: node.texture,
+                normal_map: node.normal_map,
+                roughness_map: node.roughness_map,
+                opacity_map: node.opacity_map,
+                blend_mode: node.blend_mode,
+                emission: node.emission,
+            };
+            let index = match export.materials.iter().position(|m| m.same_as(&material)) {
+                Some(index) => index,
+                None => {
+                    export.materials.push(material);
+                    export.materials.len() - 1
+                }
+            };
+            export
+                .meshes
+                .push((transform_mesh(mesh, &node.model_matrix), index));
+        }
+        export
+    }
+}
+
+/// Copies or reads back the textures an export refers to, each only once
+struct TextureWriter<'a> {
+    gl: &'a glow::Context,
+    files: &'a HashMap<NativeTexture, PathBuf>,
+    directory: PathBuf,
+    /// Relative paths of what has been written, by texture and whether it had to be portable
+    written: HashMap<(NativeTexture, bool), String>,
+    /// Color textures combined with opacity maps, by both
+    combined: HashMap<(Option<NativeTexture>, NativeTexture), String>,
+    /// Every file in the texture folder
+    names: Vec<String>,
+}
+
+impl<'a> TextureWriter<'a> {
+    fn new(
+        gl: &'a glow::Context,
+        files: &'a HashMap<NativeTexture, PathBuf>,
+        directory: &Path,
+    ) -> TextureWriter<'a> {
+        fs::create_dir_all(directory.join("textures"))
+            .unwrap_or_else(|error| panic!("Could not create {}: {}", directory.display(), error));
+        TextureWriter {
+            gl,
+            files,
+            directory: directory.to_path_buf(),
+            written: HashMap::new(),
+            combined: HashMap::new(),
+            names: vec![],
+        }
+    }
+
+    /// Pixels of a texture as they are on the GPU, with the first row at the top like an image file
+    unsafe fn read(&self, texture: &FrameBufferTexture) -> RgbaImage {
+        let (width, height) = (texture.width.max(1) as u32, texture.height.max(1) as u32);
+        let mut pixels = vec![0; (width * height * 4) as usize];
+        self.gl
+            .bind_texture(glow::TEXTURE_2D, Some(texture.texture));
+        self.gl.get_tex_image(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            PixelPackData::Slice(&mut pixels),
+        );
+        RgbaImage::from_raw(width, height, pixels).unwrap()
+    }
+
+    /// Claim a file name in the texture folder that isn't taken yet
+    fn free_name(&mut self, name: &str) -> String {
+        let path = (0..)
+            .map(|i| match i {
+                0 => format!("textures/{}", name),
+                _ => format!("textures/{}_{}", i, name),
+            })
+            .find(|candidate| !self.names.contains(candidate))
+            .unwrap();
+        self.names.push(path.clone());
+        path
+    }
+
+    /// Path of the texture relative to the export, copied from the file it was loaded from if
+    /// there is one (and it is a format everything reads, if portable), otherwise saved as PNG
+    unsafe fn path(&mut self, texture: &FrameBufferTexture, portable: bool) -> String {
+        let key = (texture.texture, portable);
+        if let Some(path) = self.written.get(&key) {
+            return path.clone();
+        }
+        let source = self.files.get(&texture.texture).filter(|file| {
+            let extension = file.extension().and_then(|extension| extension.to_str());
+            !portable || extension.is_some_and(|e| PORTABLE_EXTENSIONS.contains(&e))
+        });
+        let path = match source {
+            Some(file) => {
+                let path = self.free_name(&file.file_name().unwrap().to_string_lossy());
+                fs::copy(file, self.directory.join(&path))
+                    .unwrap_or_else(|error| panic!("Could not copy {}: {}", file.display(), error));
+                path
+            }
+            None => {
+                let path = self.free_name(&format!("texture_{}.png", self.names.len()));
+                self.save(&self.read(texture), &path);
+                path
+            }
+        };
+        self.written.insert(key, path.clone());
+        path
+    }
+
+    fn save(&self, image: &RgbaImage, path: &str) {
+        image
+            .save(self.directory.join(path))
+            .unwrap_or_else(|error| panic!("Could not save {}: {}", path, error));
+    }
+
+    /// Color texture with the opacity map moved into its alpha channel, the way glTF wants it
+    unsafe fn with_opacity(
+        &mut self,
+        texture: Option<&FrameBufferTexture>,
+        opacity_map: &FrameBufferTexture,
+    ) -> String {
+        let key = (texture.map(|texture| texture.texture), opacity_map.texture);
+        if let Some(path) = self.combined.get(&key) {
+            return path.clone();
+        }
+        let opacity = self.read(opacity_map);
+        let mut color = match texture {
+            Some(texture) => self.read(texture),
+            None => RgbaImage::from_pixel(opacity.width(), opacity.height(), [255; 4].into()),
+        };
+        let opacity = image::imageops::resize(
+            &opacity,
+            color.width(),
+            color.height(),
+            image::imageops::FilterType::Nearest,
+        );
+        for (pixel, alpha) in color.pixels_mut().zip(opacity.pixels()) {
+            pixel[3] = alpha[0];
+        }
+        let path = self.free_name(&format!("texture_{}.png", self.names.len()));
+        self.save(&color, &path);
+        self.combined.insert(key, path.clone());
+        path
+    }
+}
+
+/// Write the scene as an OBJ file with an MTL file and textures next to it,
+/// baking the node transforms into the vertices and keeping vertex colors as `v x y z r g b`
+pub unsafe fn export_obj(gl: &glow::Context, scene_graph: &SceneGraph, path: &Path) {
+    let export = SceneExport::new(scene_graph);
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut textures = TextureWriter::new(gl, &scene_graph.texture_files, directory);
+    let mtl_path = path.with_extension("mtl");
+
+    let mut mtl = String::new();
+    for (i, material) in export.materials.iter().enumerate() {
+        let color = material.color;
+        let emission = material.emission;
+        mtl += &format!("newmtl material_{}\n", i);
+        mtl += &format!("Kd {} {} {}\n", color.x, color.y, color.z);
+        mtl += &format!("Ke {} {} {}\n", emission.x, emission.y, emission.z);
+        if material.blend_mode == BlendMode::Transparent && material.opacity_map.is_none() {
+            mtl += &format!("d {}\n", color.w);
+        }
+        for (statement, texture) in [
+            ("map_Kd", material.texture),
+            ("map_Bump", material.normal_map),
+            ("map_Pr", material.roughness_map),
+            ("map_d", material.opacity_map),
+        ] {
+            if let Some(texture) = texture {
+                mtl += &format!("{} {}\n", statement, textures.path(&texture, false));
+            }
+        }
+        mtl += "\n";
+    }
+
+    let mut obj = format!(
+        "mtllib {}\n",
+        mtl_path.file_name().unwrap().to_string_lossy()
+    );
+    // OBJ indices count from 1 and keep counting across objects
+    let mut offset = 1;
+    for (i, (mesh, material)) in export.meshes.iter().enumerate() {
+        obj += &format!("o node_{}\n", i);
+        for (position, color) in mesh.positions.chunks(3).zip(mesh.colors.chunks(4)) {
+            obj += &format!(
+                "v {} {} {} {} {} {}\n",
+                position[0], position[1], position[2], color[0], color[1], color[2]
+            );
+        }
+        for uv in mesh.uvs.chunks(2) {
+            obj += &format!("vt {} {}\n", uv[0], uv[1]);
+        }
+        for normal in mesh.normals.chunks(3) {
+            obj += &format!("vn {} {} {}\n", normal[0], normal[1], normal[2]);
+        }
+        obj += &format!("usemtl material_{}\n", material);
+        let has_uvs = !mesh.uvs.is_empty();
+        for triangle in mesh.indices.chunks(3) {
+            let corners: Vec<String> = triangle
+                .iter()
+                .map(|&index| {
+                    let index = index as usize + offset;
+                    if has_uvs {
+                        format!("{0}/{0}/{0}", index)
+                    } else {
+                        format!("{0}//{0}", index)
+                    }
+                })
+                .collect();
+            obj += &format!("f {}\n", corners.join(" "));
+        }
+        offset += mesh.vertex_count();
+    }
+
+    fs::write(&mtl_path, mtl)
+        .unwrap_or_else(|error| panic!("Could not write {}: {}", mtl_path.display(), error));
+    fs::write(path, obj)
+        .unwrap_or_else(|error| panic!("Could not write {}: {}", path.display(), error));
+}
+
+/// Binary buffer of a glTF file with the JSON describing its parts
+#[derive(Default)]
+struct GltfBuffer {
+    bytes: Vec<u8>,
+    buffer_views: Vec<String>,
+    accessors: Vec<String>,
+}
+
+impl GltfBuffer {
+    /// Add tightly packed data as its own buffer view and accessor, returning the accessor index
+    fn push(&mut self, values: &[u8], target: u32, accessor: String) -> usize {
+        self.buffer_views.push(format!(
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{}}}"#,
+            self.bytes.len(),
+            values.len(),
+            target
+        ));
+        self.bytes.extend_from_slice(values);
+        // The views of the next attribute have to start aligned
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+        self.accessors.push(accessor.replacen(
+            '{',
+            &format!(r#"{{"bufferView":{},"#, self.buffer_views.len() - 1),
+            1,
+        ));
+        self.accessors.len() - 1
+    }
+
+    fn push_floats(&mut self, values: &[f32], components: usize, bounds: bool) -> usize {
+        let kind = ["SCALAR", "VEC2", "VEC3", "VEC4"][components - 1];
+        let mut accessor = format!(
+            r#"{{"componentType":5126,"count":{},"type":"{}""#,
+            values.len() / components,
+            kind
+        );
+        // Positions need their bounds
+        if bounds {
+            let (mut min, mut max) = (vec![f32::MAX; components], vec![f32::MIN; components]);
+            for value in values.chunks(components) {
+                for axis in 0..components {
+                    min[axis] = min[axis].min(value[axis]);
+                    max[axis] = max[axis].max(value[axis]);
+                }
+            }
+            accessor += &format!(r#","min":{:?},"max":{:?}"#, min, max);
+        }
+        accessor += "}";
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.push(&bytes, glow::ARRAY_BUFFER, accessor)
+    }
+
+    fn push_indices(&mut self, indices: &[u32]) -> usize {
+        let accessor = format!(
+            r#"{{"componentType":5125,"count":{},"type":"SCALAR"}}"#,
+            indices.len()
+        );
+        let bytes: Vec<u8> = indices
+            .iter()
+            .flat_map(|index| index.to_le_bytes())
+            .collect();
+        self.push(&bytes, glow::ELEMENT_ARRAY_BUFFER, accessor)
+    }
+}
+
+/// Write the scene as a glTF file with its buffer and textures next to it,
+/// baking the node transforms into the vertices and converting materials to metallic-roughness
+pub unsafe fn export_gltf(gl: &glow::Context, scene_graph: &SceneGraph, path: &Path) {
+    let export = SceneExport::new(scene_graph);
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut textures = TextureWriter::new(gl, &scene_graph.texture_files, directory);
+    let bin_path = path.with_extension("bin");
+    let mut buffer = GltfBuffer::default();
+
+    // Textures are listed in the order they are first used, each as an image of its own
+    let mut images: Vec<String> = vec![];
+    let mut texture_index = |path: String| match images.iter().position(|image| *image == path) {
+        Some(index) => index,
+        None => {
+            images.push(path);
+            images.len() - 1
+        }
+    };
+    let mut materials = vec![];
+    let mut extensions: Vec<String> = vec![];
+    for material in &export.materials {
+        let color = material.color;
+        let mut pbr = format!(
+            r#""baseColorFactor":[1,1,1,{}],"metallicFactor":0,"roughnessFactor":1"#,
+            if material.opacity_map.is_some() {
+                1.
+            } else {
+                color.w
+            }
+        );
+        let base_color = match (material.opacity_map, material.blend_mode) {
+            (Some(opacity_map), BlendMode::Cutout { .. } | BlendMode::Transparent) => {
+                Some(textures.with_opacity(material.texture.as_ref(), &opacity_map))
+            }
+            _ => material
+                .texture
+                .map(|texture| textures.path(&texture, true)),
+        };
+        if let Some(path) = base_color {
+            pbr += &format!(r#","baseColorTexture":{{"index":{}}}"#, texture_index(path));
+        }
+        // Roughness is read from green in glTF, and the maps here are gray
+        if let Some(texture) = material.roughness_map {
+            let index = texture_index(textures.path(&texture, true));
+            pbr += &format!(r#","metallicRoughnessTexture":{{"index":{}}}"#, index);
+        }
+        let mut json = format!(r#"{{"pbrMetallicRoughness":{{{}}}"#, pbr);
+        if let Some(texture) = material.normal_map {
+            let index = texture_index(textures.path(&texture, true));
+            json += &format!(r#","normalTexture":{{"index":{}}}"#, index);
+        }
+        // Emission above 1 needs an extension to be kept as strong as it is
+        let strength = material.emission.max().max(1.);
+        let emission = material.emission / strength;
+        json += &format!(
+            r#","emissiveFactor":[{},{},{}]"#,
+            emission.x, emission.y, emission.z
+        );
+        if strength > 1. {
+            extensions = vec![r#""KHR_materials_emissive_strength""#.to_string()];
+            json += &format!(
+                r#","extensions":{{"KHR_materials_emissive_strength":{{"emissiveStrength":{}}}}}"#,
+                strength
+            );
+        }
+        match material.blend_mode {
+            BlendMode::Opaque => {}
+            BlendMode::Cutout { threshold } => {
+                json += &format!(r#","alphaMode":"MASK","alphaCutoff":{}"#, threshold)
+            }
+            BlendMode::Transparent => json += r#","alphaMode":"BLEND""#,
+        }
+        json += "}";
+        materials.push(json);
+    }
+
+    let mut meshes = vec![];
+    for (mesh, material) in &export.meshes {
+        let mut attributes = vec![
+            format!(
+                r#""POSITION":{}"#,
+                buffer.push_floats(&mesh.positions, 3, true)
+            ),
+            format!(
+                r#""NORMAL":{}"#,
+                buffer.push_floats(&mesh.normals, 3, false)
+            ),
+            format!(
+                r#""COLOR_0":{}"#,
+                buffer.push_floats(&mesh.colors, 4, false)
+            ),
+        ];
+        if !mesh.uvs.is_empty() {
+            // glTF has v pointing down the image, the opposite of OBJ
+            let uvs: Vec<f32> = mesh
+                .uvs
+                .chunks(2)
+                .flat_map(|uv| [uv[0], 1. - uv[1]])
+                .collect();
+            attributes.push(format!(
+                r#""TEXCOORD_0":{}"#,
+                buffer.push_floats(&uvs, 2, false)
+            ));
+        }
+        if !mesh.tangents.is_empty() {
+            attributes.push(format!(
+                r#""TANGENT":{}"#,
+                buffer.push_floats(&mesh.tangents, 4, false)
+            ));
+        }
+        meshes.push(format!(
+            r#"{{"primitives":[{{"attributes":{{{}}},"indices":{},"material":{}}}]}}"#,
+            attributes.join(","),
+            buffer.push_indices(&mesh.indices),
+            material
+        ));
+    }
+
+    let nodes: Vec<String> = (0..meshes.len())
+        .map(|i| format!(r#"{{"name":"node_{0}","mesh":{0}}}"#, i))
+        .collect();
+    let image_list: Vec<String> = images
+        .iter()
+        .map(|path| format!(r#"{{"uri":{:?}}}"#, path))
+        .collect();
+    let texture_list: Vec<String> = (0..images.len())
+        .map(|i| format!(r#"{{"source":{}}}"#, i))
+        .collect();
+    let scene_nodes: Vec<String> = (0..nodes.len()).map(|i| i.to_string()).collect();
+    let scenes = match scene_nodes.is_empty() {
+        true => vec![],
+        false => vec![format!(r#"{{"nodes":[{}]}}"#, scene_nodes.join(","))],
+    };
+    let buffers = match buffer.bytes.is_empty() {
+        true => vec![],
+        false => vec![format!(
+            r#"{{"uri":{:?},"byteLength":{}}}"#,
+            bin_path.file_name().unwrap().to_string_lossy(),
+            buffer.bytes.len()
+        )],
+    };
+    let mut json = String::from(r#"{"asset":{"version":"2.0","generator":"grafikkprosjekt"}"#);
+    if !scenes.is_empty() {
+        json += r#","scene":0"#;
+    }
+    // Empty lists aren't allowed, so those are left out
+    for (name, items) in [
+        ("extensionsUsed", &extensions),
+        ("scenes", &scenes),
+        ("nodes", &nodes),
+        ("meshes", &meshes),
+        ("materials", &materials),
+        ("textures", &texture_list),
+        ("images", &image_list),
+        ("accessors", &buffer.accessors),
+        ("bufferViews", &buffer.buffer_views),
+        ("buffers", &buffers),
+    ] {
+        if !items.is_empty() {
+            json += &format!(",\n{:?}:[\n{}\n]", name, items.join(",\n"));
+        }
+    }
+    json += "\n}\n";
+
+    fs::write(&bin_path, &buffer.bytes)
+        .unwrap_or_else(|error| panic!("Could not write {}: {}", bin_path.display(), error));
+    fs::write(path, json)
+        .unwrap_or_else(|error| panic!("Could not write {}: {}", path.display(), error));
+}
@@ -1,10 +1,19 @@
+use std::collections::HashMap;
 use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 use glm;
 use glow::*;
 
 use super::{
-    texture::{CubemapTexture, FrameBufferTexture},
+    cluster::LightClusters,
+    crt::{CrtFilter, CrtScreen},
+    instancing::{GpuInstance, InstanceBuffer},
+    light::{GpuLight, Light, LightBuffer},
+    lod::LodChain,
+    mesh::Mesh,
+    texture::{CubemapTexture, FrameBufferTexture, ImageTexture, ProxyVolume},
     vao::VAO,
 };
 
@@ -15,6 +24,50 @@ pub enum NodeType {
     Screen,
 }
 
+/// How a node's opacity map is used
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    /// Opacity is ignored
+    Opaque,
+    /// Fragments below the threshold are discarded, the rest are opaque
+    Cutout { threshold: f32 },
+    /// Blended with whatever is behind, drawn after all opaque geometry
+    Transparent,
+}
+
+/// A node to draw, with the VAO picked for its level of detail
+pub type DrawItem = (usize, VAO);
+
+/// Everything that has to match for nodes to be drawn together in one instanced call
+#[derive(PartialEq, Eq, Hash)]
+struct BatchKey {
+    vao: NativeVertexArray,
+    textures: [Option<NativeTexture>; 6],
+    blend_mode: (u8, u32),
+    emission: [u32; 3],
+}
+
+/// Distance from a screen node's origin to the glass, along its local z axis
+const SCREEN_SURFACE_OFFSET: f32 = 0.03;
+
+/// Replace the near plane of a projection with an arbitrary clip plane given in view space,
+/// after Lengyel's oblique view frustum technique
+fn oblique_projection(projection: &glm::Mat4, plane: &glm::Vec4) -> glm::Mat4 {
+    let mut projection = *projection;
+    // Corner of the frustum opposite the plane
+    let corner = glm::vec4(
+        (plane.x.signum() + projection[(0, 2)]) / projection[(0, 0)],
+        (plane.y.signum() + projection[(1, 2)]) / projection[(1, 1)],
+        -1.,
+        (1. + projection[(2, 2)]) / projection[(2, 3)],
+    );
+    let scaled = plane * (2. / glm::dot(plane, &corner));
+    for column in 0..4 {
+        projection[(2, column)] = scaled[column] - projection[(3, column)];
+    }
+    projection
+}
+
 /// Scene node
 pub struct Node {
     index: usize,
@@ -23,14 +76,22 @@ pub struct Node {
 
     kind: NodeType,
     pub vao: Option<VAO>, // TODO problem when deleting VAO I guess :))))
+    /// CPU-side copy of what is in the VAO, for nodes that need it for picking or bounds
+    pub mesh: Option<Rc<Mesh>>,
+    /// Simpler versions of the VAO, for when the node appears small
+    pub lod: Option<LodChain>,
     pub texture: Option<FrameBufferTexture>,
     pub normal_map: Option<FrameBufferTexture>,
     pub reflection_map: Option<FrameBufferTexture>,
     pub roughness_map: Option<FrameBufferTexture>,
     pub opacity_map: Option<FrameBufferTexture>,
+    pub blend_mode: BlendMode,
     pub cubemap_texture: Option<CubemapTexture>,
     pub shader: Option<NativeShader>,
-    pub emission_color: glm::Vec3,
+    pub crt: Option<CrtScreen>,
+    pub light: Option<Light>,
+    /// Light given off by the surface itself
+    pub emission: glm::Vec3,
 
     pub position: glm::Vec3,
     pub reference_point: glm::Vec3,
@@ -57,6 +118,19 @@ pub struct SceneGraph {
     pub final_shader: Option<NativeProgram>,
     pub reflection_shader: Option<NativeProgram>,
     pub screen_shaders: Vec<(NativeProgram, usize)>,
+    // Light nodes lit by the contents of a screen, as (screen, light)
+    pub screen_lights: Vec<(usize, usize)>,
+    pub crt_filter: Option<CrtFilter>,
+    pub light_buffer: Option<LightBuffer>,
+    pub light_clusters: Option<LightClusters>,
+    pub instance_buffer: Option<InstanceBuffer>,
+    // Whether opaque nodes sharing a VAO and material are drawn in a single call
+    pub instancing: bool,
+    pub use_lod: bool,
+    // Below 1 to use simpler meshes in reflections than seen directly
+    pub reflection_lod_bias: f32,
+    // Image files that textures were loaded from, so exports can refer to them
+    pub texture_files: HashMap<NativeTexture, PathBuf>,
 }
 
 impl Node {
@@ -67,14 +141,19 @@ impl Node {
             children: vec![],
             kind,
             vao: None,
+            mesh: None,
+            lod: None,
             texture: None,
             normal_map: None,
             roughness_map: None,
             opacity_map: None,
+            blend_mode: BlendMode::Opaque,
             reflection_map: None,
             cubemap_texture: None,
             shader: None,
-            emission_color: glm::zero(),
+            crt: None,
+            light: None,
+            emission: glm::zero(),
             position: glm::zero(),
             reference_point: glm::zero(),
             rotation: glm::zero(),
@@ -88,6 +167,33 @@ impl Node {
         self.children.push(index);
     }
 
+    /// What this node has to share with others to be batched with them, when drawn with the given VAO
+    fn batch_key(&self, vao: &VAO) -> BatchKey {
+        let texture = |texture: Option<FrameBufferTexture>| texture.map(|t| t.texture);
+        let blend_mode = match self.blend_mode {
+            BlendMode::Opaque => (0, 0),
+            BlendMode::Cutout { threshold } => (1, threshold.to_bits()),
+            BlendMode::Transparent => (2, 0),
+        };
+        BatchKey {
+            vao: vao.vao,
+            textures: [
+                texture(self.texture),
+                texture(self.normal_map),
+                texture(self.roughness_map),
+                texture(self.opacity_map),
+                texture(self.reflection_map),
+                self.cubemap_texture.map(|t| t.texture),
+            ],
+            blend_mode,
+            emission: [
+                self.emission.x.to_bits(),
+                self.emission.y.to_bits(),
+                self.emission.z.to_bits(),
+            ],
+        }
+    }
+
     /// Position in world space
     pub fn world_position(&self) -> glm::Vec3 {
         glm::vec4_to_vec3(
@@ -121,9 +227,26 @@ impl SceneGraph {
             final_shader: None,
             reflection_shader: None,
             screen_shaders: vec![],
+            screen_lights: vec![],
+            crt_filter: None,
+            light_buffer: None,
+            light_clusters: None,
+            instance_buffer: None,
+            instancing: true,
+            use_lod: true,
+            reflection_lod_bias: 0.5,
+            texture_files: HashMap::new(),
         }
     }
 
+    /// Load an image file as a texture and remember where it came from
+    pub unsafe fn load_texture(&mut self, gl: &glow::Context, path: &str) -> FrameBufferTexture {
+        let texture = ImageTexture::new(gl, path);
+        self.texture_files
+            .insert(texture.texture, PathBuf::from(path));
+        texture
+    }
+
     /// Add a child node and remember it especially well if it is a light source or screen
     pub fn add_child(&mut self, parent_index: usize, child: Node) -> usize {
         let child_index = self.nodes.len();
@@ -141,35 +264,78 @@ impl SceneGraph {
         &mut self.nodes[node_index]
     }
 
+    /// Up to `count` reflection probes closest to a position, as their world positions and cubemaps
+    pub fn nearest_probes(
+        &self,
+        position: &glm::Vec3,
+        count: usize,
+    ) -> Vec<(glm::Vec3, NativeTexture)> {
+        let mut probes: Vec<(glm::Vec3, NativeTexture)> = self
+            .cameras
+            .iter()
+            .map(|&node_index| &self.nodes[node_index])
+            .filter_map(|node| {
+                let cubemap = node.cubemap_texture?;
+                Some((
+                    (node.model_matrix * glm::vec4(0., 0., 0., 1.)).xyz(),
+                    cubemap.texture,
+                ))
+            })
+            .collect();
+        probes.sort_by(|(a, _), (b, _)| {
+            glm::distance(a, position).total_cmp(&glm::distance(b, position))
+        });
+        probes.truncate(count);
+        probes
+    }
+
     pub fn update(&mut self, gl: &glow::Context) {
         self.update_transformations(self.root, &glm::identity(), &glm::zero());
 
+        // Put every light in a storage buffer, so there's no limit to how many there are
+        let lights: Vec<GpuLight> = self
+            .light_sources
+            .iter()
+            .filter_map(|&light_index| {
+                let node = &self.nodes[light_index];
+                node.light
+                    .as_ref()
+                    .map(|light| GpuLight::new(light, &node.model_matrix))
+            })
+            .collect();
         unsafe {
+            if let Some(buffer) = &mut self.light_buffer {
+                buffer.upload(gl, lights);
+                buffer.bind(gl, 0);
+            }
+        }
+    }
+
+    /// Sort the lights into clusters for the main camera, for the forward renderer
+    pub unsafe fn cull_lights(
+        &mut self,
+        gl: &glow::Context,
+        projection: &glm::Mat4,
+        view: &glm::Mat4,
+    ) {
+        if let (Some(clusters), Some(lights)) = (&mut self.light_clusters, &self.light_buffer) {
+            clusters.update(gl, &lights.lights, projection, view);
+            clusters.bind(gl);
+            let program = self.final_shader.unwrap();
             gl.use_program(self.final_shader);
-            gl.uniform_1_u32(
-                gl.get_uniform_location(self.final_shader.unwrap(), "num_light_sources")
-                    .as_ref(),
-                self.light_sources.len() as u32,
+            gl.uniform_matrix_4_f32_slice(
+                gl.get_uniform_location(program, "cluster_view").as_ref(),
+                false,
+                view.as_slice(),
+            );
+            gl.uniform_1_f32(
+                gl.get_uniform_location(program, "cluster_near").as_ref(),
+                clusters.near,
+            );
+            gl.uniform_1_f32(
+                gl.get_uniform_location(program, "cluster_far").as_ref(),
+                clusters.far,
             );
-            for (i, &light_index) in self.light_sources.clone().iter().enumerate() {
-                let light = &self.nodes[light_index];
-                gl.uniform_3_f32_slice(
-                    gl.get_uniform_location(
-                        self.final_shader.unwrap(),
-                        &format!("light_sources[{}].position", i),
-                    )
-                    .as_ref(),
-                    &light.position.as_slice(),
-                );
-                gl.uniform_3_f32_slice(
-                    gl.get_uniform_location(
-                        self.final_shader.unwrap(),
-                        &format!("light_sources[{}].color", i),
-                    )
-                    .as_ref(),
-                    &light.emission_color.as_slice(),
-                );
-            }
         }
     }
 
@@ -206,78 +372,190 @@ impl SceneGraph {
         }
     }
 
+    /// Render the shader of each CRT screen into its own buffers,
+    /// so the CRT filter can be applied when displaying it.
+    /// Should happen before `update`, so the screen lights are uploaded with the new colors.
+    pub unsafe fn update_screen_contents(&mut self, gl: &glow::Context, time: f32) {
+        // Let the screens light up the room with whatever they showed as of the last readback,
+        // picked up before asking for new ones so they have had a frame to finish
+        for &(screen_index, light_index) in self.screen_lights.iter() {
+            if let Some(screen) = &mut self.nodes[screen_index].crt {
+                let color = screen.average_color(gl);
+                if let Some(light) = &mut self.nodes[light_index].light {
+                    light.color = color;
+                }
+            }
+        }
+        if let Some(filter) = &self.crt_filter {
+            for &(shader, node_index) in self.screen_shaders.iter() {
+                if let Some(screen) = &mut self.nodes[node_index].crt {
+                    filter.render_content(gl, screen, shader, time);
+                }
+            }
+        }
+        for &(screen_index, _) in self.screen_lights.iter() {
+            if let Some(screen) = &mut self.nodes[screen_index].crt {
+                screen.request_average_color(gl);
+            }
+        }
+    }
+
     /// Render screen contents (to a texture that must be bound outside this code)
     pub unsafe fn render_screens(&self, gl: &glow::Context, time: f32, view_transform: &glm::Mat4) {
         for (shader, node_index) in self.screen_shaders.clone() {
             let node = &self.nodes[node_index];
-            gl.use_program(Some(shader));
-            gl.uniform_1_f32(gl.get_uniform_location(shader, "time").as_ref(), time);
-            gl.uniform_matrix_4_f32_slice(
-                gl.get_uniform_location(shader, "view_transform").as_ref(),
-                false,
-                (view_transform * node.model_matrix).as_slice(),
-            );
-            node.vao.unwrap().draw(&gl);
+            let transform = view_transform * node.model_matrix;
+            match (&self.crt_filter, &node.crt) {
+                (Some(filter), Some(screen)) => {
+                    filter.display(gl, screen, &node.vao.unwrap(), &transform);
+                }
+                _ => {
+                    // No CRT filter, draw the shader directly onto the screen
+                    gl.use_program(Some(shader));
+                    gl.uniform_1_f32(gl.get_uniform_location(shader, "time").as_ref(), time);
+                    gl.uniform_matrix_4_f32_slice(
+                        gl.get_uniform_location(shader, "view_transform").as_ref(),
+                        false,
+                        transform.as_slice(),
+                    );
+                    node.vao.unwrap().draw(gl);
+                }
+            }
         }
     }
 
-    /// Render planar reflections from all monitors
-    pub unsafe fn render_reflections(&self, gl: &glow::Context) {
+    /// Render planar reflections for the monitors facing the viewer,
+    /// by mirroring the main camera in the plane of each screen
+    pub unsafe fn render_planar_reflections(
+        &self,
+        gl: &glow::Context,
+        projection: &glm::Mat4,
+        view: &glm::Mat4,
+        camera_position: &glm::Vec3,
+    ) {
         for node_index in self.cameras.clone() {
-            let texture = self.nodes[node_index].reflection_map.expect(&format!(
-                "Node {} was not assigned reflection texture",
-                node_index
-            ));
-            gl.bind_framebuffer(glow::FRAMEBUFFER, texture.framebuffer);
-            gl.viewport(0, 0, texture.width, texture.height);
-            gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
-            gl.use_program(self.final_shader);
-            self.render_in_terms_of(&gl, node_index);
+            self.render_planar_reflection(gl, node_index, projection, view, camera_position);
         }
     }
 
-    /// Render scene tree from the persepective of one particular node
-    pub unsafe fn render_in_terms_of(&self, gl: &glow::Context, node_index: usize) {
+    /// Render the planar reflection of a single monitor as seen by the viewer
+    pub unsafe fn render_planar_reflection(
+        &self,
+        gl: &glow::Context,
+        node_index: usize,
+        projection: &glm::Mat4,
+        view: &glm::Mat4,
+        camera_position: &glm::Vec3,
+    ) {
         let node = &self.nodes[node_index];
+        let texture = node.reflection_map.expect(&format!(
+            "Node {} was not assigned reflection texture",
+            node_index
+        ));
+        // The glass sits slightly in front of the node's origin and faces -z
+        let point = (node.model_matrix * glm::vec4(0., 0., SCREEN_SURFACE_OFFSET, 1.)).xyz();
+        let normal = glm::normalize(&(node.model_matrix * glm::vec4(0., 0., -1., 0.)).xyz());
+        if glm::dot(&(camera_position - point), &normal) <= 0. {
+            // Seen from behind, so there's nothing to reflect
+            return;
+        }
+        let distance = -glm::dot(&normal, &point);
 
-        let perspective: glm::Mat4 = glm::perspective(1., PI / 1.5, 1.0, 100.0);
-        let camera_position: glm::Vec3 =
-            glm::vec4_to_vec3(&(node.model_matrix * glm::vec4(0., 0., 0., 1.)));
-        // Reverse rotation order and either invert the angles or the rotation axes
-        // (yes, I did the latter at first but realized it was... rather odd)
-        let mut rotation: glm::Mat4 = glm::identity();
-        rotation = glm::rotation(-node.total_rotation.y, &glm::vec3(0., 1., 0.)) * rotation;
-        rotation = glm::rotation(-node.total_rotation.x, &glm::vec3(1., 0., 0.)) * rotation;
-        rotation = glm::rotation(-node.total_rotation.z, &glm::vec3(0., 0., 1.)) * rotation;
-        let camera_transform = perspective * rotation * glm::translation(&-camera_position);
-        self.render(gl, self.root, &camera_transform, &camera_position, false);
+        // Householder reflection through the plane
+        let mut reflection: glm::Mat4 = glm::identity();
+        for row in 0..3 {
+            for column in 0..3 {
+                reflection[(row, column)] -= 2. * normal[row] * normal[column];
+            }
+            reflection[(row, 3)] = -2. * distance * normal[row];
+        }
+        let mirrored_view = view * reflection;
+        let mirrored_position = (reflection
+            * glm::vec4(camera_position.x, camera_position.y, camera_position.z, 1.))
+        .xyz();
+
+        // Move the near plane onto the screen, so nothing behind it shows up in the reflection
+        let plane = glm::transpose(&glm::inverse(&mirrored_view))
+            * glm::vec4(normal.x, normal.y, normal.z, distance);
+        let clipped_projection = oblique_projection(projection, &plane);
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, texture.framebuffer);
+        gl.viewport(0, 0, texture.width, texture.height);
+        gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+        gl.use_program(self.final_shader);
+        self.prepare_reflection_pass(gl, texture.width, texture.height);
+        // Mirroring turns the triangles inside out
+        gl.front_face(glow::CW);
+        self.render(
+            gl,
+            self.root,
+            &(clipped_projection * mirrored_view),
+            &mirrored_position,
+            false,
+        );
+        gl.front_face(glow::CCW);
+    }
+
+    /// Turn off things that only make sense from the main camera,
+    /// like the occlusion texture, light clusters and deferred shading
+    unsafe fn prepare_reflection_pass(&self, gl: &glow::Context, width: i32, height: i32) {
+        gl.uniform_2_f32(
+            gl.get_uniform_location(self.final_shader.unwrap(), "screen_size")
+                .as_ref(),
+            width as f32,
+            height as f32,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(self.final_shader.unwrap(), "mode")
+                .as_ref(),
+            0,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(self.final_shader.unwrap(), "use_clusters")
+                .as_ref(),
+            0,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(self.final_shader.unwrap(), "use_ssao")
+                .as_ref(),
+            0,
+        );
+        gl.uniform_1_i32(
+            gl.get_uniform_location(self.final_shader.unwrap(), "reflective_only")
+                .as_ref(),
+            0,
+        );
     }
 
     /// Render cubemap reflections from all monitors
     pub unsafe fn render_cubemap_reflections(&self, gl: &glow::Context) {
         for node_index in self.cameras.clone() {
-            if let Some(texture) = self.nodes[node_index].cubemap_texture {
-                gl.use_program(self.final_shader);
-                for (i, &(center, up)) in [
-                    (glm::vec3(1., 0., 0.), glm::vec3(0., -1., 0.)), // +X
-                    (glm::vec3(-1., 0., 0.), glm::vec3(0., -1., 0.)), // -X
-                    (glm::vec3(0., 1., 0.), glm::vec3(0., 0., -1.)), // +Y
-                    (glm::vec3(0., -1., 0.), glm::vec3(0., 0., -1.)), // -Y
-                    (glm::vec3(0., 0., 1.), glm::vec3(0., -1., 0.)), // +Z
-                    (glm::vec3(0., 0., -1.), glm::vec3(0., -1., 0.)), // -Z
-                ]
-                .iter()
-                .enumerate()
-                {
-                    gl.bind_framebuffer(glow::FRAMEBUFFER, Some(texture.framebuffers[i]));
-                    gl.viewport(0, 0, texture.size, texture.size);
-                    gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
-                    self.render_in_terms_of_with_lookat(&gl, node_index, &center, &up);
-                }
+            for face in 0..6 {
+                self.render_cubemap_face(gl, node_index, face);
             }
         }
     }
 
+    /// Render one side of the cubemap reflection of a single monitor
+    pub unsafe fn render_cubemap_face(&self, gl: &glow::Context, node_index: usize, face: usize) {
+        if let Some(texture) = self.nodes[node_index].cubemap_texture {
+            gl.use_program(self.final_shader);
+            self.prepare_reflection_pass(gl, texture.size, texture.size);
+            let (center, up) = [
+                (glm::vec3(1., 0., 0.), glm::vec3(0., -1., 0.)), // +X
+                (glm::vec3(-1., 0., 0.), glm::vec3(0., -1., 0.)), // -X
+                (glm::vec3(0., 1., 0.), glm::vec3(0., 0., -1.)), // +Y
+                (glm::vec3(0., -1., 0.), glm::vec3(0., 0., -1.)), // -Y
+                (glm::vec3(0., 0., 1.), glm::vec3(0., -1., 0.)), // +Z
+                (glm::vec3(0., 0., -1.), glm::vec3(0., -1., 0.)), // -Z
+            ][face];
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(texture.framebuffers[face]));
+            gl.viewport(0, 0, texture.size, texture.size);
+            gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+            self.render_in_terms_of_with_lookat(&gl, node_index, &center, &up);
+        }
+    }
+
     /// Render scene tree from one node in a direction given by center and up vectors
     pub unsafe fn render_in_terms_of_with_lookat(
         &self,
@@ -298,7 +576,8 @@ impl SceneGraph {
         self.render(gl, self.root, &camera_transform, &camera_position, false);
     }
 
-    /// Render scene tree, setting uniforms as needed
+    /// Render scene tree for a reflection, setting uniforms as needed
+    /// and using simpler meshes than the main view would
     pub unsafe fn render(
         &self,
         gl: &glow::Context,
@@ -307,113 +586,375 @@ impl SceneGraph {
         camera_position: &glm::Vec3,
         with_reflection: bool,
     ) {
-        let node = &self.nodes[node_index];
-        if let Some(vao) = &node.vao {
-            // Set uniforms (a lot of them)
-            gl.uniform_matrix_4_f32_slice(
-                gl.get_uniform_location(self.final_shader.unwrap(), "model_transform")
-                    .as_ref(),
-                false,
-                node.model_matrix.as_slice(),
-            );
+        let program = self.final_shader.unwrap();
+        let (opaque, transparent) =
+            self.render_queues(node_index, view_transform, self.reflection_lod_bias);
+        self.draw_opaque(
+            gl,
+            program,
+            &opaque,
+            view_transform,
+            camera_position,
+            with_reflection,
+        );
+        self.draw_transparent(
+            gl,
+            program,
+            &transparent,
+            view_transform,
+            camera_position,
+            with_reflection,
+        );
+    }
+
+    /// VAO to draw a node with from the given view, if it has one
+    fn select_vao(&self, node: &Node, view_transform: &glm::Mat4, lod_bias: f32) -> Option<VAO> {
+        let vao = node.vao?;
+        Some(match &node.lod {
+            Some(lod) if self.use_lod => {
+                lod.select(vao, &node.model_matrix, view_transform, lod_bias)
+            }
+            _ => vao,
+        })
+    }
+
+    /// Tell the shader what shape to assume for the surroundings of a node's cubemap
+    unsafe fn set_proxy_uniforms(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        node: &Node,
+        proxy: &ProxyVolume,
+    ) {
+        // Same point the cubemap is rendered from
+        let position = (node.model_matrix * glm::vec4(0., 0., 0., 1.)).xyz();
+        gl.uniform_3_f32_slice(
+            gl.get_uniform_location(program, "probe_position").as_ref(),
+            position.as_slice(),
+        );
+        let kind = match proxy {
+            ProxyVolume::None => 0,
+            ProxyVolume::Box { min, max } => {
+                gl.uniform_3_f32_slice(
+                    gl.get_uniform_location(program, "probe_box_min").as_ref(),
+                    min.as_slice(),
+                );
+                gl.uniform_3_f32_slice(
+                    gl.get_uniform_location(program, "probe_box_max").as_ref(),
+                    max.as_slice(),
+                );
+                1
+            }
+            ProxyVolume::Sphere { radius } => {
+                gl.uniform_1_f32(
+                    gl.get_uniform_location(program, "probe_radius").as_ref(),
+                    *radius,
+                );
+                2
+            }
+        };
+        gl.uniform_1_i32(
+            gl.get_uniform_location(program, "probe_proxy").as_ref(),
+            kind,
+        );
+    }
+
+    /// Nodes with something to draw below the given one, split into the opaque queue in tree order
+    /// and the transparent queue sorted back-to-front by view depth.
+    /// The level of detail is picked for each, with a bias below 1 for simpler meshes.
+    pub fn render_queues(
+        &self,
+        node_index: usize,
+        view_transform: &glm::Mat4,
+        lod_bias: f32,
+    ) -> (Vec<DrawItem>, Vec<DrawItem>) {
+        let mut opaque = vec![];
+        let mut transparent = vec![];
+        let mut stack = vec![node_index];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if let Some(vao) = self.select_vao(node, view_transform, lod_bias) {
+                if node.blend_mode == BlendMode::Transparent {
+                    transparent.push((index, vao));
+                } else {
+                    opaque.push((index, vao));
+                }
+            }
+            // Reversed so children come off the stack in order
+            stack.extend(node.children.iter().rev());
+        }
+        // Clip space w is the distance along the view direction
+        let depth =
+            |&(index, _): &DrawItem| (view_transform * self.nodes[index].model_matrix)[(3, 3)];
+        transparent.sort_by(|a, b| depth(b).total_cmp(&depth(a)));
+        (opaque, transparent)
+    }
+
+    /// Render only the opaque and cutout parts of the scene tree,
+    /// for passes where blending makes no sense
+    pub unsafe fn render_opaque_with_shader(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        node_index: usize,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        let (opaque, _) = self.render_queues(node_index, view_transform, 1.);
+        self.draw_opaque(
+            gl,
+            program,
+            &opaque,
+            view_transform,
+            camera_position,
+            with_reflection,
+        );
+    }
+
+    /// Render only the transparent parts of the scene tree, back to front
+    pub unsafe fn render_transparent_with_shader(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        node_index: usize,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        let (_, transparent) = self.render_queues(node_index, view_transform, 1.);
+        self.draw_transparent(
+            gl,
+            program,
+            &transparent,
+            view_transform,
+            camera_position,
+            with_reflection,
+        );
+    }
+
+    /// Draw opaque nodes, with those sharing a VAO and material batched together if instancing is on.
+    /// Batches are drawn in the order of their first node.
+    pub unsafe fn draw_opaque(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        nodes: &[DrawItem],
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        let mut batches: Vec<Vec<DrawItem>> = vec![];
+        if self.instancing && self.instance_buffer.is_some() {
+            let mut batch_indices: HashMap<BatchKey, usize> = HashMap::new();
+            for &(index, vao) in nodes {
+                let key = self.nodes[index].batch_key(&vao);
+                let batch = *batch_indices.entry(key).or_insert(batches.len());
+                if batch == batches.len() {
+                    batches.push(vec![]);
+                }
+                batches[batch].push((index, vao));
+            }
+        } else {
+            batches = nodes.iter().map(|&item| vec![item]).collect();
+        }
+
+        for batch in batches {
+            if batch.len() == 1 {
+                self.draw_node(
+                    gl,
+                    program,
+                    batch[0],
+                    view_transform,
+                    camera_position,
+                    with_reflection,
+                );
+            } else {
+                self.draw_batch(
+                    gl,
+                    program,
+                    &batch,
+                    view_transform,
+                    camera_position,
+                    with_reflection,
+                );
+            }
+        }
+    }
+
+    /// Draw nodes sharing a VAO and material in one instanced call, using the material of the first
+    unsafe fn draw_batch(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        nodes: &[DrawItem],
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        let (first, vao) = nodes[0];
+        let node = &self.nodes[first];
+        if let Some(instance_buffer) = &self.instance_buffer {
+            gl.uniform_1_i32(gl.get_uniform_location(program, "instanced").as_ref(), 1);
             gl.uniform_matrix_4_f32_slice(
-                gl.get_uniform_location(self.final_shader.unwrap(), "view_transform")
-                    .as_ref(),
+                gl.get_uniform_location(program, "view_projection").as_ref(),
                 false,
-                (view_transform * node.model_matrix).as_slice(),
+                view_transform.as_slice(),
             );
-            gl.uniform_matrix_3_f32_slice(
-                gl.get_uniform_location(self.final_shader.unwrap(), "normal_transform")
-                    .as_ref(),
-                false,
-                // Normal restoration matrix from earlier
-                &glm::mat4_to_mat3(&glm::transpose(&glm::inverse(&node.model_matrix))).as_slice(),
+            self.set_material_uniforms(gl, program, node, camera_position, with_reflection);
+
+            let instances: Vec<GpuInstance> = nodes
+                .iter()
+                .map(|&(index, _)| GpuInstance::new(&self.nodes[index].model_matrix))
+                .collect();
+            instance_buffer.draw(gl, &vao, &instances);
+            gl.uniform_1_i32(gl.get_uniform_location(program, "instanced").as_ref(), 0);
+        }
+    }
+
+    /// Draw transparent nodes without writing depth, so they don't hide each other
+    pub unsafe fn draw_transparent(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        nodes: &[DrawItem],
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        gl.depth_mask(false);
+        for &item in nodes {
+            self.draw_node(
+                gl,
+                program,
+                item,
+                view_transform,
+                camera_position,
+                with_reflection,
             );
+        }
+        gl.depth_mask(true);
+    }
+
+    /// Draw a single node, setting uniforms as needed
+    unsafe fn draw_node(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        (node_index, vao): DrawItem,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        let node = &self.nodes[node_index];
+        // Set uniforms (a lot of them)
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "model_transform").as_ref(),
+            false,
+            node.model_matrix.as_slice(),
+        );
+        gl.uniform_matrix_4_f32_slice(
+            gl.get_uniform_location(program, "view_transform").as_ref(),
+            false,
+            (view_transform * node.model_matrix).as_slice(),
+        );
+        gl.uniform_matrix_3_f32_slice(
+            gl.get_uniform_location(program, "normal_transform")
+                .as_ref(),
+            false,
+            // Normal restoration matrix from earlier
+            &glm::mat4_to_mat3(&glm::transpose(&glm::inverse(&node.model_matrix))).as_slice(),
+        );
+        self.set_material_uniforms(gl, program, node, camera_position, with_reflection);
+
+        // Then draw the VAO
+        vao.draw(gl);
+    }
+
+    /// Set the uniforms and textures describing the surface of a node
+    unsafe fn set_material_uniforms(
+        &self,
+        gl: &glow::Context,
+        program: NativeProgram,
+        node: &Node,
+        camera_position: &glm::Vec3,
+        with_reflection: bool,
+    ) {
+        if let Some(vao) = &node.vao {
             gl.uniform_3_f32_slice(
-                gl.get_uniform_location(self.final_shader.unwrap(), "camera_position")
-                    .as_ref(),
+                gl.get_uniform_location(program, "camera_position").as_ref(),
                 &camera_position.as_slice(),
             );
             gl.uniform_1_f32(
-                gl.get_uniform_location(self.final_shader.unwrap(), "shininess")
-                    .as_ref(),
+                gl.get_uniform_location(program, "shininess").as_ref(),
                 vao.shininess,
             );
 
             // Bind texture if one exists, and indicate whether the model has a texture or not
             if let Some(texture) = node.texture {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_texture")
-                        .as_ref(),
-                    1,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_texture").as_ref(), 1);
                 gl.active_texture(glow::TEXTURE0);
                 gl.bind_texture(glow::TEXTURE_2D, Some(texture.texture));
             } else {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_texture")
-                        .as_ref(),
-                    0,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_texture").as_ref(), 0);
             }
 
             // Normal map
             if let Some(texture) = node.normal_map {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_normals")
-                        .as_ref(),
-                    1,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_normals").as_ref(), 1);
                 gl.active_texture(glow::TEXTURE2);
                 gl.bind_texture(glow::TEXTURE_2D, Some(texture.texture));
             } else {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_normals")
-                        .as_ref(),
-                    0,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_normals").as_ref(), 0);
             }
 
             // Roughness map
             if let Some(texture) = node.roughness_map {
                 gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_roughness")
-                        .as_ref(),
+                    gl.get_uniform_location(program, "use_roughness").as_ref(),
                     1,
                 );
                 gl.active_texture(glow::TEXTURE3);
                 gl.bind_texture(glow::TEXTURE_2D, Some(texture.texture));
             } else {
                 gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_roughness")
-                        .as_ref(),
+                    gl.get_uniform_location(program, "use_roughness").as_ref(),
                     0,
                 );
             }
 
             // Opacity map
             if let Some(texture) = node.opacity_map {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_opacity")
-                        .as_ref(),
-                    1,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_opacity").as_ref(), 1);
                 gl.active_texture(glow::TEXTURE4);
                 gl.bind_texture(glow::TEXTURE_2D, Some(texture.texture));
             } else {
-                gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_opacity")
-                        .as_ref(),
-                    0,
-                );
+                gl.uniform_1_i32(gl.get_uniform_location(program, "use_opacity").as_ref(), 0);
             }
+            gl.uniform_3_f32_slice(
+                gl.get_uniform_location(program, "emission").as_ref(),
+                node.emission.as_slice(),
+            );
+
+            let (blend_mode, alpha_cutoff) = match node.blend_mode {
+                BlendMode::Opaque => (0, 0.),
+                BlendMode::Cutout { threshold } => (1, threshold),
+                BlendMode::Transparent => (2, 0.),
+            };
+            gl.uniform_1_i32(
+                gl.get_uniform_location(program, "blend_mode").as_ref(),
+                blend_mode,
+            );
+            gl.uniform_1_f32(
+                gl.get_uniform_location(program, "alpha_cutoff").as_ref(),
+                alpha_cutoff,
+            );
 
             // Reflection texture
             if with_reflection {
                 if let Some(reflection) = node.reflection_map {
                     gl.uniform_1_i32(
-                        gl.get_uniform_location(self.final_shader.unwrap(), "use_reflection")
-                            .as_ref(),
+                        gl.get_uniform_location(program, "use_reflection").as_ref(),
                         1,
                     );
                     gl.active_texture(glow::TEXTURE1);
@@ -423,28 +964,21 @@ impl SceneGraph {
                         glow::TEXTURE_CUBE_MAP,
                         node.cubemap_texture.map(|t| t.texture),
                     );
+                    if let Some(cubemap) = node.cubemap_texture {
+                        self.set_proxy_uniforms(gl, program, node, &cubemap.proxy);
+                    }
                 } else {
                     gl.uniform_1_i32(
-                        gl.get_uniform_location(self.final_shader.unwrap(), "use_reflection")
-                            .as_ref(),
+                        gl.get_uniform_location(program, "use_reflection").as_ref(),
                         0,
                     );
                 }
             } else {
                 gl.uniform_1_i32(
-                    gl.get_uniform_location(self.final_shader.unwrap(), "use_reflection")
-                        .as_ref(),
+                    gl.get_uniform_location(program, "use_reflection").as_ref(),
                     0,
                 );
             }
-
-            // Then draw the VAO
-            vao.draw(gl);
-        }
-
-        // Recurse
-        for child in node.children.to_vec() {
-            self.render(gl, child, view_transform, camera_position, with_reflection);
         }
     }
 }
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use gltf::{image::Format, material::AlphaMode, mesh::Mode};
+
+use super::graph::{BlendMode, Node, NodeType, SceneGraph};
+use super::lod::LodChain;
+use super::mesh::Mesh;
+use super::texture::{FrameBufferTexture, ImageTexture};
+use super::vao::VAO;
+
+/// Which part of a material an image is used for, since each needs its own conversion
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum TextureRole {
+    BaseColor,
+    Normal,
+    /// Green channel of the metallic-roughness image, moved to red where the shaders read it
+    Roughness,
+    /// Alpha channel of the base color image, moved to red for the opacity map
+    Opacity,
+}
+
+/// Keeps track of what has been loaded from one glTF file, so textures are shared between primitives
+struct Importer<'a> {
+    gl: &'a glow::Context,
+    buffers: Vec<gltf::buffer::Data>,
+    images: Vec<gltf::image::Data>,
+    /// By image index, role and the factor baked into it
+    textures: HashMap<(usize, TextureRole, [u32; 4]), FrameBufferTexture>,
+}
+
+/// Expand any glTF image to 8-bit RGBA. 16-bit channels are scaled down,
+/// and floating point ones are clamped to the range from 0 to 1.
+fn to_rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    let (channels, channel_size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    // The pixels are in the byte order of this machine
+    let values: Vec<u8> = image
+        .pixels
+        .chunks_exact(channel_size)
+        .map(|bytes| match *bytes {
+            [value] => value,
+            [a, b] => ((u16::from_ne_bytes([a, b]) as u32 * 255 + 32767) / 65535) as u8,
+            _ => {
+                let value = f32::from_ne_bytes(bytes.try_into().unwrap());
+                (value.clamp(0., 1.) * 255.).round() as u8
+            }
+        })
+        .collect();
+    values
+        .chunks(channels)
+        .flat_map(|pixel| match channels {
+            1 => [pixel[0], pixel[0], pixel[0], 255],
+            // Two channels are grey and alpha
+            2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+            3 => [pixel[0], pixel[1], pixel[2], 255],
+            _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        })
+        .collect()
+}
+
+impl<'a> Importer<'a> {
+    /// Texture for one role of an image, with a factor multiplied into the channel that matters
+    unsafe fn texture(
+        &mut self,
+        image_index: usize,
+        role: TextureRole,
+        factor: [f32; 4],
+    ) -> FrameBufferTexture {
+        let key = (image_index, role, factor.map(f32::to_bits));
+        if let Some(&texture) = self.textures.get(&key) {
+            return texture;
+        }
+        let image = &self.images[image_index];
+        let mut pixels = to_rgba8(image);
+        let scale = |value: u8, factor: f32| (value as f32 * factor).round().min(255.) as u8;
+        for pixel in pixels.chunks_mut(4) {
+            match role {
+                TextureRole::BaseColor => {
+                    for channel in 0..4 {
+                        pixel[channel] = scale(pixel[channel], factor[channel]);
+                    }
+                }
+                TextureRole::Normal => {}
+                TextureRole::Roughness => {
+                    let roughness = scale(pixel[1], factor[0]);
+                    pixel.copy_from_slice(&[roughness, roughness, roughness, 255]);
+                }
+                TextureRole::Opacity => {
+                    let alpha = scale(pixel[3], factor[0]);
+                    pixel.copy_from_slice(&[alpha, alpha, alpha, 255]);
+                }
+            }
+        }
+        let texture =
+            ImageTexture::from_pixels(self.gl, image.width as i32, image.height as i32, &pixels);
+        self.textures.insert(key, texture);
+        texture
+    }
+
+    /// Single-pixel texture, for material constants the shaders only know how to read from textures
+    unsafe fn constant(&mut self, value: f32) -> FrameBufferTexture {
+        let key = (usize::MAX, TextureRole::Roughness, [value.to_bits(); 4]);
+        if let Some(&texture) = self.textures.get(&key) {
+            return texture;
+        }
+        let value = (value.clamp(0., 1.) * 255.).round() as u8;
+        let texture = ImageTexture::from_pixels(self.gl, 1, 1, &[value, value, value, 255]);
+        self.textures.insert(key, texture);
+        texture
+    }
+
+    /// Turn one primitive into a scene node with its VAO and material
+    unsafe fn primitive_node(&mut self, primitive: &gltf::Primitive) -> Option<Node> {
+        if primitive.mode() != Mode::Triangles {
+            println!(
+                "Skipping glTF primitive drawn as {:?}, only triangles are supported",
+                primitive.mode()
+            );
+            return None;
+        }
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions: Vec<f32> = reader
+            .read_positions()
+            .expect("glTF primitive has no positions")
+            .flatten()
+            .collect();
+        let vertex_count = positions.len() / 3;
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..vertex_count as u32).collect(),
+        };
+        let normals: Vec<f32> = match reader.read_normals() {
+            Some(normals) => normals.flatten().collect(),
+            None => vec![],
+        };
+        // glTF has its origin in the upper left, the shaders flip for images with it in the lower left
+        let uvs: Vec<f32> = match reader.read_tex_coords(0) {
+            Some(uvs) => uvs.into_f32().flat_map(|[u, v]| [u, 1. - v]).collect(),
+            None => vec![],
+        };
+
+        let material = primitive.material();
+        let pbr = material.pbr_metallic_roughness();
+        let base_color = pbr.base_color_factor();
+        let colors: Vec<f32> = match reader.read_colors(0) {
+            Some(colors) => colors
+                .into_rgba_f32()
+                .flat_map(|color| {
+                    [
+                        color[0] * base_color[0],
+                        color[1] * base_color[1],
+                        color[2] * base_color[2],
+                        color[3] * base_color[3],
+                    ]
+                })
+                .collect(),
+            None => base_color.repeat(vertex_count),
+        };
+
+        let roughness = pbr.roughness_factor();
+        let shininess = 5. / (roughness * roughness).max(0.01);
+        let mut mesh = Mesh {
+            positions,
+            normals,
+            uvs,
+            colors,
+            tangents: vec![],
+            indices,
+        };
+        if mesh.normals.is_empty() {
+            mesh.generate_normals();
+        }
+        // Tangents from the file are used as they are, or else generated if they can be
+        match reader.read_tangents() {
+            Some(tangents) => mesh.tangents = tangents.flatten().collect(),
+            None => mesh.generate_tangents(),
+        }
+        let mut node = Node::new(NodeType::Geometry);
+        node.vao = Some(VAO::from_mesh(self.gl, &mesh, shininess));
+        node.lod = Some(LodChain::new(self.gl, &mesh, shininess));
+        node.mesh = Some(Rc::new(mesh));
+
+        let base_color_image = pbr
+            .base_color_texture()
+            .map(|info| info.texture().source().index());
+        if let Some(image) = base_color_image {
+            node.texture = Some(self.texture(image, TextureRole::BaseColor, base_color));
+        }
+        if let Some(normal) = material.normal_texture() {
+            node.normal_map = Some(self.texture(
+                normal.texture().source().index(),
+                TextureRole::Normal,
+                [1.; 4],
+            ));
+        }
+        node.roughness_map = Some(match pbr.metallic_roughness_texture() {
+            Some(info) => self.texture(
+                info.texture().source().index(),
+                TextureRole::Roughness,
+                [roughness; 4],
+            ),
+            None => self.constant(roughness),
+        });
+
+        node.emission = glm::make_vec3(&material.emissive_factor());
+
+        let blend_mode = match material.alpha_mode() {
+            AlphaMode::Opaque => BlendMode::Opaque,
+            AlphaMode::Mask => BlendMode::Cutout {
+                threshold: material.alpha_cutoff().unwrap_or(0.5),
+            },
+            AlphaMode::Blend => BlendMode::Transparent,
+        };
+        if blend_mode != BlendMode::Opaque {
+            node.blend_mode = blend_mode;
+            node.opacity_map = Some(match base_color_image {
+                Some(image) => self.texture(image, TextureRole::Opacity, [base_color[3]; 4]),
+                None => self.constant(base_color[3]),
+            });
+        }
+
+        Some(node)
+    }
+
+    /// Add a glTF node and everything below it to the scene graph
+    unsafe fn add_node(&mut self, scene_graph: &mut SceneGraph, parent: usize, node: &gltf::Node) {
+        let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+        let mut scene_node = Node::new(NodeType::Root);
+        scene_node.position = glm::make_vec3(&translation);
+        scene_node.scale = glm::make_vec3(&scale);
+        // The scene graph rotates about y, then x, then z
+        let rotation = glm::quat_to_mat3(&glm::quat(x, y, z, w));
+        scene_node.rotation = glm::vec3(
+            (-rotation[(1, 2)]).clamp(-1., 1.).asin(),
+            rotation[(0, 2)].atan2(rotation[(2, 2)]),
+            rotation[(1, 0)].atan2(rotation[(1, 1)]),
+        );
+        let index = scene_graph.add_child(parent, scene_node);
+
+        // One child per primitive, since each has its own material
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if let Some(primitive_node) = self.primitive_node(&primitive) {
+                    scene_graph.add_child(index, primitive_node);
+                }
+            }
+        }
+        for child in node.children() {
+            self.add_node(scene_graph, index, &child);
+        }
+    }
+}
+
+/// Load a `.gltf` or `.glb` file into the scene graph below the given parent,
+/// returning the index of the node holding the whole scene.
+/// Only the default scene (or else the first one) is loaded,
+/// and cameras, lights, animations and skins are ignored.
+pub unsafe fn load_gltf(
+    gl: &glow::Context,
+    scene_graph: &mut SceneGraph,
+    parent: usize,
+    path: &str,
+) -> usize {
+    let (document, buffers, images) =
+        gltf::import(path).unwrap_or_else(|e| panic!("Could not load glTF file {}: {}", path, e));
+    let mut importer = Importer {
+        gl,
+        buffers,
+        images,
+        textures: HashMap::new(),
+    };
+
+    let root = scene_graph.add_child(parent, Node::new(NodeType::Root));
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .unwrap_or_else(|| panic!("No scenes in glTF file {}", path));
+    for node in scene.nodes() {
+        importer.add_node(scene_graph, root, &node);
+    }
+    root
+}
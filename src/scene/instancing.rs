@@ -0,0 +1,76 @@
+use glow::*;
+
+use super::vao::{size_of, to_u8_slice, VAO};
+
+/// First attribute location of the per-instance transforms in world.vert,
+/// the model matrix taking up four locations and the normal matrix the three after
+const INSTANCE_LOCATION: u32 = 5;
+
+/// Transforms of one instance, laid out the way the attributes expect them
+#[repr(C)]
+pub struct GpuInstance {
+    model: [[f32; 4]; 4],
+    normal: [[f32; 3]; 3],
+}
+
+impl GpuInstance {
+    pub fn new(model_matrix: &glm::Mat4) -> GpuInstance {
+        // Normal restoration matrix, same as for single nodes
+        let normal = glm::mat4_to_mat3(&glm::transpose(&glm::inverse(model_matrix)));
+        GpuInstance {
+            model: (*model_matrix).into(),
+            normal: normal.into(),
+        }
+    }
+}
+
+/// Vertex buffer with a transform per instance, refilled for every batch that is drawn
+pub struct InstanceBuffer {
+    pub buffer: NativeBuffer,
+}
+
+impl InstanceBuffer {
+    pub unsafe fn new(gl: &glow::Context) -> InstanceBuffer {
+        InstanceBuffer {
+            buffer: gl
+                .create_buffer()
+                .expect("Unable to create instance buffer"),
+        }
+    }
+
+    /// Draw a VAO once for each instance in a single call
+    pub unsafe fn draw(&self, gl: &glow::Context, vao: &VAO, instances: &[GpuInstance]) {
+        gl.bind_vertex_array(Some(vao.vao));
+        gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.buffer));
+        gl.buffer_data_u8_slice(
+            glow::ARRAY_BUFFER,
+            to_u8_slice(instances),
+            glow::STREAM_DRAW,
+        );
+        // Matrices are passed as one attribute per column, as (components, offset in floats)
+        let columns: Vec<(i32, i32)> = (0..4)
+            .map(|column| (4, column * 4))
+            .chain((0..3).map(|column| (3, 16 + column * 3)))
+            .collect();
+        for (i, &(components, offset)) in columns.iter().enumerate() {
+            let location = INSTANCE_LOCATION + i as u32;
+            gl.vertex_attrib_pointer_f32(
+                location,
+                components,
+                glow::FLOAT,
+                false,
+                size_of::<GpuInstance>(),
+                offset * size_of::<f32>(),
+            );
+            gl.vertex_attrib_divisor(location, 1);
+            gl.enable_vertex_attrib_array(location);
+        }
+
+        vao.draw_instanced(gl, instances.len() as i32);
+
+        // Leave the VAO the way ordinary draws expect it
+        for i in 0..columns.len() {
+            gl.disable_vertex_attrib_array(INSTANCE_LOCATION + i as u32);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use glow::*;
+
+use super::vao::to_u8_slice;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LightKind {
+    Point,
+    /// Cone along the node's -z axis, fading out between the inner and outer angle (radians)
+    Spot {
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    /// Infinitely far away, shining along the node's -z axis
+    Directional,
+    /// Rectangle in the node's xy plane, emitting towards -z
+    Area {
+        width: f32,
+        height: f32,
+    },
+}
+
+/// Light component for nodes of type `NodeType::Light`
+#[derive(Clone, Copy)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: glm::Vec3,
+    /// Scales the color, falling off with the inverse square of the distance
+    pub intensity: f32,
+    /// Distance where the light has faded out completely, or 0 for no cutoff.
+    /// Lights with a range can be culled, so small lights should have one.
+    pub range: f32,
+}
+
+impl Default for Light {
+    fn default() -> Light {
+        Light {
+            kind: LightKind::Point,
+            color: glm::vec3(1., 1., 1.),
+            intensity: 1.,
+            range: 0.,
+        }
+    }
+}
+
+/// A light source as laid out in the shader storage buffer (std430),
+/// padded to whole vec4s. See lights.glsl for what goes where.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GpuLight {
+    pub position: [f32; 4],
+    pub color: [f32; 4],
+    pub direction: [f32; 4],
+    pub tangent: [f32; 4],
+    pub size: [f32; 4],
+}
+
+impl GpuLight {
+    /// Place a light in the world according to its node's transformation
+    pub fn new(light: &Light, model_matrix: &glm::Mat4) -> GpuLight {
+        let position = model_matrix * glm::vec4(0., 0., 0., 1.);
+        let direction = glm::normalize(&(model_matrix * glm::vec4(0., 0., -1., 0.)).xyz());
+        let tangent = glm::normalize(&(model_matrix * glm::vec4(1., 0., 0., 0.)).xyz());
+        let color = light.color * light.intensity;
+        let (kind, cos_inner, cos_outer, size) = match light.kind {
+            LightKind::Point => (0, 1., -1., [0., 0.]),
+            LightKind::Spot {
+                inner_angle,
+                outer_angle,
+            } => (1, inner_angle.cos(), outer_angle.cos(), [0., 0.]),
+            LightKind::Directional => (2, 1., -1., [0., 0.]),
+            LightKind::Area { width, height } => (3, 1., -1., [width / 2., height / 2.]),
+        };
+        // Directional lights reach everywhere no matter what
+        let range = if light.kind == LightKind::Directional {
+            0.
+        } else {
+            light.range
+        };
+        GpuLight {
+            position: [position.x, position.y, position.z, range],
+            color: [color.x, color.y, color.z, kind as f32],
+            direction: [direction.x, direction.y, direction.z, cos_outer],
+            tangent: [tangent.x, tangent.y, tangent.z, cos_inner],
+            size: [size[0], size[1], 0., 0.],
+        }
+    }
+}
+
+/// Shader storage buffer holding every light in the scene,
+/// so shaders can loop over as many as there are
+pub struct LightBuffer {
+    pub buffer: NativeBuffer,
+    /// Copy of what was last uploaded, for culling on the CPU
+    pub lights: Vec<GpuLight>,
+}
+
+impl LightBuffer {
+    pub unsafe fn new(gl: &glow::Context) -> LightBuffer {
+        LightBuffer {
+            buffer: gl.create_buffer().expect("Unable to create light buffer"),
+            lights: vec![],
+        }
+    }
+
+    pub unsafe fn upload(&mut self, gl: &glow::Context, lights: Vec<GpuLight>) {
+        gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, Some(self.buffer));
+        gl.buffer_data_u8_slice(
+            glow::SHADER_STORAGE_BUFFER,
+            to_u8_slice(&lights),
+            glow::DYNAMIC_DRAW,
+        );
+        gl.bind_buffer(glow::SHADER_STORAGE_BUFFER, None);
+        self.lights = lights;
+    }
+
+    /// Make the lights available at the given binding point
+    pub unsafe fn bind(&self, gl: &glow::Context, binding: u32) {
+        gl.bind_buffer_base(glow::SHADER_STORAGE_BUFFER, binding, Some(self.buffer));
+    }
+}
@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use super::mesh::Mesh;
+use super::vao::VAO;
+
+/// Projected size (radius over distance, scaled by the focal length) from which a mesh is drawn in full.
+/// Every halving of the size below it moves one level further down the chain.
+const FULL_DETAIL_SIZE: f32 = 0.5;
+
+/// Each level gets about this fraction of the triangles of the one before
+const LEVEL_RATIO: f32 = 0.5;
+
+/// Meshes aren't simplified any further than this
+const MIN_TRIANGLES: usize = 128;
+
+const MAX_LEVELS: usize = 4;
+
+/// Sum of squared distances to a set of planes, after Garland and Heckbert,
+/// stored as the upper triangle of the symmetric 4x4 matrix
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// Plane through a triangle, weighted by its area
+    fn triangle(a: &glm::DVec3, b: &glm::DVec3, c: &glm::DVec3) -> Quadric {
+        let normal = glm::cross(&(b - a), &(c - a));
+        let length = glm::length(&normal);
+        if length == 0. {
+            return Quadric::default();
+        }
+        let n = normal / length;
+        let d = -glm::dot(&n, a);
+        let q = [
+            n.x * n.x,
+            n.x * n.y,
+            n.x * n.z,
+            n.x * d,
+            n.y * n.y,
+            n.y * n.z,
+            n.y * d,
+            n.z * n.z,
+            n.z * d,
+            d * d,
+        ];
+        Quadric(q.map(|value| value * length / 2.))
+    }
+
+    fn add(&self, other: &Quadric) -> Quadric {
+        let mut sum = *self;
+        for (value, other) in sum.0.iter_mut().zip(other.0) {
+            *value += other;
+        }
+        sum
+    }
+
+    fn error(&self, p: &glm::DVec3) -> f64 {
+        let q = &self.0;
+        q[0] * p.x * p.x
+            + 2. * q[1] * p.x * p.y
+            + 2. * q[2] * p.x * p.z
+            + 2. * q[3] * p.x
+            + q[4] * p.y * p.y
+            + 2. * q[5] * p.y * p.z
+            + 2. * q[6] * p.y
+            + q[7] * p.z * p.z
+            + 2. * q[8] * p.z
+            + q[9]
+    }
+}
+
+/// Moving one position onto a neighbouring one, valid as long as neither has changed since
+struct Collapse {
+    cost: f64,
+    from: usize,
+    to: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Collapse) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Collapse) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    /// Reversed, so the cheapest collapse is on top of the heap
+    fn cmp(&self, other: &Collapse) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/// Reduce a mesh to about the given number of triangles by collapsing edges,
+/// cheapest first by quadric error. Vertices are only ever moved onto their neighbours,
+/// so every attribute stays as it was. Vertices split along UV seams only move along the seam,
+/// so the texture doesn't tear, and open borders are kept in place so holes don't grow.
+pub fn simplify(mesh: &Mesh, target_triangles: usize) -> Mesh {
+    let vertex_count = mesh.vertex_count();
+    // Vertices that are split along seams are welded back together by position
+    let mut position_ids: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut points: Vec<glm::DVec3> = vec![];
+    let mut position_of: Vec<usize> = Vec::with_capacity(vertex_count);
+    for position in mesh.positions.chunks(3) {
+        let key = [
+            position[0].to_bits(),
+            position[1].to_bits(),
+            position[2].to_bits(),
+        ];
+        let id = *position_ids.entry(key).or_insert_with(|| {
+            points.push(glm::vec3(
+                position[0] as f64,
+                position[1] as f64,
+                position[2] as f64,
+            ));
+            points.len() - 1
+        });
+        position_of.push(id);
+    }
+
+    let mut triangles: Vec<[u32; 3]> = mesh
+        .indices
+        .chunks(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect();
+    let corners = |triangle: &[u32; 3]| triangle.map(|vertex| position_of[vertex as usize]);
+    let mut alive: Vec<bool> = triangles
+        .iter()
+        .map(|triangle| {
+            let [a, b, c] = corners(triangle);
+            a != b && b != c && c != a
+        })
+        .collect();
+    let mut triangle_count = alive.iter().filter(|&&alive| alive).count();
+
+    let mut quadrics = vec![Quadric::default(); points.len()];
+    let mut incident: Vec<Vec<usize>> = vec![vec![]; points.len()];
+    let mut edges: HashMap<(usize, usize), u32> = HashMap::new();
+    for (t, triangle) in triangles.iter().enumerate().filter(|&(t, _)| alive[t]) {
+        let ids = corners(triangle);
+        let quadric = Quadric::triangle(&points[ids[0]], &points[ids[1]], &points[ids[2]]);
+        for i in 0..3 {
+            quadrics[ids[i]] = quadrics[ids[i]].add(&quadric);
+            incident[ids[i]].push(t);
+            let (a, b) = (ids[i], ids[(i + 1) % 3]);
+            *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    // Borders stay where they are
+    let mut locked = vec![false; points.len()];
+    for (&(a, b), &count) in edges.iter() {
+        if count == 1 {
+            locked[a] = true;
+            locked[b] = true;
+        }
+    }
+
+    let mut versions = vec![0u32; points.len()];
+    let mut removed = vec![false; points.len()];
+    let mut heap = BinaryHeap::new();
+    let candidate = |from: usize, to: usize, quadrics: &[Quadric], versions: &[u32]| Collapse {
+        cost: quadrics[from].add(&quadrics[to]).error(&points[to]),
+        from,
+        to,
+        versions: (versions[from], versions[to]),
+    };
+    for &(a, b) in edges.keys() {
+        if !locked[a] {
+            heap.push(candidate(a, b, &quadrics, &versions));
+        }
+        if !locked[b] {
+            heap.push(candidate(b, a, &quadrics, &versions));
+        }
+    }
+
+    while triangle_count > target_triangles {
+        let collapse = match heap.pop() {
+            Some(collapse) => collapse,
+            None => break,
+        };
+        let (a, b) = (collapse.from, collapse.to);
+        if removed[a] || removed[b] || collapse.versions != (versions[a], versions[b]) {
+            continue;
+        }
+        let around: Vec<usize> = incident[a].iter().copied().filter(|&t| alive[t]).collect();
+
+        // Each vertex at a is replaced by the one at b it shares an edge with,
+        // which there must be exactly one of, or the collapse would tear a seam open
+        let mut replacements: HashMap<u32, u32> = HashMap::new();
+        let mut consistent = true;
+        for &t in around.iter() {
+            let from = triangles[t]
+                .iter()
+                .find(|&&vertex| position_of[vertex as usize] == a);
+            let to = triangles[t]
+                .iter()
+                .find(|&&vertex| position_of[vertex as usize] == b);
+            if let (Some(&from), Some(&to)) = (from, to) {
+                consistent &= *replacements.entry(from).or_insert(to) == to;
+            }
+        }
+        let complete = around.iter().all(|&t| {
+            triangles[t].iter().all(|&vertex| {
+                position_of[vertex as usize] != a || replacements.contains_key(&vertex)
+            })
+        });
+        if !consistent || !complete {
+            continue;
+        }
+
+        // Triangles that stay mustn't fold over or collapse
+        let folds = around.iter().any(|&t| {
+            let ids = corners(&triangles[t]);
+            if ids.contains(&b) {
+                return false;
+            }
+            let moved = ids.map(|id| if id == a { points[b] } else { points[id] });
+            let before = glm::cross(
+                &(points[ids[1]] - points[ids[0]]),
+                &(points[ids[2]] - points[ids[0]]),
+            );
+            let after = glm::cross(&(moved[1] - moved[0]), &(moved[2] - moved[0]));
+            glm::dot(&before, &after) < 0.2 * glm::length(&before) * glm::length(&after)
+                || glm::length2(&after) == 0.
+        });
+        if folds {
+            continue;
+        }
+
+        for &t in around.iter() {
+            if corners(&triangles[t]).contains(&b) {
+                alive[t] = false;
+                triangle_count -= 1;
+            } else {
+                for vertex in triangles[t].iter_mut() {
+                    if let Some(&replacement) = replacements.get(vertex) {
+                        *vertex = replacement;
+                    }
+                }
+                incident[b].push(t);
+            }
+        }
+        removed[a] = true;
+        quadrics[b] = quadrics[b].add(&quadrics[a]);
+        versions[b] += 1;
+
+        // Everything around b now has a different cost of moving
+        let mut neighbours: Vec<usize> = incident[b]
+            .iter()
+            .filter(|&&t| alive[t])
+            .flat_map(|&t| corners(&triangles[t]))
+            .filter(|&id| id != b)
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        for neighbour in neighbours {
+            if !locked[b] {
+                heap.push(candidate(b, neighbour, &quadrics, &versions));
+            }
+            if !locked[neighbour] {
+                heap.push(candidate(neighbour, b, &quadrics, &versions));
+            }
+        }
+    }
+
+    // Keep only the vertices that are still in use
+    let mut new_index: HashMap<u32, u32> = HashMap::new();
+    let mut simplified = Mesh::default();
+    for (t, triangle) in triangles.iter().enumerate() {
+        if !alive[t] {
+            continue;
+        }
+        for &vertex in triangle {
+            let index = *new_index.entry(vertex).or_insert_with(|| {
+                let v = vertex as usize;
+                let copy = |source: &[f32], into: &mut Vec<f32>, components: usize| {
+                    if !source.is_empty() {
+                        into.extend_from_slice(&source[v * components..(v + 1) * components]);
+                    }
+                };
+                copy(&mesh.positions, &mut simplified.positions, 3);
+                copy(&mesh.normals, &mut simplified.normals, 3);
+                copy(&mesh.uvs, &mut simplified.uvs, 2);
+                copy(&mesh.colors, &mut simplified.colors, 4);
+                copy(&mesh.tangents, &mut simplified.tangents, 4);
+                simplified.vertex_count() as u32 - 1
+            });
+            simplified.indices.push(index);
+        }
+    }
+    simplified
+}
+
+/// Simplify a mesh step by step until it is small enough or won't get any smaller,
+/// giving the levels from the most detailed down, not including the mesh itself
+pub fn simplify_levels(mesh: &Mesh) -> Vec<Mesh> {
+    let mut levels: Vec<Mesh> = vec![];
+    while levels.len() < MAX_LEVELS {
+        let previous = levels.last().unwrap_or(mesh);
+        let triangles = previous.indices.len() / 3;
+        let target = (triangles as f32 * LEVEL_RATIO) as usize;
+        if target < MIN_TRIANGLES {
+            break;
+        }
+        let simplified = simplify(previous, target);
+        // Mostly seams left, so there's little point in going on
+        if simplified.indices.len() / 3 > triangles * 9 / 10 {
+            break;
+        }
+        levels.push(simplified);
+    }
+    levels
+}
+
+/// Simplified versions of a mesh, and the sphere around it for judging how large it appears
+pub struct LodChain {
+    /// From the most detailed simplification down, not including the full mesh
+    pub levels: Vec<VAO>,
+    /// Bounding sphere in model space
+    center: glm::Vec3,
+    radius: f32,
+}
+
+impl LodChain {
+    /// Simplify a mesh and upload the levels
+    pub unsafe fn new(gl: &glow::Context, mesh: &Mesh, shininess: f32) -> LodChain {
+        LodChain::from_levels(gl, mesh, &simplify_levels(mesh), shininess)
+    }
+
+    /// Upload levels that were simplified earlier, like the ones in the mesh cache
+    pub unsafe fn from_levels(
+        gl: &glow::Context,
+        mesh: &Mesh,
+        levels: &[Mesh],
+        shininess: f32,
+    ) -> LodChain {
+        let levels = levels
+            .iter()
+            .map(|level| VAO::from_mesh(gl, level, shininess))
+            .collect();
+
+        let mut min = glm::vec3(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = -min;
+        for position in mesh.positions.chunks(3) {
+            min = glm::min2(&min, &glm::make_vec3(position));
+            max = glm::max2(&max, &glm::make_vec3(position));
+        }
+        let center = (min + max) / 2.;
+        let radius = mesh
+            .positions
+            .chunks(3)
+            .map(|position| glm::distance(&center, &glm::make_vec3(position)))
+            .fold(0., f32::max);
+        LodChain {
+            levels,
+            center,
+            radius,
+        }
+    }
+
+    /// VAO to draw for a node with the given full-detail VAO, as seen through `view_transform`.
+    /// A bias below 1 makes the node count as smaller, so simpler levels are picked sooner.
+    pub fn select(
+        &self,
+        full: VAO,
+        model_matrix: &glm::Mat4,
+        view_transform: &glm::Mat4,
+        bias: f32,
+    ) -> VAO {
+        let center = model_matrix * glm::vec4(self.center.x, self.center.y, self.center.z, 1.);
+        // Clip space w is the distance along the view direction
+        let distance = (view_transform * center).w;
+        let scale = (0..3)
+            .map(|column| {
+                glm::length(&glm::vec3(
+                    model_matrix[(0, column)],
+                    model_matrix[(1, column)],
+                    model_matrix[(2, column)],
+                ))
+            })
+            .fold(0., f32::max);
+        let radius = self.radius * scale;
+        if distance <= radius {
+            return full;
+        }
+        // The rows of the view transform are the view axes scaled by the projection,
+        // so the length of the second one is the focal length however the camera is turned
+        let focal_length = glm::length(&glm::vec3(
+            view_transform[(1, 0)],
+            view_transform[(1, 1)],
+            view_transform[(1, 2)],
+        ));
+        let size = radius * focal_length / distance * bias;
+        if size >= FULL_DETAIL_SIZE {
+            return full;
+        }
+        let level = (FULL_DETAIL_SIZE / size).log2() as usize;
+        self.levels
+            .get(level.min(self.levels.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or(full)
+    }
+}
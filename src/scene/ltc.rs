@@ -0,0 +1,546 @@
+use std::convert::TryInto;
+use std::f32::consts::PI;
+
+use glow::{HasContext, NativeTexture};
+
+use super::vao::to_u8_slice;
+
+// Linearly transformed cosines, from "Real-Time Polygonal-Light Shading with
+// Linearly Transformed Cosines" by Heitz et al., with the fitting ported from their code.
+// A clamped cosine lobe transformed by a 3x3 matrix approximates the GGX BRDF,
+// and can be integrated over a polygon exactly, which is what the area lights need.
+
+/// Fitted tables, made by the `fit-ltc` subcommand and checked by `check-ltc`
+pub const LTC_TABLES: &str = "res/textures/ltc.bin";
+
+/// Entries along each side of the tables, must match LTC_SIZE in lights.glsl
+const LTC_SIZE: usize = 64;
+/// Samples along each axis when comparing a lobe with the BRDF
+const SAMPLES: usize = 32;
+const MIN_ALPHA: f32 = 0.00001;
+
+/// GGX BRDF times the cosine for a view direction around the normal +z,
+/// together with the probability density of `sample_ggx` giving the light direction
+fn ggx(view: &glm::Vec3, light: &glm::Vec3, alpha: f32) -> (f32, f32) {
+    if view.z <= 0. {
+        return (0., 0.);
+    }
+    // Smith masking and shadowing
+    let lambda = |cos_theta: f32| {
+        if cos_theta >= 1. {
+            return 0.;
+        }
+        let a = 1. / (alpha * cos_theta.acos().tan());
+        0.5 * (-1. + (1. + 1. / (a * a)).sqrt())
+    };
+    let shadowing = if light.z <= 0. {
+        0.
+    } else {
+        1. / (1. + lambda(view.z) + lambda(light.z))
+    };
+    let half = glm::normalize(&(view + light));
+    let slope = (half.x * half.x + half.y * half.y) / (half.z * half.z);
+    let d = 1. / (1. + slope / (alpha * alpha));
+    let d = d * d / (PI * alpha * alpha * half.z.powi(4));
+    let pdf = (d * half.z / 4. / glm::dot(view, &half)).abs();
+    (d * shadowing / 4. / view.z, pdf)
+}
+
+/// Light direction reflected off a microfacet normal picked from the GGX distribution
+fn sample_ggx(view: &glm::Vec3, alpha: f32, u1: f32, u2: f32) -> glm::Vec3 {
+    let phi = 2. * PI * u1;
+    let r = alpha * (u2 / (1. - u2)).sqrt();
+    let normal = glm::normalize(&glm::vec3(r * phi.cos(), r * phi.sin(), 1.));
+    normal * 2. * glm::dot(&normal, view) - view
+}
+
+/// Points spread evenly over the unit square
+fn sample_grid() -> impl Iterator<Item = (f32, f32)> {
+    (0..SAMPLES * SAMPLES).map(|i| {
+        let u1 = ((i % SAMPLES) as f32 + 0.5) / SAMPLES as f32;
+        let u2 = ((i / SAMPLES) as f32 + 0.5) / SAMPLES as f32;
+        (u1, u2)
+    })
+}
+
+/// Clamped cosine lobe, scaled by `magnitude` and transformed by `matrix`,
+/// which is built from a basis and the three parameters that get fitted
+#[derive(Clone, Copy)]
+struct Lobe {
+    magnitude: f32,
+    /// Average of the Schlick Fresnel term, for the specular color
+    fresnel: f32,
+    m11: f32,
+    m22: f32,
+    m13: f32,
+    basis: glm::Mat3,
+    matrix: glm::Mat3,
+    inverse: glm::Mat3,
+    determinant: f32,
+}
+
+impl Lobe {
+    fn new() -> Lobe {
+        Lobe {
+            magnitude: 1.,
+            fresnel: 1.,
+            m11: 1.,
+            m22: 1.,
+            m13: 0.,
+            basis: glm::Mat3::identity(),
+            matrix: glm::Mat3::identity(),
+            inverse: glm::Mat3::identity(),
+            determinant: 1.,
+        }
+    }
+
+    fn update(&mut self) {
+        #[rustfmt::skip]
+        let parameters = glm::mat3(
+            self.m11, 0., self.m13,
+            0., self.m22, 0.,
+            0., 0., 1.,
+        );
+        self.matrix = self.basis * parameters;
+        self.inverse = self
+            .matrix
+            .try_inverse()
+            .unwrap_or_else(glm::Mat3::identity);
+        self.determinant = self.matrix.determinant().abs();
+    }
+
+    fn eval(&self, light: &glm::Vec3) -> f32 {
+        let original = glm::normalize(&(self.inverse * light));
+        let length = glm::length(&(self.matrix * original));
+        let jacobian = self.determinant / length.powi(3);
+        self.magnitude * original.z.max(0.) / PI / jacobian
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> glm::Vec3 {
+        let theta = u1.sqrt().acos();
+        let phi = 2. * PI * u2;
+        let direction = glm::vec3(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos(),
+        );
+        glm::normalize(&(self.matrix * direction))
+    }
+
+    /// Difference from the BRDF, sampled both by the lobe and by the BRDF
+    /// and weighted by multiple importance sampling
+    fn error(&self, view: &glm::Vec3, alpha: f32) -> f64 {
+        let mut error = 0.;
+        for (u1, u2) in sample_grid() {
+            for light in [self.sample(u1, u2), sample_ggx(view, alpha, u1, u2)] {
+                let (brdf, brdf_pdf) = ggx(view, &light, alpha);
+                let value = self.eval(&light);
+                let pdf = value / self.magnitude;
+                let difference = (brdf - value).abs() as f64;
+                error += difference.powi(3) / (pdf + brdf_pdf) as f64;
+            }
+        }
+        error / (SAMPLES * SAMPLES) as f64
+    }
+
+    fn set_parameters(&mut self, parameters: &[f32; 3], isotropic: bool) {
+        self.m11 = parameters[0].max(1e-7);
+        self.m22 = if isotropic {
+            self.m11
+        } else {
+            parameters[1].max(1e-7)
+        };
+        self.m13 = if isotropic { 0. } else { parameters[2] };
+        self.update();
+    }
+
+    /// Find the parameters closest to the BRDF, starting from the current ones
+    fn fit(&mut self, view: &glm::Vec3, alpha: f32, isotropic: bool) {
+        let start = [self.m11, self.m22, self.m13];
+        let best = nelder_mead(start, 0.05, 1e-5, 100, |parameters| {
+            let mut lobe = *self;
+            lobe.set_parameters(parameters, isotropic);
+            lobe.error(view, alpha)
+        });
+        self.set_parameters(&best, isotropic);
+    }
+}
+
+/// Albedo of the BRDF, its average Fresnel term and its average direction
+fn average_terms(view: &glm::Vec3, alpha: f32) -> (f32, f32, glm::Vec3) {
+    let (mut magnitude, mut fresnel, mut direction) = (0., 0., glm::Vec3::zeros());
+    for (u1, u2) in sample_grid() {
+        let light = sample_ggx(view, alpha, u1, u2);
+        let (value, pdf) = ggx(view, &light, alpha);
+        if pdf > 0. {
+            let weight = value / pdf;
+            let half = glm::normalize(&(view + light));
+            magnitude += weight;
+            fresnel += weight * (1. - glm::dot(view, &half).max(0.)).powi(5);
+            direction += light * weight;
+        }
+    }
+    let count = (SAMPLES * SAMPLES) as f32;
+    // Isotropic, so the average lies in the plane of the view direction
+    direction.y = 0.;
+    (
+        magnitude / count,
+        fresnel / count,
+        glm::normalize(&direction),
+    )
+}
+
+/// Minimize a function of three parameters with the downhill simplex method
+fn nelder_mead(
+    start: [f32; 3],
+    delta: f32,
+    tolerance: f64,
+    max_iterations: usize,
+    function: impl Fn(&[f32; 3]) -> f64,
+) -> [f32; 3] {
+    let mut points = [start; 4];
+    for (i, point) in points.iter_mut().skip(1).enumerate() {
+        point[i] += delta;
+    }
+    let mut values = points.map(|point| function(&point));
+    let along = |from: &[f32; 3], to: &[f32; 3], t: f32| -> [f32; 3] {
+        [0, 1, 2].map(|i| from[i] + t * (to[i] - from[i]))
+    };
+
+    for _ in 0..max_iterations {
+        let mut order = [0, 1, 2, 3];
+        order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+        let (lowest, next_highest, highest) = (order[0], order[2], order[3]);
+        let (low, high) = (values[lowest].abs(), values[highest].abs());
+        if 2. * (low - high).abs() < (low + high) * tolerance {
+            break;
+        }
+
+        // Centroid of all points but the worst one
+        let mut centroid = [0.; 3];
+        for &i in &order[..3] {
+            for axis in 0..3 {
+                centroid[axis] += points[i][axis] / 3.;
+            }
+        }
+
+        let worst = points[highest];
+        let reflected = along(&centroid, &worst, -1.);
+        let reflected_value = function(&reflected);
+        if reflected_value < values[next_highest] {
+            (points[highest], values[highest]) = (reflected, reflected_value);
+            if reflected_value < values[lowest] {
+                let expanded = along(&centroid, &worst, -2.);
+                let expanded_value = function(&expanded);
+                if expanded_value < reflected_value {
+                    (points[highest], values[highest]) = (expanded, expanded_value);
+                }
+            }
+            continue;
+        }
+
+        let contracted = along(&centroid, &worst, 0.5);
+        let contracted_value = function(&contracted);
+        if contracted_value < values[highest] {
+            (points[highest], values[highest]) = (contracted, contracted_value);
+            continue;
+        }
+
+        // Shrink everything towards the best point
+        for &i in &order[1..] {
+            points[i] = along(&points[lowest], &points[i], 0.5);
+            values[i] = function(&points[i]);
+        }
+    }
+    let best = (0..4)
+        .min_by(|&a, &b| values[a].total_cmp(&values[b]))
+        .unwrap();
+    points[best]
+}
+
+/// Fitted lobe for one roughness and view angle. The tables are indexed by
+/// the roughness and by sqrt(1 - cos(theta)) of the angle between the view and the normal.
+fn fit_entry(lobe: &mut Lobe, roughness_index: usize, theta_index: usize, rougher: Option<f32>) {
+    let x = theta_index as f32 / (LTC_SIZE - 1) as f32;
+    let theta = (1. - x * x).acos().min(1.57);
+    let view = glm::vec3(theta.sin(), 0., theta.cos());
+    let roughness = roughness_index as f32 / (LTC_SIZE - 1) as f32;
+    let alpha = (roughness * roughness).max(MIN_ALPHA);
+
+    let (magnitude, fresnel, direction) = average_terms(&view, alpha);
+    lobe.magnitude = magnitude;
+    lobe.fresnel = fresnel;
+    let isotropic = theta_index == 0;
+    if isotropic {
+        // Looking straight down the lobe is symmetric, start from the rougher fit
+        lobe.basis = glm::Mat3::identity();
+        lobe.m11 = rougher.unwrap_or(1.);
+        lobe.m22 = lobe.m11;
+        lobe.m13 = 0.;
+    } else {
+        // Otherwise start from the previous angle, turned towards the average direction
+        lobe.basis = glm::Mat3::from_columns(&[
+            glm::vec3(direction.z, 0., -direction.x),
+            glm::vec3(0., 1., 0.),
+            direction,
+        ]);
+    }
+    lobe.update();
+    lobe.fit(&view, alpha, isotropic);
+}
+
+/// Fit the lobes for every roughness and angle. Gives the inverse matrices,
+/// scaled so the middle element is 1 and reduced to their four other non-zero elements,
+/// followed by the magnitudes and Fresnel terms, as two tables of RGBA texels.
+pub fn fit_tables() -> Vec<f32> {
+    // Normal incidence first, each roughness starting from the one above
+    let mut normal_incidence = vec![Lobe::new(); LTC_SIZE];
+    let mut rougher = None;
+    for roughness_index in (0..LTC_SIZE).rev() {
+        let lobe = &mut normal_incidence[roughness_index];
+        fit_entry(lobe, roughness_index, 0, rougher);
+        rougher = Some(lobe.matrix[(0, 0)]);
+    }
+    // Then every roughness on its own thread, each angle starting from the one before
+    let columns: Vec<Vec<Lobe>> = std::thread::scope(|scope| {
+        let threads: Vec<_> = normal_incidence
+            .iter()
+            .enumerate()
+            .map(|(roughness_index, &first)| {
+                scope.spawn(move || {
+                    let mut lobe = first;
+                    let mut column = vec![first];
+                    for theta_index in 1..LTC_SIZE {
+                        fit_entry(&mut lobe, roughness_index, theta_index, None);
+                        column.push(lobe);
+                    }
+                    column
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("LTC fitting failed"))
+            .collect()
+    });
+
+    let mut matrices = vec![];
+    let mut amplitudes = vec![];
+    for theta_index in 0..LTC_SIZE {
+        for column in &columns {
+            let lobe = &column[theta_index];
+            let inverse = lobe.inverse / lobe.inverse[(1, 1)];
+            matrices.extend_from_slice(&[
+                inverse[(0, 0)],
+                inverse[(2, 0)],
+                inverse[(0, 2)],
+                inverse[(2, 2)],
+            ]);
+            amplitudes.extend_from_slice(&[lobe.magnitude, lobe.fresnel, 0., 0.]);
+        }
+    }
+    matrices.extend(amplitudes);
+    matrices
+}
+
+/// Fit the tables and save them, for the `fit-ltc` subcommand
+pub fn write_tables(path: &str) {
+    let before = std::time::Instant::now();
+    let tables = fit_tables();
+    let bytes: Vec<u8> = tables
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    std::fs::write(path, bytes)
+        .unwrap_or_else(|error| panic!("Could not write {}: {}", path, error));
+    println!(
+        "Fitted LTC tables in {} seconds",
+        before.elapsed().as_secs_f32()
+    );
+}
+
+/// Both tables from a file, as written by `write_tables`
+fn read_tables(path: &str) -> Vec<f32> {
+    let bytes = std::fs::read(path)
+        .unwrap_or_else(|_| panic!("No LTC tables at {}, run with fit-ltc", path));
+    let values: Vec<f32> = bytes
+        .chunks_exact(4)
+        .map(|word| f32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    if values.len() != 2 * LTC_SIZE * LTC_SIZE * 4 {
+        panic!(
+            "{} does not hold two {}x{} tables",
+            path, LTC_SIZE, LTC_SIZE
+        );
+    }
+    values
+}
+
+/// Part of a polygon above the horizon, cut off where it crosses it
+fn clip_to_horizon(polygon: &[glm::Vec3]) -> Vec<glm::Vec3> {
+    let mut clipped = vec![];
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if a.z > 0. {
+            clipped.push(*a);
+        }
+        if (a.z > 0.) != (b.z > 0.) {
+            clipped.push(a + (b - a) * (a.z / (a.z - b.z)));
+        }
+    }
+    clipped
+}
+
+/// Integral of the clamped cosine over a polygon transformed by a matrix,
+/// like ltc_integral in lights.glsl
+fn polygon_integral(matrix: &glm::Mat3, polygon: &[glm::Vec3]) -> f32 {
+    let corners: Vec<glm::Vec3> = polygon.iter().map(|corner| matrix * corner).collect();
+    let clipped = clip_to_horizon(&corners);
+    let mut sum = 0.;
+    for (i, a) in clipped.iter().enumerate() {
+        let a = glm::normalize(a);
+        let b = glm::normalize(&clipped[(i + 1) % clipped.len()]);
+        let cross = glm::cross(&a, &b);
+        let length = glm::length(&cross);
+        if length > 0. {
+            sum += cross.z / length * glm::dot(&a, &b).clamp(-1., 1.).acos();
+        }
+    }
+    sum.abs() / (2. * PI)
+}
+
+/// Whether a ray from the origin passes through a triangle
+fn hits_triangle(direction: &glm::Vec3, a: &glm::Vec3, b: &glm::Vec3, c: &glm::Vec3) -> bool {
+    let (edge1, edge2) = (b - a, c - a);
+    let p = glm::cross(direction, &edge2);
+    let determinant = glm::dot(&edge1, &p);
+    if determinant.abs() < 1e-8 {
+        return false;
+    }
+    let q = glm::cross(&-a, &edge1);
+    let u = glm::dot(&-a, &p) / determinant;
+    let v = glm::dot(direction, &q) / determinant;
+    let t = glm::dot(&edge2, &q) / determinant;
+    u >= 0. && v >= 0. && u + v <= 1. && t > 0.
+}
+
+/// Integral of the GGX BRDF times the cosine over a convex polygon, by importance sampling
+fn reference_integral(view: &glm::Vec3, alpha: f32, polygon: &[glm::Vec3]) -> f32 {
+    let steps = 512;
+    let mut sum = 0.;
+    for i in 0..steps * steps {
+        let u1 = ((i % steps) as f32 + 0.5) / steps as f32;
+        let u2 = ((i / steps) as f32 + 0.5) / steps as f32;
+        let light = sample_ggx(view, alpha, u1, u2);
+        let hit = (1..polygon.len() - 1)
+            .any(|j| hits_triangle(&light, &polygon[0], &polygon[j], &polygon[j + 1]));
+        let (value, pdf) = ggx(view, &light, alpha);
+        if hit && pdf > 0. {
+            sum += (value / pdf) as f64;
+        }
+    }
+    (sum / (steps * steps) as f64) as f32
+}
+
+/// Compare the tables in a file with the BRDF integrated over a few quads
+/// for some of the fitted roughnesses and angles, for the `check-ltc` subcommand
+pub fn check_tables(path: &str) {
+    let tables = read_tables(path);
+    let (matrices, amplitudes) = tables.split_at(LTC_SIZE * LTC_SIZE * 4);
+    // Above the surface, a wall on the side of the reflection and one crossing the horizon
+    #[rustfmt::skip]
+    let quads = [
+        ("overhead", [glm::vec3(-1., -1., 1.), glm::vec3(1., -1., 1.), glm::vec3(1., 1., 1.), glm::vec3(-1., 1., 1.)]),
+        ("reflected", [glm::vec3(-1., -1., 0.2), glm::vec3(-1., 1., 0.2), glm::vec3(-1., 1., 1.5), glm::vec3(-1., -1., 1.5)]),
+        ("horizon", [glm::vec3(1., -1., -0.5), glm::vec3(1., 1., -0.5), glm::vec3(1., 1., 1.), glm::vec3(1., -1., 1.)]),
+    ];
+    let mut largest: f32 = 0.;
+    for roughness_index in [16, 32, 48, 63] {
+        for theta_index in [0, 24, 40, 56] {
+            let x = theta_index as f32 / (LTC_SIZE - 1) as f32;
+            let theta = (1. - x * x).acos().min(1.57);
+            let view = glm::vec3(theta.sin(), 0., theta.cos());
+            let roughness = roughness_index as f32 / (LTC_SIZE - 1) as f32;
+            let alpha = (roughness * roughness).max(MIN_ALPHA);
+
+            let texel = (theta_index * LTC_SIZE + roughness_index) * 4;
+            let entry = &matrices[texel..texel + 4];
+            #[rustfmt::skip]
+            let inverse = glm::mat3(
+                entry[0], 0., entry[2],
+                0., 1., 0.,
+                entry[1], 0., entry[3],
+            );
+            let magnitude = amplitudes[texel];
+            for (name, quad) in &quads {
+                let fitted = magnitude * polygon_integral(&inverse, quad);
+                let reference = reference_integral(&view, alpha, quad);
+                largest = largest.max((fitted - reference).abs());
+                println!(
+                    "roughness {:.2}, view angle {:4.1}, {:9} quad: {:.4} fitted, {:.4} reference",
+                    roughness,
+                    theta.to_degrees(),
+                    name,
+                    fitted,
+                    reference
+                );
+            }
+        }
+    }
+    println!("Largest difference: {:.4}", largest);
+}
+
+/// The fitted tables as textures, for the area lights in lights.glsl
+pub struct LtcTables {
+    pub matrices: NativeTexture,
+    pub amplitudes: NativeTexture,
+}
+
+impl LtcTables {
+    pub unsafe fn from_file(gl: &glow::Context, path: &str) -> LtcTables {
+        let values = read_tables(path);
+        let table_length = LTC_SIZE * LTC_SIZE * 4;
+        let mut textures = values.chunks(table_length).map(|table| {
+            let texture = gl.create_texture().expect("Could not create texture");
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                glow::RGBA32F as i32,
+                LTC_SIZE as i32,
+                LTC_SIZE as i32,
+                0,
+                glow::RGBA,
+                glow::FLOAT,
+                Some(to_u8_slice(table)),
+            );
+            for (parameter, value) in [
+                (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+                (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                gl.tex_parameter_i32(glow::TEXTURE_2D, parameter, value as i32);
+            }
+            texture
+        });
+        let matrices = textures.next().unwrap();
+        let amplitudes = textures.next().unwrap();
+        LtcTables {
+            matrices,
+            amplitudes,
+        }
+    }
+
+    /// Bind the tables where lights.glsl expects them
+    pub unsafe fn bind(&self, gl: &glow::Context) {
+        for (unit, texture) in [
+            (glow::TEXTURE10, self.matrices),
+            (glow::TEXTURE11, self.amplitudes),
+        ] {
+            gl.active_texture(unit);
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        }
+        gl.active_texture(glow::TEXTURE0);
+    }
+}
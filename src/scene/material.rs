@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use glow::NativeTexture;
+
+use super::graph::{BlendMode, Node};
+use super::texture::{FrameBufferTexture, ImageTexture};
+
+/// Extensions to try when an MTL file refers to an image that was converted to something else
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "png", "exr"];
+
+/// Where to look for images that aren't where the MTL file says
+const TEXTURE_DIRECTORY: &str = "res/textures";
+
+/// Different names the same kind of map goes by, as (in MTL file, on disk)
+const NAME_ALIASES: [(&str, &str); 2] = [("_roughness_", "_rough_"), ("_metallic_", "_metal_")];
+
+/// Opacity of glass that the MTL file gives a dissolve of zero
+const GLASS_ALPHA: f32 = 0.2;
+
+/// Surface of a node as described by an MTL file
+#[derive(Clone, Copy)]
+pub struct Material {
+    pub texture: Option<FrameBufferTexture>,
+    pub normal_map: Option<FrameBufferTexture>,
+    pub roughness_map: Option<FrameBufferTexture>,
+    pub opacity_map: Option<FrameBufferTexture>,
+    pub blend_mode: BlendMode,
+    pub emission: glm::Vec3,
+}
+
+impl Material {
+    /// Give a node this surface
+    pub fn apply(&self, node: &mut Node) {
+        node.texture = self.texture;
+        node.normal_map = self.normal_map;
+        node.roughness_map = self.roughness_map;
+        node.opacity_map = self.opacity_map;
+        node.blend_mode = self.blend_mode;
+        node.emission = self.emission;
+    }
+}
+
+/// Builds materials from the texture references in MTL files,
+/// loading each image only once even when several materials use it
+pub struct MaterialLoader {
+    /// Directory of the MTL file, which texture paths are relative to
+    directory: PathBuf,
+    /// By the file they were loaded from, or nothing for files that don't exist
+    textures: HashMap<PathBuf, Option<FrameBufferTexture>>,
+}
+
+impl MaterialLoader {
+    pub fn new(directory: &Path) -> MaterialLoader {
+        MaterialLoader {
+            directory: directory.to_path_buf(),
+            textures: HashMap::new(),
+        }
+    }
+
+    /// Load an image file as a texture, or nothing if it doesn't exist
+    pub unsafe fn texture(
+        &mut self,
+        gl: &glow::Context,
+        path: &Path,
+    ) -> Option<FrameBufferTexture> {
+        if let Some(&texture) = self.textures.get(path) {
+            return texture;
+        }
+        let texture = if path.exists() {
+            Some(ImageTexture::new(gl, &path.to_string_lossy()))
+        } else {
+            None
+        };
+        self.textures.insert(path.to_path_buf(), texture);
+        texture
+    }
+
+    /// Every texture loaded so far, with the file it came from
+    pub fn files(&self) -> impl Iterator<Item = (NativeTexture, PathBuf)> + '_ {
+        self.textures
+            .iter()
+            .filter_map(|(path, texture)| texture.map(|texture| (texture.texture, path.clone())))
+    }
+
+    /// Find the image a texture statement refers to, like `-bm 1.0 textures/name_nor_gl_2k.exr`.
+    /// Falls back to the texture directory and other extensions,
+    /// since the files in the repository don't always match what was exported.
+    fn find_texture(&self, statement: &str) -> Option<PathBuf> {
+        // Options come first, the file name last
+        let file = Path::new(statement.split_whitespace().last()?);
+        let file_name = file.file_name()?.to_string_lossy();
+        let mut candidates = vec![
+            self.directory.join(file),
+            Path::new(TEXTURE_DIRECTORY).join(&*file_name),
+        ];
+        for (alias, name) in NAME_ALIASES {
+            if file_name.contains(alias) {
+                candidates.push(Path::new(TEXTURE_DIRECTORY).join(file_name.replace(alias, name)));
+            }
+        }
+        for candidate in candidates {
+            if candidate.exists() {
+                return Some(candidate);
+            }
+            for extension in IMAGE_EXTENSIONS {
+                let candidate = candidate.with_extension(extension);
+                if candidate.exists() {
+                    return Some(candidate);
+                }
+            }
+        }
+        println!("Could not find texture {} from MTL file", statement);
+        None
+    }
+
+    /// Texture for the first of the given MTL statements that is set and can be found
+    unsafe fn texture_from(
+        &mut self,
+        gl: &glow::Context,
+        statements: &[Option<&String>],
+    ) -> Option<FrameBufferTexture> {
+        let path = statements
+            .iter()
+            .flatten()
+            .filter(|statement| !statement.is_empty())
+            .find_map(|statement| self.find_texture(statement))?;
+        self.texture(gl, &path)
+    }
+
+    /// Build a material from `map_Kd`, `map_Bump`/`norm`, `map_Pr`/`map_Ns`, `map_d`, `d` and `Ke`
+    pub unsafe fn load(&mut self, gl: &glow::Context, material: &tobj::Material) -> Material {
+        let unknown = |key: &str| material.unknown_param.get(key);
+        let texture = self.texture_from(gl, &[Some(&material.diffuse_texture)]);
+        let normal_map = self.texture_from(gl, &[Some(&material.normal_texture), unknown("norm")]);
+        // Blender exports roughness maps as map_Ns, so that is read as roughness too
+        let roughness_map =
+            self.texture_from(gl, &[unknown("map_Pr"), Some(&material.shininess_texture)]);
+
+        // Opacity maps are cut out (like the fringe of the sofa),
+        // unless the material is also partly see-through as a whole (like glass)
+        // A dissolve of zero is what Blender writes for transmissive glass (illum 9 and the like),
+        // so it isn't taken literally, the glass is just made faint
+        let dissolve = if material.dissolve <= 0. {
+            GLASS_ALPHA
+        } else {
+            material.dissolve
+        };
+        let see_through = dissolve < 1.;
+        let mut opacity_map = self.texture_from(gl, &[Some(&material.dissolve_texture)]);
+        let blend_mode = match (opacity_map, see_through) {
+            (Some(_), false) => BlendMode::Cutout { threshold: 0.5 },
+            (Some(_), true) => BlendMode::Transparent,
+            (None, true) => {
+                let alpha = (dissolve * 255.).round() as u8;
+                opacity_map = Some(ImageTexture::from_pixels(
+                    gl,
+                    1,
+                    1,
+                    &[alpha, alpha, alpha, 255],
+                ));
+                BlendMode::Transparent
+            }
+            (None, false) => BlendMode::Opaque,
+        };
+
+        let emission = unknown("Ke")
+            .and_then(|value| {
+                let channels: Vec<f32> = value
+                    .split_whitespace()
+                    .filter_map(|channel| channel.parse().ok())
+                    .collect();
+                match channels[..] {
+                    [grey] => Some(glm::vec3(grey, grey, grey)),
+                    [r, g, b] => Some(glm::vec3(r, g, b)),
+                    _ => {
+                        println!(
+                            "Ignoring malformed Ke {} in material {}",
+                            value, material.name
+                        );
+                        None
+                    }
+                }
+            })
+            .unwrap_or_else(glm::zero);
+
+        Material {
+            texture,
+            normal_map,
+            roughness_map,
+            opacity_map,
+            blend_mode,
+            emission,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+/// One vertex attribute within an interleaved vertex, in floats
+#[derive(Clone, Copy)]
+pub struct VertexAttribute {
+    /// Location in the vertex shader, see world.vert
+    pub location: u32,
+    pub components: i32,
+    pub offset: i32,
+}
+
+/// How the attributes of a mesh are laid out after each other in a single buffer
+pub struct VertexLayout {
+    pub attributes: Vec<VertexAttribute>,
+    /// Size of a whole vertex, in floats
+    pub stride: i32,
+}
+
+/// Vertex data on the CPU side, flattened the same way as tobj does it.
+/// UVs and tangents may be left empty, everything else has an entry per vertex.
+#[derive(Clone, Default)]
+pub struct Mesh {
+    pub positions: Vec<f32>,
+    pub normals: Vec<f32>,
+    pub uvs: Vec<f32>,
+    pub colors: Vec<f32>,
+    /// Four components each, with the handedness of the bitangent last
+    pub tangents: Vec<f32>,
+    pub indices: Vec<u32>,
+}
+
+impl Mesh {
+    /// Mesh for one model of an OBJ file, colored by the diffuse color of its material
+    pub fn from_obj(model: &tobj::Model, materials: &[tobj::Material]) -> Mesh {
+        let id = model
+            .mesh
+            .material_id
+            .expect("No material in texture; abort!");
+        let vertex_count = model.mesh.positions.len() / 3;
+        // Repeat single-color material
+        let mut color = materials[id].diffuse.to_vec();
+        color.push(1.0);
+        let mut mesh = Mesh {
+            positions: model.mesh.positions.clone(),
+            normals: model.mesh.normals.clone(),
+            uvs: model.mesh.texcoords.clone(),
+            colors: color.repeat(vertex_count),
+            tangents: vec![],
+            indices: model.mesh.indices.clone(),
+        };
+        // OBJ files may leave out the normals
+        if mesh.normals.is_empty() {
+            mesh.generate_normals();
+        }
+        mesh.generate_tangents();
+        mesh
+    }
+
+    /// Square from -1 to 1 in the xy plane, the canvas for screen-space passes
+    pub fn square() -> Mesh {
+        let mut mesh = Mesh {
+            positions: vec![-1., -1., 0., 1., -1., 0., 1., 1., 0., -1., 1., 0.],
+            normals: [0., 0., -1.].repeat(4),
+            uvs: vec![0., 0., 1., 0., 1., 1., 0., 1.],
+            colors: [1., 0., 1., 1.].repeat(4), // Color is irrelevant here
+            tangents: vec![],
+            indices: vec![0, 1, 2, 0, 2, 3],
+        };
+        mesh.generate_tangents();
+        mesh
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len() / 3
+    }
+
+    /// Fill in normals from the faces around each vertex, for meshes that come without them
+    pub fn generate_normals(&mut self) {
+        let mut normals = vec![glm::Vec3::zeros(); self.vertex_count()];
+        let position = |i: u32| {
+            let i = i as usize * 3;
+            glm::make_vec3(&self.positions[i..i + 3])
+        };
+        for triangle in self.indices.chunks(3) {
+            // Not normalized, so larger faces count more
+            let normal = glm::cross(
+                &(position(triangle[1]) - position(triangle[0])),
+                &(position(triangle[2]) - position(triangle[0])),
+            );
+            for &i in triangle {
+                normals[i as usize] += normal;
+            }
+        }
+        self.normals = normals
+            .iter()
+            .flat_map(|n| {
+                let n = glm::normalize(n);
+                [n.x, n.y, n.z]
+            })
+            .collect();
+    }
+
+    /// Fill in tangents for normal mapping with MikkTSpace, if there are UVs to take them from,
+    /// so normal maps baked by other tools line up. Vertices whose corners get different tangents,
+    /// like along UV seams and where mirrored halves meet, are split up.
+    pub fn generate_tangents(&mut self) {
+        if self.uvs.is_empty() {
+            return;
+        }
+        let mut corners = TriangleCorners {
+            mesh: self,
+            tangents: vec![[0.; 4]; self.indices.len()],
+        };
+        if !bevy_mikktspace::generate_tangents(&mut corners) {
+            println!("Could not generate tangents for a mesh");
+            return;
+        }
+        let tangents = corners.tangents;
+
+        // Weld corners back together where they share both vertex and tangent
+        let mut welded = Mesh::default();
+        let mut new_index = HashMap::new();
+        for (&vertex, tangent) in self.indices.iter().zip(&tangents) {
+            let key = (vertex, tangent.map(f32::to_bits));
+            let index = *new_index.entry(key).or_insert_with(|| {
+                let v = vertex as usize;
+                let copy = |source: &[f32], into: &mut Vec<f32>, components: usize| {
+                    into.extend_from_slice(&source[v * components..(v + 1) * components]);
+                };
+                copy(&self.positions, &mut welded.positions, 3);
+                copy(&self.normals, &mut welded.normals, 3);
+                copy(&self.uvs, &mut welded.uvs, 2);
+                copy(&self.colors, &mut welded.colors, 4);
+                welded.tangents.extend_from_slice(tangent);
+                welded.vertex_count() as u32 - 1
+            });
+            welded.indices.push(index);
+        }
+        *self = welded;
+    }
+
+    /// Check that every attribute has an entry per vertex and that the indices stay within them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.positions.len() % 3 != 0 {
+            return Err(format!(
+                "{} position floats is not a multiple of 3",
+                self.positions.len()
+            ));
+        }
+        let vertex_count = self.vertex_count();
+        for (name, attribute, components, optional) in [
+            ("normals", &self.normals, 3, false),
+            ("UVs", &self.uvs, 2, true),
+            ("colors", &self.colors, 4, false),
+            ("tangents", &self.tangents, 4, true),
+        ] {
+            if !(optional && attribute.is_empty()) && attribute.len() != vertex_count * components {
+                return Err(format!(
+                    "{} {} floats for {} vertices",
+                    attribute.len(),
+                    name,
+                    vertex_count
+                ));
+            }
+        }
+        if self.indices.len() % 3 != 0 {
+            return Err(format!(
+                "{} indices don't make whole triangles",
+                self.indices.len()
+            ));
+        }
+        if let Some(&index) = self.indices.iter().find(|&&i| i as usize >= vertex_count) {
+            return Err(format!(
+                "Index {} out of bounds for {} vertices",
+                index, vertex_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Attributes present in this mesh, at the locations world.vert expects them
+    pub fn layout(&self) -> VertexLayout {
+        let mut attributes = vec![];
+        let mut offset = 0;
+        for (location, attribute, components) in [
+            (0, &self.positions, 3),
+            (1, &self.normals, 3),
+            (2, &self.uvs, 2),
+            (3, &self.colors, 4),
+            (4, &self.tangents, 4),
+        ] {
+            if !attribute.is_empty() {
+                attributes.push(VertexAttribute {
+                    location,
+                    components,
+                    offset,
+                });
+                offset += components;
+            }
+        }
+        VertexLayout {
+            attributes,
+            stride: offset,
+        }
+    }
+
+    /// All attributes of each vertex after each other, as described by `layout`
+    pub fn interleaved(&self, layout: &VertexLayout) -> Vec<f32> {
+        let sources: Vec<&Vec<f32>> = layout
+            .attributes
+            .iter()
+            .map(|attribute| match attribute.location {
+                0 => &self.positions,
+                1 => &self.normals,
+                2 => &self.uvs,
+                3 => &self.colors,
+                _ => &self.tangents,
+            })
+            .collect();
+        let mut vertices = Vec::with_capacity(self.vertex_count() * layout.stride as usize);
+        for i in 0..self.vertex_count() {
+            for (attribute, source) in layout.attributes.iter().zip(&sources) {
+                let components = attribute.components as usize;
+                vertices.extend_from_slice(&source[i * components..(i + 1) * components]);
+            }
+        }
+        vertices
+    }
+}
+
+/// Every corner of every triangle on its own, the way MikkTSpace looks at a mesh
+struct TriangleCorners<'a> {
+    mesh: &'a Mesh,
+    /// Result for each corner, in the order of the indices
+    tangents: Vec<[f32; 4]>,
+}
+
+impl TriangleCorners<'_> {
+    fn vertex(&self, face: usize, corner: usize) -> usize {
+        self.mesh.indices[face * 3 + corner] as usize
+    }
+}
+
+impl bevy_mikktspace::Geometry for TriangleCorners<'_> {
+    fn num_faces(&self) -> usize {
+        self.mesh.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, corner: usize) -> [f32; 3] {
+        let v = self.vertex(face, corner);
+        [
+            self.mesh.positions[v * 3],
+            self.mesh.positions[v * 3 + 1],
+            self.mesh.positions[v * 3 + 2],
+        ]
+    }
+
+    fn normal(&self, face: usize, corner: usize) -> [f32; 3] {
+        let v = self.vertex(face, corner);
+        [
+            self.mesh.normals[v * 3],
+            self.mesh.normals[v * 3 + 1],
+            self.mesh.normals[v * 3 + 2],
+        ]
+    }
+
+    fn tex_coord(&self, face: usize, corner: usize) -> [f32; 2] {
+        let v = self.vertex(face, corner);
+        [self.mesh.uvs[v * 2], self.mesh.uvs[v * 2 + 1]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, corner: usize) {
+        self.tangents[face * 3 + corner] = tangent;
+    }
+}
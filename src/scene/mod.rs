@@ -1,5 +1,22 @@
+pub mod cache;
 pub mod camera;
+pub mod cluster;
+pub mod crt;
+pub mod deferred;
+pub mod environment;
+pub mod export;
 pub mod graph;
+pub mod import;
+pub mod instancing;
+pub mod light;
+pub mod lod;
+pub mod ltc;
+pub mod material;
+pub mod mesh;
+pub mod primitives;
+pub mod probes;
+pub mod sdf;
 pub mod setup;
 pub mod texture;
+pub mod transparency;
 pub mod vao;
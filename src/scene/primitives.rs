@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::f32::consts::{PI, TAU};
+use std::path::Path;
+use std::rc::Rc;
+
+use super::graph::{Node, NodeType, SceneGraph};
+use super::mesh::Mesh;
+use super::sdf::SdfScene;
+use super::vao::VAO;
+
+/// Shapes that are generated rather than loaded, all fitting in the cube from -1 to 1
+/// (except the tube of the torus, which sticks out by its radius)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Primitive {
+    Cube,
+    UvSphere {
+        segments: u32,
+        rings: u32,
+    },
+    Icosphere {
+        subdivisions: u32,
+    },
+    Cylinder {
+        segments: u32,
+    },
+    Cone {
+        segments: u32,
+    },
+    /// Ring of radius 1 around the y axis, with a tube of the given radius
+    Torus {
+        segments: u32,
+        sides: u32,
+        radius: f32,
+    },
+    /// Facing up
+    Plane {
+        subdivisions: u32,
+    },
+    /// Cube with its edges and corners rounded off by the given radius
+    RoundedBox {
+        radius: f32,
+        segments: u32,
+    },
+    /// Scene of a ray-marching shader at the given time, meshed with about `resolution`
+    /// cells along its longest side and colored the way the shader colors it
+    Sdf {
+        scene: SdfScene,
+        time: f32,
+        resolution: u32,
+    },
+}
+
+/// Evenly spaced values from start to end, both included
+fn steps(count: u32, start: f32, end: f32) -> Vec<f32> {
+    (0..=count)
+        .map(|i| start + (end - start) * i as f32 / count as f32)
+        .collect()
+}
+
+fn push_vertex(mesh: &mut Mesh, position: glm::Vec3, normal: glm::Vec3, uv: glm::Vec2) -> u32 {
+    mesh.positions.extend_from_slice(position.as_slice());
+    mesh.normals.extend_from_slice(normal.as_slice());
+    mesh.uvs.extend_from_slice(uv.as_slice());
+    mesh.vertex_count() as u32 - 1
+}
+
+/// Triangles with no area, like the ones meeting at the poles of a sphere, are left out
+fn push_triangle(mesh: &mut Mesh, triangle: [u32; 3]) {
+    let position = |i: u32| glm::make_vec3(&mesh.positions[i as usize * 3..i as usize * 3 + 3]);
+    let [a, b, c] = triangle.map(position);
+    if glm::length2(&glm::cross(&(b - a), &(c - a))) > 1e-12 {
+        mesh.indices.extend_from_slice(&triangle);
+    }
+}
+
+/// Add a grid of quads, with the position, normal and UV at each pair of coordinates given by `surface`.
+/// The surface must face the direction of increasing u crossed with increasing v.
+fn add_surface(
+    mesh: &mut Mesh,
+    us: &[f32],
+    vs: &[f32],
+    surface: &dyn Fn(f32, f32) -> (glm::Vec3, glm::Vec3, glm::Vec2),
+) {
+    let first = mesh.vertex_count() as u32;
+    for &v in vs {
+        for &u in us {
+            let (position, normal, uv) = surface(u, v);
+            push_vertex(mesh, position, normal, uv);
+        }
+    }
+    let row = us.len() as u32;
+    for j in 0..vs.len() as u32 - 1 {
+        for i in 0..row - 1 {
+            let a = first + j * row + i;
+            push_triangle(mesh, [a, a + 1, a + row + 1]);
+            push_triangle(mesh, [a, a + row + 1, a + row]);
+        }
+    }
+}
+
+/// Add the six sides of the cube from -1 to 1, split along the given coordinates,
+/// with each point on them moved by `shape`, which gives a new position and normal
+fn add_box(
+    mesh: &mut Mesh,
+    coordinates: &[f32],
+    shape: &dyn Fn(glm::Vec3, glm::Vec3) -> (glm::Vec3, glm::Vec3),
+) {
+    // As (normal, up), with the UVs upright when looking at each side
+    let sides = [
+        (glm::vec3(1., 0., 0.), glm::vec3(0., 1., 0.)),
+        (glm::vec3(-1., 0., 0.), glm::vec3(0., 1., 0.)),
+        (glm::vec3(0., 1., 0.), glm::vec3(0., 0., -1.)),
+        (glm::vec3(0., -1., 0.), glm::vec3(0., 0., 1.)),
+        (glm::vec3(0., 0., 1.), glm::vec3(0., 1., 0.)),
+        (glm::vec3(0., 0., -1.), glm::vec3(0., 1., 0.)),
+    ];
+    for (normal, up) in sides {
+        let right = glm::cross(&up, &normal);
+        add_surface(mesh, coordinates, coordinates, &|a, b| {
+            let (position, normal) = shape(normal + right * a + up * b, normal);
+            (position, normal, glm::vec2((a + 1.) / 2., (b + 1.) / 2.))
+        });
+    }
+}
+
+/// Flat disk of radius 1 at the given height, facing up or down
+fn add_disk(mesh: &mut Mesh, segments: u32, y: f32, up: bool) {
+    let radii = if up { [1., 0.] } else { [0., 1.] };
+    let normal = glm::vec3(0., if up { 1. } else { -1. }, 0.);
+    add_surface(mesh, &steps(segments, 0., TAU), &radii, &|angle, radius| {
+        let (x, z) = (angle.sin() * radius, angle.cos() * radius);
+        // Projected straight down, upright when looking at it from outside
+        let v = if up { (1. - z) / 2. } else { (z + 1.) / 2. };
+        (glm::vec3(x, y, z), normal, glm::vec2((x + 1.) / 2., v))
+    });
+}
+
+/// Subdivided icosahedron pushed out onto the unit sphere,
+/// with vertices split along the UV seam and at the poles
+fn add_icosphere(mesh: &mut Mesh, subdivisions: u32) {
+    let t = (1. + 5f32.sqrt()) / 2.;
+    let mut points: Vec<glm::Vec3> = [
+        (-1., t, 0.),
+        (1., t, 0.),
+        (-1., -t, 0.),
+        (1., -t, 0.),
+        (0., -1., t),
+        (0., 1., t),
+        (0., -1., -t),
+        (0., 1., -t),
+        (t, 0., -1.),
+        (t, 0., 1.),
+        (-t, 0., -1.),
+        (-t, 0., 1.),
+    ]
+    .iter()
+    .map(|&(x, y, z)| glm::normalize(&glm::vec3(x, y, z)))
+    .collect();
+    let mut triangles: Vec<[u32; 3]> = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        // Edges are shared, so each midpoint is only made once
+        let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut midpoint = |a: u32, b: u32| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                points.push(glm::normalize(&(points[a as usize] + points[b as usize])));
+                points.len() as u32 - 1
+            })
+        };
+        triangles = triangles
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    let uv = |point: &glm::Vec3| {
+        glm::vec2(
+            0.5 + point.x.atan2(point.z) / TAU,
+            0.5 + point.y.clamp(-1., 1.).asin() / PI,
+        )
+    };
+    for triangle in triangles {
+        let mut uvs = triangle.map(|i| uv(&points[i as usize]));
+        let is_pole = triangle.map(|i| points[i as usize].y.abs() > 0.9999);
+        // Wrap around rather than going back across the whole texture
+        let max_u = (0..3)
+            .filter(|&i| !is_pole[i])
+            .map(|i| uvs[i].x)
+            .fold(f32::MIN, f32::max);
+        for i in 0..3 {
+            if !is_pole[i] && max_u - uvs[i].x > 0.5 {
+                uvs[i].x += 1.;
+            }
+        }
+        // The poles have no longitude, so they take the middle of the rest of the triangle
+        let others: Vec<f32> = (0..3).filter(|&i| !is_pole[i]).map(|i| uvs[i].x).collect();
+        for i in 0..3 {
+            if is_pole[i] {
+                uvs[i].x = others.iter().sum::<f32>() / others.len() as f32;
+            }
+        }
+        // Every corner gets its own vertex, since so many of them end up split anyway
+        let corners = [0, 1, 2].map(|i| {
+            let point = points[triangle[i] as usize];
+            push_vertex(mesh, point, point, uvs[i])
+        });
+        push_triangle(mesh, corners);
+    }
+}
+
+impl Primitive {
+    /// A primitive by name, with default parameters
+    pub fn new(name: &str) -> Option<Primitive> {
+        Some(match name {
+            "cube" => Primitive::Cube,
+            "sphere" | "uv_sphere" => Primitive::UvSphere {
+                segments: 32,
+                rings: 16,
+            },
+            "icosphere" => Primitive::Icosphere { subdivisions: 3 },
+            "cylinder" => Primitive::Cylinder { segments: 32 },
+            "cone" => Primitive::Cone { segments: 32 },
+            "torus" => Primitive::Torus {
+                segments: 32,
+                sides: 16,
+                radius: 0.25,
+            },
+            "plane" => Primitive::Plane { subdivisions: 1 },
+            "rounded_box" => Primitive::RoundedBox {
+                radius: 0.2,
+                segments: 4,
+            },
+            "smooth" | "gyroid" | "shadow" => Primitive::Sdf {
+                scene: match name {
+                    "smooth" => SdfScene::Smooth,
+                    "gyroid" => SdfScene::Gyroid,
+                    _ => SdfScene::Shadow,
+                },
+                time: 0.,
+                // The sheets of the gyroid are thin
+                resolution: if name == "gyroid" { 128 } else { 64 },
+            },
+            _ => return None,
+        })
+    }
+
+    /// Change a parameter by name, returning false if this primitive doesn't have it
+    pub fn set_parameter(&mut self, name: &str, value: f32) -> bool {
+        let count = value.round().max(0.) as u32;
+        match (self, name) {
+            (Primitive::UvSphere { segments, .. }, "segments")
+            | (Primitive::Cylinder { segments }, "segments")
+            | (Primitive::Cone { segments }, "segments")
+            | (Primitive::Torus { segments, .. }, "segments")
+            | (Primitive::RoundedBox { segments, .. }, "segments") => *segments = count,
+            (Primitive::UvSphere { rings, .. }, "rings") => *rings = count,
+            (Primitive::Icosphere { subdivisions }, "subdivisions")
+            | (Primitive::Plane { subdivisions }, "subdivisions") => *subdivisions = count,
+            (Primitive::Torus { sides, .. }, "sides") => *sides = count,
+            (Primitive::Sdf { resolution, .. }, "resolution") => *resolution = count,
+            (Primitive::Sdf { time, .. }, "time") => *time = value,
+            (Primitive::Torus { radius, .. }, "radius")
+            | (Primitive::RoundedBox { radius, .. }, "radius") => *radius = value,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Generate the mesh, in white (unless it has colors of its own) and with tangents
+    pub fn mesh(&self) -> Mesh {
+        let mut mesh = Mesh::default();
+        match *self {
+            Primitive::Cube => add_box(&mut mesh, &[-1., 1.], &|position, normal| {
+                (position, normal)
+            }),
+            Primitive::UvSphere { segments, rings } => add_surface(
+                &mut mesh,
+                &steps(segments.max(3), 0., TAU),
+                &steps(rings.max(2), -PI / 2., PI / 2.),
+                &|longitude, latitude| {
+                    let point = glm::vec3(
+                        latitude.cos() * longitude.sin(),
+                        latitude.sin(),
+                        latitude.cos() * longitude.cos(),
+                    );
+                    (
+                        point,
+                        point,
+                        glm::vec2(longitude / TAU, latitude / PI + 0.5),
+                    )
+                },
+            ),
+            Primitive::Icosphere { subdivisions } => add_icosphere(&mut mesh, subdivisions),
+            Primitive::Cylinder { segments } => {
+                let segments = segments.max(3);
+                add_surface(
+                    &mut mesh,
+                    &steps(segments, 0., TAU),
+                    &[-1., 1.],
+                    &|angle, y| {
+                        let normal = glm::vec3(angle.sin(), 0., angle.cos());
+                        (
+                            glm::vec3(normal.x, y, normal.z),
+                            normal,
+                            glm::vec2(angle / TAU, (y + 1.) / 2.),
+                        )
+                    },
+                );
+                add_disk(&mut mesh, segments, 1., true);
+                add_disk(&mut mesh, segments, -1., false);
+            }
+            Primitive::Cone { segments } => {
+                let segments = segments.max(3);
+                add_surface(
+                    &mut mesh,
+                    &steps(segments, 0., TAU),
+                    &[0., 1.],
+                    &|angle, t| {
+                        let (sin, cos) = angle.sin_cos();
+                        // Sloping up by the height of 2 over the radius of 1
+                        let normal = glm::normalize(&glm::vec3(2. * sin, 1., 2. * cos));
+                        (
+                            glm::vec3(sin * (1. - t), 2. * t - 1., cos * (1. - t)),
+                            normal,
+                            glm::vec2(angle / TAU, t),
+                        )
+                    },
+                );
+                add_disk(&mut mesh, segments, -1., false);
+            }
+            Primitive::Torus {
+                segments,
+                sides,
+                radius,
+            } => add_surface(
+                &mut mesh,
+                &steps(segments.max(3), 0., TAU),
+                &steps(sides.max(3), 0., TAU),
+                &|around, tube| {
+                    let (sin, cos) = around.sin_cos();
+                    let normal = glm::vec3(tube.cos() * sin, tube.sin(), tube.cos() * cos);
+                    (
+                        glm::vec3(sin, 0., cos) + normal * radius,
+                        normal,
+                        glm::vec2(around / TAU, tube / TAU),
+                    )
+                },
+            ),
+            Primitive::Plane { subdivisions } => {
+                let coordinates = steps(subdivisions.max(1), -1., 1.);
+                add_surface(&mut mesh, &coordinates, &coordinates, &|x, z| {
+                    (
+                        glm::vec3(x, 0., -z),
+                        glm::vec3(0., 1., 0.),
+                        glm::vec2((x + 1.) / 2., (z + 1.) / 2.),
+                    )
+                });
+            }
+            Primitive::RoundedBox { radius, segments } => {
+                let radius = radius.clamp(0.01, 1.);
+                let inner = 1. - radius;
+                // Grid lines along the rounded edges, with one flat quad in between
+                let quarter = steps(segments.max(1), 0., PI / 2.);
+                let coordinates: Vec<f32> = quarter
+                    .iter()
+                    .map(|angle| -inner - radius * angle.cos())
+                    .chain(quarter.iter().map(|angle| inner + radius * angle.sin()))
+                    .collect();
+                add_box(&mut mesh, &coordinates, &|position, _| {
+                    // Push each point out from the closest point on the inner box
+                    let core = glm::clamp(&position, -inner, inner);
+                    let normal = glm::normalize(&(position - core));
+                    (core + normal * radius, normal)
+                });
+            }
+            Primitive::Sdf {
+                scene,
+                time,
+                resolution,
+            } => {
+                mesh = scene.mesh(time, resolution);
+                // Shrink the scene into the cube from -1 to 1
+                let (min, max) = scene.bounds();
+                let center = (min + max) / 2.;
+                let scale = 2. / (max - min).max();
+                for position in mesh.positions.chunks_mut(3) {
+                    for (axis, coordinate) in position.iter_mut().enumerate() {
+                        *coordinate = (*coordinate - center[axis]) * scale;
+                    }
+                }
+            }
+        }
+        if mesh.colors.is_empty() {
+            mesh.colors = [1.; 4].repeat(mesh.vertex_count());
+        }
+        mesh.generate_tangents();
+        mesh
+    }
+}
+
+/// Comma-separated floats, like `1,0.5,0`
+fn parse_floats(value: &str) -> Option<Vec<f32>> {
+    value
+        .split(',')
+        .map(|part| part.trim().parse::<f32>().ok())
+        .collect()
+}
+
+/// Add the primitives listed in a file below the given parent, returning the node holding them.
+/// There is one per line, followed by optional parameter=value pairs, where `position`,
+/// `rotation` (in degrees), `scale` and `color` place and paint it and the rest shape it.
+pub unsafe fn load_primitives(
+    gl: &glow::Context,
+    scene_graph: &mut SceneGraph,
+    parent: usize,
+    path: &str,
+) -> usize {
+    let description = std::fs::read_to_string(Path::new(path))
+        .unwrap_or_else(|_| panic!("No primitives at {}", path));
+    let root = scene_graph.add_child(parent, Node::new(NodeType::Root));
+    for (line_number, line) in description.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap();
+        let mut primitive = Primitive::new(name)
+            .unwrap_or_else(|| panic!("{}:{}: Unknown primitive {}", path, line_number + 1, name));
+        let mut node = Node::new(NodeType::Geometry);
+        let mut color = None;
+        for word in words {
+            let (key, value) = word.split_once('=').unwrap_or_else(|| {
+                panic!("{}:{}: Expected parameter=value", path, line_number + 1)
+            });
+            let values = parse_floats(value).unwrap_or_else(|| {
+                panic!("{}:{}: Invalid value for {}", path, line_number + 1, key)
+            });
+            let vector = || match values[..] {
+                [x, y, z] => glm::vec3(x, y, z),
+                [s] => glm::vec3(s, s, s),
+                _ => panic!(
+                    "{}:{}: Expected 1 or 3 values for {}",
+                    path,
+                    line_number + 1,
+                    key
+                ),
+            };
+            match key {
+                "position" => node.position = vector(),
+                "rotation" => node.rotation = vector() * PI / 180.,
+                "scale" => node.scale = vector(),
+                "color" => {
+                    let rgb = vector();
+                    color = Some(glm::vec4(rgb.x, rgb.y, rgb.z, 1.));
+                }
+                _ => {
+                    if values.len() != 1 || !primitive.set_parameter(key, values[0]) {
+                        panic!(
+                            "{}:{}: {} has no parameter {}",
+                            path,
+                            line_number + 1,
+                            name,
+                            key
+                        );
+                    }
+                }
+            }
+        }
+        let mut mesh = primitive.mesh();
+        if let Some(color) = color {
+            mesh.colors = color.as_slice().repeat(mesh.vertex_count());
+        }
+        node.vao = Some(VAO::from_mesh(gl, &mesh, 32.));
+        node.mesh = Some(Rc::new(mesh));
+        scene_graph.add_child(root, node);
+    }
+    root
+}
@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use super::graph::SceneGraph;
+
+/// How the reflections of the screens are kept up to date
+#[derive(Clone, Copy)]
+pub struct ProbeSettings {
+    /// Size of each cubemap face
+    pub cubemap_resolution: i32,
+    /// Size of the planar reflection textures
+    /// (these depend on the viewer, so they are rendered every frame instead)
+    pub planar_resolution: i32,
+    /// Budget of cubemap faces rendered per frame
+    pub renders_per_frame: usize,
+    /// Keep cycling through the probes even when nothing moves,
+    /// so animated screen contents show up in the reflections
+    pub continuous: bool,
+}
+
+impl Default for ProbeSettings {
+    fn default() -> ProbeSettings {
+        ProbeSettings {
+            cubemap_resolution: 512,
+            planar_resolution: 1024,
+            renders_per_frame: 6,
+            continuous: true,
+        }
+    }
+}
+
+/// One face of the cubemap of one screen, as (node, face)
+type CubemapFace = (usize, usize);
+
+/// Spreads the rendering of cubemap reflections across frames,
+/// queueing every probe again whenever something in the scene has moved
+pub struct ReflectionProbes {
+    pub settings: ProbeSettings,
+    queue: VecDeque<CubemapFace>,
+    /// Model matrices as of the last check, to notice when the scene changes
+    last_transforms: Vec<glm::Mat4>,
+}
+
+impl ReflectionProbes {
+    pub fn new(settings: ProbeSettings) -> ReflectionProbes {
+        ReflectionProbes {
+            settings,
+            queue: VecDeque::new(),
+            last_transforms: vec![],
+        }
+    }
+
+    /// Put every cubemap face at the back of the queue, unless it is already waiting there
+    fn enqueue_all(&mut self, scene_graph: &SceneGraph) {
+        for &node_index in scene_graph.cameras.iter() {
+            if scene_graph.nodes[node_index].cubemap_texture.is_none() {
+                continue;
+            }
+            for face in 0..6 {
+                if !self.queue.contains(&(node_index, face)) {
+                    self.queue.push_back((node_index, face));
+                }
+            }
+        }
+    }
+
+    /// Render as many queued reflections as the budget allows.
+    /// Should happen after the scene graph has been updated for the frame.
+    pub unsafe fn update(&mut self, gl: &glow::Context, scene_graph: &SceneGraph) {
+        let transforms: Vec<glm::Mat4> = scene_graph
+            .nodes
+            .iter()
+            .map(|node| node.model_matrix)
+            .collect();
+        if transforms != self.last_transforms {
+            self.enqueue_all(scene_graph);
+            self.last_transforms = transforms;
+        } else if self.settings.continuous && self.queue.is_empty() {
+            self.enqueue_all(scene_graph);
+        }
+
+        for _ in 0..self.settings.renders_per_frame {
+            match self.queue.pop_front() {
+                Some((node_index, face)) => scene_graph.render_cubemap_face(gl, node_index, face),
+                None => break,
+            }
+        }
+    }
+}
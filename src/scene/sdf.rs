@@ -0,0 +1,259 @@
+use super::mesh::Mesh;
+
+// Signed distance functions as written in the screen shaders, so their scenes can be meshed
+
+pub fn sphere(point: &glm::Vec3, center: &glm::Vec3, radius: f32) -> f32 {
+    glm::distance(point, center) - radius
+}
+
+/// SDF from Inigo Quilez
+pub fn capsule(point: &glm::Vec3, a: &glm::Vec3, b: &glm::Vec3, radius: f32) -> f32 {
+    let (pa, ba) = (point - a, b - a);
+    let h = (glm::dot(&pa, &ba) / glm::dot(&ba, &ba)).clamp(0., 1.);
+    glm::length(&(pa - ba * h)) - radius
+}
+
+/// Polynomial smooth minimum by Inigo Quilez, as in shadow.frag
+pub fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    let h = (0.5 + 0.5 * (a - b) / k).clamp(0., 1.);
+    a + (b - a) * h - k * h * (1. - h)
+}
+
+/// Cubic smooth minimum by Inigo Quilez, as in smooth.frag
+pub fn smooth_min_cubic(a: f32, b: f32, k: f32) -> f32 {
+    let h = (k - (a - b).abs()).max(0.) / k;
+    a.min(b) - h * h * h * k * (1. / 6.)
+}
+
+/// Smoothly cut the first shape out of the second, by Inigo Quilez
+pub fn smooth_diff(d1: f32, d2: f32, k: f32) -> f32 {
+    let h = (0.5 - 0.5 * (d2 + d1) / k).clamp(0., 1.);
+    d2 + (-d1 - d2) * h + k * h * (1. - h)
+}
+
+/// Box centered between min and max
+pub fn aligned_box(point: &glm::Vec3, min: &glm::Vec3, max: &glm::Vec3) -> f32 {
+    let center = (min + max) / 2.;
+    let q = glm::abs(&(point - center)) - (max - min) / 2.;
+    glm::length(&glm::max(&q, 0.)) + q.max().min(0.)
+}
+
+/// The scenes of the ray-marched screen shaders, at some point in their animation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SdfScene {
+    /// Smoothed bulbs from smooth.frag
+    Smooth,
+    /// Gyroid inside a sphere from gyroid.frag
+    Gyroid,
+    /// Blob with a face from shadow.frag
+    Shadow,
+}
+
+impl SdfScene {
+    /// Same as `distance_from_everything` in the shader
+    pub fn distance(&self, point: &glm::Vec3, time: f32) -> f32 {
+        match self {
+            SdfScene::Smooth => {
+                let k = 0.3;
+                let mut d = sphere(point, &glm::vec3(time.sin(), 0., 0.), 0.8);
+                for (center, radius) in [
+                    (glm::vec3(time.cos(), 0.8, -0.5), 0.6),
+                    (glm::vec3((1.1 * time + 0.5).sin(), -0.8, -0.3), 0.6),
+                    (glm::vec3(1.2, 0., 0.), 1.),
+                    (glm::vec3(-1.2, 0., 0.), 1.),
+                    (glm::vec3(0., 1., 0.), 1.),
+                ] {
+                    d = smooth_min_cubic(d, sphere(point, &center, radius), k);
+                }
+                d
+            }
+            SdfScene::Gyroid => {
+                let s = sphere(point, &glm::vec3(0., 0., 2.), 3.);
+                let thickness = 0.1;
+                let a = 0.5 * (time.cos() + 2.);
+                let b = 0.7 * (time.sin() + 2.);
+                let sines = (point * a).map(f32::sin);
+                let cosines = (glm::vec3(point.z, point.x, point.y) * b).map(f32::cos);
+                let d = glm::dot(&sines, &cosines).abs() - thickness;
+                (d * 0.2).max(s)
+            }
+            SdfScene::Shadow => {
+                let mut d = smooth_min(
+                    point.y,
+                    capsule(point, &glm::zero(), &glm::vec3(0., 2., 0.), 1.),
+                    1.2,
+                );
+                let mut eye = glm::vec3(0.3, 1.8, -1.);
+                d = smooth_diff(sphere(point, &eye, 0.15), d, 0.05);
+                d = smooth_diff(
+                    sphere(point, &glm::vec3(-eye.x, eye.y, eye.z), 0.15),
+                    d,
+                    0.05,
+                );
+                eye.y -= 0.4;
+                eye.x -= 0.1;
+                let mouth = capsule(point, &eye, &glm::vec3(-eye.x, eye.y, eye.z), 0.15);
+                smooth_diff(mouth, d, 0.05)
+            }
+        }
+    }
+
+    /// Box around the interesting part of the scene, as (min, max).
+    /// The floor of the shadow scene goes on forever, so it is cut off.
+    pub fn bounds(&self) -> (glm::Vec3, glm::Vec3) {
+        match self {
+            SdfScene::Smooth => (glm::vec3(-2.4, -1.6, -1.3), glm::vec3(2.4, 2.2, 1.3)),
+            SdfScene::Gyroid => (glm::vec3(-3.1, -3.1, -1.1), glm::vec3(3.1, 3.1, 5.1)),
+            SdfScene::Shadow => (glm::vec3(-2.5, -0.3, -2.5), glm::vec3(2.5, 3.2, 2.5)),
+        }
+    }
+
+    /// Surface color, as it is before lighting in the shader
+    pub fn color(&self, point: &glm::Vec3, normal: &glm::Vec3) -> glm::Vec4 {
+        match self {
+            SdfScene::Smooth => glm::vec4(1., (point.y + 0.9).cos(), point.x.sin(), 1.),
+            SdfScene::Gyroid => glm::vec4(0.76471, 0.78039, 0.78039, 1.),
+            SdfScene::Shadow => {
+                // Values in the range [0.6, 1.0], swizzled like the shader does it
+                let color = normal * 0.2 + glm::vec3(0.8, 0.8, 0.8);
+                glm::vec4(color.x, color.z, color.y, 1.)
+            }
+        }
+    }
+
+    /// Mesh the scene at the given time, with about `resolution` cells along its longest side
+    pub fn mesh(&self, time: f32, resolution: u32) -> Mesh {
+        let (min, max) = self.bounds();
+        let distance = |point: &glm::Vec3| self.distance(point, time);
+        let mut mesh = mesh_sdf(&distance, &min, &max, resolution);
+        mesh.colors = mesh
+            .positions
+            .chunks(3)
+            .zip(mesh.normals.chunks(3))
+            .flat_map(|(position, normal)| {
+                let color = self.color(&glm::make_vec3(position), &glm::make_vec3(normal));
+                [color.x, color.y, color.z, color.w]
+            })
+            .collect();
+        mesh
+    }
+}
+
+/// Turn the inside of a signed distance function into a closed mesh by dual contouring,
+/// with a vertex in every cell of a grid that the surface passes through,
+/// placed at the average of where the surface crosses the edges of the cell.
+/// The shape is cut off at the bounds, and has white vertex colors and normals from the gradient.
+pub fn mesh_sdf(
+    sdf: &dyn Fn(&glm::Vec3) -> f32,
+    min: &glm::Vec3,
+    max: &glm::Vec3,
+    resolution: u32,
+) -> Mesh {
+    let cell_size = (max - min).max() / resolution.max(1) as f32;
+    let counts = ((max - min) / cell_size).map(|cells| cells.ceil() as usize + 1);
+    let [nx, ny, nz] = [counts.x, counts.y, counts.z];
+    let grid_point =
+        |x: usize, y: usize, z: usize| min + glm::vec3(x as f32, y as f32, z as f32) * cell_size;
+    // Cut off half a cell inside the outermost grid points, so the mesh is closed there
+    let inset = glm::vec3(cell_size, cell_size, cell_size) / 2.;
+    let (inner_min, inner_max) = (min + inset, grid_point(nx - 1, ny - 1, nz - 1) - inset);
+    let bounded = |point: &glm::Vec3| sdf(point).max(aligned_box(point, &inner_min, &inner_max));
+
+    let index = |x: usize, y: usize, z: usize| (z * ny + y) * nx + x;
+    let mut values = vec![0.; nx * ny * nz];
+    for z in 0..nz {
+        for y in 0..ny {
+            for x in 0..nx {
+                values[index(x, y, z)] = bounded(&grid_point(x, y, z));
+            }
+        }
+    }
+
+    // One vertex per cell crossed by the surface, where the cell is named by its lowest corner
+    let mut mesh = Mesh::default();
+    let mut cell_vertices = vec![u32::MAX; nx * ny * nz];
+    let corners: Vec<[usize; 3]> = (0..8).map(|i| [i & 1, (i >> 1) & 1, i >> 2]).collect();
+    for z in 0..nz - 1 {
+        for y in 0..ny - 1 {
+            for x in 0..nx - 1 {
+                let value = |[dx, dy, dz]: [usize; 3]| values[index(x + dx, y + dy, z + dz)];
+                let mut crossings = glm::Vec3::zeros();
+                let mut count = 0;
+                for (i, &a) in corners.iter().enumerate() {
+                    for &b in corners[i + 1..].iter() {
+                        // Only the edges, where the corners differ along one axis
+                        let differences = (0..3).filter(|&axis| a[axis] != b[axis]).count();
+                        let (value_a, value_b) = (value(a), value(b));
+                        if differences != 1 || (value_a < 0.) == (value_b < 0.) {
+                            continue;
+                        }
+                        let t = value_a / (value_a - value_b);
+                        let corner = |[dx, dy, dz]: [usize; 3]| grid_point(x + dx, y + dy, z + dz);
+                        crossings += glm::lerp(&corner(a), &corner(b), t);
+                        count += 1;
+                    }
+                }
+                if count == 0 {
+                    continue;
+                }
+                let position = crossings / count as f32;
+                // Gradient by central differences, like estimate_normal in the shaders
+                let e = cell_size / 2.;
+                let gradient = glm::vec3(
+                    bounded(&(position + glm::vec3(e, 0., 0.)))
+                        - bounded(&(position - glm::vec3(e, 0., 0.))),
+                    bounded(&(position + glm::vec3(0., e, 0.)))
+                        - bounded(&(position - glm::vec3(0., e, 0.))),
+                    bounded(&(position + glm::vec3(0., 0., e)))
+                        - bounded(&(position - glm::vec3(0., 0., e))),
+                );
+                let normal = if glm::length2(&gradient) > 0. {
+                    glm::normalize(&gradient)
+                } else {
+                    glm::vec3(0., 1., 0.)
+                };
+                cell_vertices[index(x, y, z)] = mesh.vertex_count() as u32;
+                mesh.positions.extend_from_slice(position.as_slice());
+                mesh.normals.extend_from_slice(normal.as_slice());
+            }
+        }
+    }
+
+    // A quad around every grid edge the surface crosses, joining the four cells sharing it.
+    // The other two axes are taken in cyclic order, so the quad faces along the edge.
+    for z in 0..nz {
+        for y in 0..ny {
+            for x in 0..nx {
+                let start = [x, y, z];
+                let inside = values[index(x, y, z)] < 0.;
+                for axis in 0..3 {
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let mut end = start;
+                    end[axis] += 1;
+                    if end[axis] >= counts[axis] || start[u] == 0 || start[v] == 0 {
+                        continue;
+                    }
+                    if inside == (values[index(end[0], end[1], end[2])] < 0.) {
+                        continue;
+                    }
+                    let cell = |du: usize, dv: usize| {
+                        let mut cell = start;
+                        cell[u] -= 1 - du;
+                        cell[v] -= 1 - dv;
+                        cell_vertices[index(cell[0], cell[1], cell[2])]
+                    };
+                    let mut quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+                    // Facing out of the shape
+                    if !inside {
+                        quad.reverse();
+                    }
+                    mesh.indices
+                        .extend_from_slice(&[quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+                }
+            }
+        }
+    }
+
+    mesh.colors = [1.; 4].repeat(mesh.vertex_count());
+    mesh
+}
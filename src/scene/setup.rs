@@ -1,17 +1,52 @@
 use std::f32::consts::PI;
+use std::path::Path;
+use std::rc::Rc;
 use std::time::Instant;
 
 use crate::shader;
 
+use super::cache::{load_obj_cached, CachedMesh};
+use super::cluster::LightClusters;
+use super::crt::{CrtFilter, CrtScreen, CrtSettings, PhosphorMask};
 use super::graph::{Node, NodeType, SceneGraph};
-use super::texture::{CubemapTexture, FrameBufferTexture, ImageTexture};
+use super::import::load_gltf;
+use super::instancing::InstanceBuffer;
+use super::light::{Light, LightBuffer, LightKind};
+use super::lod::LodChain;
+use super::material::{Material, MaterialLoader};
+use super::mesh::Mesh;
+use super::primitives::load_primitives;
+use super::probes::ProbeSettings;
+use super::texture::{CubemapTexture, FrameBufferTexture, ProxyVolume};
 use super::vao::{load_obj, VAO};
 
 const SIMPLE: bool = false;
+// Optional list of generated shapes to add to the room
+const PRIMITIVES: &str = "res/primitives.txt";
 
-pub fn create_scene(gl: &glow::Context) -> SceneGraph {
+/// Upload one model of an OBJ file, keeping the mesh for nodes to share
+unsafe fn upload_obj(
+    gl: &glow::Context,
+    model: &tobj::Model,
+    materials: &[tobj::Material],
+) -> (VAO, Rc<Mesh>) {
+    let id = model
+        .mesh
+        .material_id
+        .expect("No material in texture; abort!");
+    let mesh = Mesh::from_obj(model, materials);
+    (
+        VAO::from_mesh(gl, &mesh, materials[id].shininess),
+        Rc::new(mesh),
+    )
+}
+
+pub fn create_scene(gl: &glow::Context, probe_settings: &ProbeSettings) -> SceneGraph {
     // Create scene graph
     let mut scene_graph = SceneGraph::new();
+    scene_graph.light_buffer = unsafe { Some(LightBuffer::new(gl)) };
+    scene_graph.light_clusters = unsafe { Some(LightClusters::new(gl)) };
+    scene_graph.instance_buffer = unsafe { Some(InstanceBuffer::new(gl)) };
 
     ///////// Room /////////
 
@@ -21,26 +56,18 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     let room_size = 30.;
     let mut floor_node = Node::new(NodeType::Geometry);
     floor_node.vao = Some(square_vao);
+    floor_node.mesh = Some(Rc::new(Mesh::square()));
     floor_node.scale = glm::vec3(room_size, room_size, room_size);
     floor_node.rotation.x = -PI / 2.;
     if !SIMPLE {
         floor_node.texture = unsafe {
-            Some(ImageTexture::new(
-                gl,
-                "res/textures/weathered_brown_planks_diff_4k.jpg",
-            ))
+            Some(scene_graph.load_texture(gl, "res/textures/weathered_brown_planks_diff_4k.jpg"))
         };
         floor_node.normal_map = unsafe {
-            Some(ImageTexture::new(
-                gl,
-                "res/textures/weathered_brown_planks_nor_gl_4k.jpg",
-            ))
+            Some(scene_graph.load_texture(gl, "res/textures/weathered_brown_planks_nor_gl_4k.jpg"))
         };
         floor_node.roughness_map = unsafe {
-            Some(ImageTexture::new(
-                gl,
-                "res/textures/weathered_brown_planks_rough_4k.jpg",
-            ))
+            Some(scene_graph.load_texture(gl, "res/textures/weathered_brown_planks_rough_4k.jpg"))
         };
     }
     scene_graph.add_child(0, floor_node);
@@ -52,8 +79,8 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     ///////// Screens /////////
 
     let (models, materials) = load_obj("res/models/crt.obj");
-    let crt_vao = unsafe { VAO::from_mesh(&gl, &models[0], &materials) };
-    let screen_vao = unsafe { VAO::from_mesh(&gl, &models[1], &materials) };
+    let (crt_vao, crt_mesh) = unsafe { upload_obj(gl, &models[0], &materials) };
+    let (screen_vao, screen_mesh) = unsafe { upload_obj(gl, &models[1], &materials) };
 
     let mut crt_root_node = Node::new(NodeType::Root);
     crt_root_node.position.y += 2.;
@@ -96,16 +123,39 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     {
         let mut crt_node = Node::new(NodeType::Geometry);
         crt_node.vao = Some(crt_vao);
+        crt_node.mesh = Some(crt_mesh.clone());
         crt_node.scale = glm::vec3(1.5, 1.5, 1.5);
         crt_node.rotation = rotation;
         crt_node.position = position;
         let crt_index = scene_graph.add_child(crt_root, crt_node);
         let mut screen_node = Node::new(NodeType::Screen);
         screen_node.vao = Some(screen_vao);
-        screen_node.cubemap_texture = unsafe { Some(CubemapTexture::new(&gl, 2000)) };
-        screen_node.reflection_map = unsafe { Some(FrameBufferTexture::new(&gl, 2000, 2000)) };
+        screen_node.mesh = Some(screen_mesh.clone());
+        let mut cubemap = unsafe { CubemapTexture::new(&gl, probe_settings.cubemap_resolution) };
+        // Reflect the room as a box around the floor, instead of infinitely far away
+        cubemap.proxy = ProxyVolume::Box {
+            min: glm::vec3(-room_size / 2., 0., -room_size / 2.),
+            max: glm::vec3(room_size / 2., 12., room_size / 2.),
+        };
+        screen_node.cubemap_texture = Some(cubemap);
+        screen_node.reflection_map = unsafe {
+            Some(FrameBufferTexture::new(
+                &gl,
+                probe_settings.planar_resolution,
+                probe_settings.planar_resolution,
+            ))
+        };
         crts.push(scene_graph.add_child(crt_index, screen_node));
     }
+    // The upper ring mostly reflects the monitors across from it rather than the walls,
+    // so its screens assume a sphere about as wide as the ring instead of the room
+    for &screen in &crts[8..] {
+        if let Some(cubemap) = scene_graph.get_node(screen).cubemap_texture.as_mut() {
+            cubemap.proxy = ProxyVolume::Sphere {
+                radius: 2. * radius2,
+            };
+        }
+    }
 
     let mut shaders: Vec<(glow::NativeProgram, usize)> = vec![];
     for (crt_index, shader_source) in vec![
@@ -124,15 +174,46 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     ] {
         let shader = unsafe { shader::Shader::new(&gl, "res/shaders/screen.vert", shader_source) };
         shaders.push((shader.program, crts[crt_index]));
+        // Vary the phosphor layout a little between monitors
+        let settings = CrtSettings {
+            mask: match crt_index % 3 {
+                0 => PhosphorMask::ApertureGrille,
+                1 => PhosphorMask::ShadowMask,
+                _ => PhosphorMask::None,
+            },
+            ..Default::default()
+        };
+        scene_graph.get_node(crts[crt_index]).crt = unsafe { Some(CrtScreen::new(gl, settings)) };
+
+        // Area light just in front of the glass, colored by the screen contents every frame
+        let mut screen_light_node = Node::new(NodeType::Light);
+        screen_light_node.position.z = -0.05;
+        screen_light_node.light = Some(Light {
+            kind: LightKind::Area {
+                width: 2.4,
+                height: 2.4,
+            },
+            color: glm::zero(),
+            intensity: 1.,
+            range: 12.,
+        });
+        let screen_light = scene_graph.add_child(crts[crt_index], screen_light_node);
+        scene_graph
+            .screen_lights
+            .push((crts[crt_index], screen_light));
     }
     scene_graph.screen_shaders = shaders;
+    scene_graph.crt_filter = unsafe { Some(CrtFilter::new(gl)) };
 
     ///////// Miscellaneous interior /////////
 
     let (goose_models, goose_materials) = load_obj("res/models/goose.obj");
-    let goose_body_vao = unsafe { VAO::from_mesh(&gl, &goose_models[0], &goose_materials) };
-    let goose_beak_vao = unsafe { VAO::from_mesh(&gl, &goose_models[1], &goose_materials) };
-    let goose_eyes_vao = unsafe { VAO::from_mesh(&gl, &goose_models[2], &goose_materials) };
+    let (goose_body_vao, goose_body_mesh) =
+        unsafe { upload_obj(gl, &goose_models[0], &goose_materials) };
+    let (goose_beak_vao, goose_beak_mesh) =
+        unsafe { upload_obj(gl, &goose_models[1], &goose_materials) };
+    let (goose_eyes_vao, goose_eyes_mesh) =
+        unsafe { upload_obj(gl, &goose_models[2], &goose_materials) };
 
     let mut goose_node = Node::new(NodeType::Geometry);
     let mut goose_beak_node = Node::new(NodeType::Geometry);
@@ -140,6 +221,9 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     goose_node.vao = Some(goose_body_vao);
     goose_beak_node.vao = Some(goose_beak_vao);
     goose_eyes_node.vao = Some(goose_eyes_vao);
+    goose_node.mesh = Some(goose_body_mesh);
+    goose_beak_node.mesh = Some(goose_beak_mesh);
+    goose_eyes_node.mesh = Some(goose_eyes_mesh);
     goose_node.rotation.y = PI;
 
     let goose_root = scene_graph.add_child(0, goose_node);
@@ -147,12 +231,10 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
     scene_graph.add_child(goose_root, goose_eyes_node);
 
     let (cube_models, cube_materials) = load_obj("res/models/cube.obj");
-    let cube_vaos: Vec<VAO> = unsafe {
-        cube_models
-            .iter()
-            .map(|model| VAO::from_mesh(&gl, &model, &cube_materials))
-            .collect()
-    };
+    let cube_vaos: Vec<(VAO, Rc<Mesh>)> = cube_models
+        .iter()
+        .map(|model| unsafe { upload_obj(gl, model, &cube_materials) })
+        .collect();
 
     if SIMPLE {
         for (i, (x, y)) in vec![
@@ -169,7 +251,8 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
         .enumerate()
         {
             let mut chair_node = Node::new(NodeType::Geometry);
-            chair_node.vao = Some(cube_vaos[i]);
+            chair_node.vao = Some(cube_vaos[i].0);
+            chair_node.mesh = Some(cube_vaos[i].1.clone());
             chair_node.scale = glm::vec3(4., 4., 4.);
             chair_node.position.x = x * 4.;
             chair_node.position.y = 2.;
@@ -180,10 +263,11 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
 
     if !SIMPLE {
         let before = Instant::now();
-        for (objname, name, position, rotation, scale) in vec![
+        // Shared between all the models, since some of them use the same textures
+        let mut material_loader = MaterialLoader::new(Path::new("res/models"));
+        for (objname, position, rotation, scale) in vec![
             // +Z: Sofa
             (
-                "sofa_03",
                 "sofa_03",
                 glm::vec3(0., 0., 12.),
                 glm::vec3(0., PI, 0.),
@@ -192,20 +276,17 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
             // +X: Cabinet and table
             (
                 "vintage_cabinet_01",
-                "vintage_cabinet_01_a",
                 glm::vec3(17., 0., 0.),
                 glm::vec3(0., -PI / 2., 0.),
                 glm::vec3(4., 4., 4.),
             ),
             (
-                "round_wooden_table_01",
                 "round_wooden_table_01",
                 glm::vec3(12., 0., 0.),
                 glm::vec3(0., -PI / 2., 0.),
                 glm::vec3(4., 4., 4.),
             ),
             (
-                "modern_ceiling_lamp_01",
                 "modern_ceiling_lamp_01",
                 glm::vec3(12., 8., 0.),
                 glm::vec3(0., -PI / 2., 0.),
@@ -213,29 +294,23 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
             ),
             // -Z: Drawer with stuff on top
             (
-                "vintage_wooden_drawer_01",
                 "vintage_wooden_drawer_01",
                 glm::vec3(0., 0., -12.),
                 glm::vec3(0., 0., 0.),
                 glm::vec3(8., 8., 8.),
             ),
             (
-                "CashRegister_01",
                 "CashRegister_01",
                 glm::vec3(2., 4.25, -12.2),
                 glm::vec3(0., 0., 0.),
                 glm::vec3(4., 4., 4.),
             ),
         ] {
-            let (models, materials) = load_obj(&format!("res/models/{}_2k.obj", objname));
-            let texture =
-                unsafe { ImageTexture::new(gl, &format!("res/textures/{}_diff_2k.jpg", name)) };
-            let normal_map =
-                unsafe { ImageTexture::new(gl, &format!("res/textures/{}_nor_gl_2k.jpg", name)) };
-            let roughness_map =
-                unsafe { ImageTexture::new(gl, &format!("res/textures/{}_rough_2k.jpg", name)) };
-            //let opacity_map =
-            //unsafe { ImageTexture::new(gl, &format!("res/textures/{}_opacity_2k.jpg", name)) };
+            let (meshes, materials) = load_obj_cached(&format!("res/models/{}_2k.obj", objname));
+            let node_materials: Vec<Material> = materials
+                .iter()
+                .map(|material| unsafe { material_loader.load(gl, material) })
+                .collect();
 
             let mut root_node = Node::new(NodeType::Root);
             root_node.position = position;
@@ -243,32 +318,124 @@ pub fn create_scene(gl: &glow::Context) -> SceneGraph {
             root_node.scale = scale;
             let root = scene_graph.add_child(0, root_node);
 
-            for model in models {
+            for CachedMesh {
+                mesh,
+                material_id: id,
+                levels,
+            } in meshes
+            {
                 let mut node = Node::new(NodeType::Geometry);
-                node.vao = unsafe { Some(VAO::from_mesh(&gl, &model, &materials)) };
-                node.texture = Some(texture);
-                node.normal_map = Some(normal_map);
-                node.roughness_map = Some(roughness_map);
-                //node.opacity_map = Some(opacity_map);
+                let shininess = materials[id].shininess;
+                node.vao = unsafe { Some(VAO::from_mesh(gl, &mesh, shininess)) };
+                node.lod = unsafe { Some(LodChain::from_levels(gl, &mesh, &levels, shininess)) };
+                // Kept on the CPU side like the imported models
+                node.mesh = Some(Rc::new(mesh));
+                node_materials[id].apply(&mut node);
                 scene_graph.add_child(root, node);
             }
         }
+        scene_graph.texture_files.extend(material_loader.files());
+        // glTF files bring their own materials and placement, so they are loaded as they are
+        let mut gltf_paths: Vec<_> = std::fs::read_dir("res/models")
+            .expect("Could not list models")
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|extension| extension.to_str()),
+                    Some("gltf" | "glb")
+                )
+            })
+            .collect();
+        gltf_paths.sort();
+        for path in gltf_paths {
+            unsafe {
+                load_gltf(gl, &mut scene_graph, 0, &path.to_string_lossy());
+            }
+        }
         println!(
             "Loading models took {} seconds",
             Instant::now().duration_since(before).as_secs_f32(),
         );
     }
+    if Path::new(PRIMITIVES).exists() {
+        unsafe {
+            load_primitives(gl, &mut scene_graph, 0, PRIMITIVES);
+        }
+    }
 
     for (position, color) in vec![
         //(glm::vec3(10., 3., 0.), glm::vec3(0.4, 0.4, 0.4)),
         (glm::vec3(0., 6., 6.), glm::vec3(0.4, 0.4, 0.4)),
         (glm::vec3(-10., 4., 10.), glm::vec3(0.6, 0.4, 0.4)),
         //(glm::vec3(0., 2., -4.), glm::vec3(0.6, 0.6, 0.6)),
-        (glm::vec3(12., 8., 0.), glm::vec3(0.6, 0.6, 0.6)), // at lamp
     ] {
         let mut light_node = Node::new(NodeType::Light);
         light_node.position = position.clone();
-        light_node.emission_color = color;
+        light_node.light = Some(Light {
+            color,
+            intensity: 40.,
+            ..Default::default()
+        });
+        scene_graph.add_child(0, light_node);
+    }
+
+    // Ceiling lamp shining down on the table
+    let mut lamp_node = Node::new(NodeType::Light);
+    lamp_node.position = glm::vec3(12., 7.5, 0.);
+    lamp_node.rotation.x = -PI / 2.;
+    lamp_node.light = Some(Light {
+        kind: LightKind::Spot {
+            inner_angle: PI / 6.,
+            outer_angle: PI / 3.,
+        },
+        color: glm::vec3(1., 0.9, 0.75),
+        intensity: 50.,
+        range: 20.,
+    });
+    scene_graph.add_child(0, lamp_node);
+
+    // Faint moonlight from the side
+    let mut moon_node = Node::new(NodeType::Light);
+    moon_node.rotation = glm::vec3(-PI / 5., PI / 3., 0.);
+    moon_node.light = Some(Light {
+        kind: LightKind::Directional,
+        color: glm::vec3(0.5, 0.6, 1.),
+        intensity: 0.08,
+        ..Default::default()
+    });
+    scene_graph.add_child(0, moon_node);
+
+    // Softbox-like panel over the drawer
+    let mut panel_node = Node::new(NodeType::Light);
+    panel_node.position = glm::vec3(0., 9., -11.);
+    panel_node.rotation.x = -PI / 2.;
+    panel_node.light = Some(Light {
+        kind: LightKind::Area {
+            width: 4.,
+            height: 1.5,
+        },
+        color: glm::vec3(0.9, 0.95, 1.),
+        intensity: 1.5,
+        range: 25.,
+    });
+    scene_graph.add_child(0, panel_node);
+
+    // LED strip on the floor around the screens, cycling through the hues
+    let led_count = 128;
+    for i in 0..led_count {
+        let angle = i as f32 / led_count as f32 * 2. * PI;
+        let mut light_node = Node::new(NodeType::Light);
+        light_node.position = glm::vec3(6.5 * angle.cos(), 0.1, 6.5 * angle.sin());
+        light_node.light = Some(Light {
+            color: glm::vec3(
+                0.5 + 0.5 * angle.cos(),
+                0.5 + 0.5 * (angle + 2. * PI / 3.).cos(),
+                0.5 + 0.5 * (angle + 4. * PI / 3.).cos(),
+            ),
+            intensity: 0.3,
+            range: 1.5,
+            ..Default::default()
+        });
         scene_graph.add_child(0, light_node);
     }
 
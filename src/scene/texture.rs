@@ -21,11 +21,58 @@ pub struct PBRTextures {
     pub roughness: NativeTexture,
 }
 
+/// Shape the surroundings of a cubemap are assumed to have,
+/// so reflections can be corrected for where they were captured from
+#[derive(Clone, Copy)]
+pub enum ProxyVolume {
+    /// Everything is infinitely far away
+    None,
+    /// Axis-aligned box in world space
+    Box { min: glm::Vec3, max: glm::Vec3 },
+    /// Sphere around the point the cubemap was captured from
+    Sphere { radius: f32 },
+}
+
 #[derive(Clone, Copy)]
 pub struct CubemapTexture {
     pub framebuffers: [NativeFramebuffer; 6],
     pub texture: NativeTexture,
     pub size: i32,
+    pub proxy: ProxyVolume,
+}
+
+/// Offscreen target with several samples per pixel,
+/// which must be resolved into a regular texture before it can be sampled
+#[derive(Clone, Copy)]
+pub struct MultisampleFramebuffer {
+    pub framebuffer: NativeFramebuffer,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Geometry buffer for deferred shading, one texture per surface property
+#[derive(Clone, Copy)]
+pub struct GBuffer {
+    pub framebuffer: NativeFramebuffer,
+    pub albedo_texture: NativeTexture,
+    pub normal_texture: NativeTexture,
+    /// Roughness, metalness and emission
+    pub material_texture: NativeTexture,
+    pub depth_texture: NativeTexture,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Targets for weighted blended order-independent transparency
+#[derive(Clone, Copy)]
+pub struct OitBuffer {
+    pub framebuffer: NativeFramebuffer,
+    /// Weighted sum of premultiplied colors, and of the weights in alpha
+    pub accumulation_texture: NativeTexture,
+    /// Product of one minus the opacities, i.e. how much of the background shows through
+    pub revealage_texture: NativeTexture,
+    pub width: i32,
+    pub height: i32,
 }
 
 #[derive(Clone, Copy)]
@@ -33,6 +80,7 @@ pub struct PostProcessingTexture {
     pub framebuffer: NativeFramebuffer,
     pub color_buffer_texture: NativeTexture,
     pub depth_buffer_texture: NativeTexture,
+    pub normal_buffer_texture: Option<NativeTexture>,
     pub width: i32,
     pub height: i32,
 }
@@ -142,7 +190,21 @@ impl ImageTexture {
             .expect(&format!("Could not open {}", filepath))
             .decode()
             .expect(&format!("Error processing image at {}", filepath));
+        ImageTexture::from_pixels(
+            gl,
+            image.width() as i32,
+            image.height() as i32,
+            image.to_rgba8().as_raw(),
+        )
+    }
 
+    /// Texture from RGBA pixels that are already in memory, like those embedded in glTF files
+    pub unsafe fn from_pixels(
+        gl: &glow::Context,
+        width: i32,
+        height: i32,
+        pixels: &[u8],
+    ) -> FrameBufferTexture {
         // Create texture
         let texture = gl.create_texture().expect("Could not create texture");
         gl.bind_texture(glow::TEXTURE_2D, Some(texture));
@@ -150,12 +212,12 @@ impl ImageTexture {
             glow::TEXTURE_2D,
             0,
             glow::RGBA as i32,
-            image.width() as i32,
-            image.height() as i32,
+            width,
+            height,
             0,
             glow::RGBA,
             glow::UNSIGNED_BYTE,
-            Some(image.to_rgba8().to_vec().as_slice()),
+            Some(pixels),
         );
         // Specify mipmap interpolation
         gl.tex_parameter_i32(
@@ -172,8 +234,9 @@ impl ImageTexture {
         FrameBufferTexture {
             framebuffer: None,
             texture,
-            width: 0, // irrelevant
-            height: 0,
+            // Needed for reading the pixels back when exporting
+            width,
+            height,
         }
     }
 }
@@ -312,12 +375,78 @@ impl CubemapTexture {
             ],
             texture,
             size,
+            proxy: ProxyVolume::None,
         }
     }
 }
 
 impl PostProcessingTexture {
     pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> PostProcessingTexture {
+        PostProcessingTexture::with_format(gl, width, height, glow::RGBA, glow::UNSIGNED_BYTE)
+    }
+
+    /// Floating-point color buffer, so bright things can exceed 1 until tonemapping
+    pub unsafe fn hdr(gl: &glow::Context, width: i32, height: i32) -> PostProcessingTexture {
+        PostProcessingTexture::with_format(gl, width, height, glow::RGBA16F, glow::FLOAT)
+    }
+
+    /// HDR buffer with a second color attachment for world-space normals
+    /// (written to location 1 by the fragment shader)
+    pub unsafe fn with_normals(
+        gl: &glow::Context,
+        width: i32,
+        height: i32,
+    ) -> PostProcessingTexture {
+        let mut texture = PostProcessingTexture::hdr(gl, width, height);
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(texture.framebuffer));
+
+        let normal_buffer_texture = gl.create_texture().expect("Could not create texture");
+        gl.bind_texture(glow::TEXTURE_2D, Some(normal_buffer_texture));
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA16F as i32,
+            width,
+            height,
+            0,
+            glow::RGBA,
+            glow::FLOAT,
+            None,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MAG_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_MIN_FILTER,
+            glow::NEAREST as i32,
+        );
+        gl.framebuffer_texture(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT1,
+            Some(normal_buffer_texture),
+            0,
+        );
+        gl.draw_buffers(&[glow::COLOR_ATTACHMENT0, glow::COLOR_ATTACHMENT1]);
+
+        if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
+            panic!("Framebuffer creation failed!");
+        }
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+        texture.normal_buffer_texture = Some(normal_buffer_texture);
+        texture
+    }
+
+    pub unsafe fn with_format(
+        gl: &glow::Context,
+        width: i32,
+        height: i32,
+        internal_format: u32,
+        data_type: u32,
+    ) -> PostProcessingTexture {
         let framebuffer = gl
             .create_framebuffer()
             .expect("Could not create framebuffer");
@@ -329,12 +458,12 @@ impl PostProcessingTexture {
         gl.tex_image_2d(
             glow::TEXTURE_2D,
             0,
-            glow::RGBA as i32,
+            internal_format as i32,
             width,
             height,
             0,
             glow::RGBA,
-            glow::UNSIGNED_BYTE,
+            data_type,
             None,
         );
         // Specify mipmap interpolation
@@ -348,6 +477,17 @@ impl PostProcessingTexture {
             glow::TEXTURE_MIN_FILTER,
             glow::LINEAR as i32,
         );
+        // Clamp so that filters reading outside the screen don't wrap around
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
 
         // Attach texture to framebuffer
         gl.framebuffer_texture(
@@ -414,6 +554,250 @@ impl PostProcessingTexture {
             framebuffer,
             color_buffer_texture,
             depth_buffer_texture,
+            normal_buffer_texture: None,
+            width,
+            height,
+        }
+    }
+}
+
+impl MultisampleFramebuffer {
+    pub unsafe fn new(
+        gl: &glow::Context,
+        width: i32,
+        height: i32,
+        samples: i32,
+        internal_format: u32,
+    ) -> MultisampleFramebuffer {
+        let framebuffer = gl
+            .create_framebuffer()
+            .expect("Could not create framebuffer");
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+
+        // Color buffer
+        let color_buffer = gl
+            .create_renderbuffer()
+            .expect("Could not create renderbuffer");
+        gl.bind_renderbuffer(glow::RENDERBUFFER, Some(color_buffer));
+        gl.renderbuffer_storage_multisample(
+            glow::RENDERBUFFER,
+            samples,
+            internal_format,
+            width,
+            height,
+        );
+        gl.framebuffer_renderbuffer(
+            glow::FRAMEBUFFER,
+            glow::COLOR_ATTACHMENT0,
+            glow::RENDERBUFFER,
+            Some(color_buffer),
+        );
+        gl.draw_buffer(glow::COLOR_ATTACHMENT0);
+
+        // Depth buffer, in the same format as the textures it is resolved into
+        let depth_buffer = gl
+            .create_renderbuffer()
+            .expect("Could not create renderbuffer");
+        gl.bind_renderbuffer(glow::RENDERBUFFER, Some(depth_buffer));
+        gl.renderbuffer_storage_multisample(
+            glow::RENDERBUFFER,
+            samples,
+            glow::DEPTH_COMPONENT24,
+            width,
+            height,
+        );
+        gl.framebuffer_renderbuffer(
+            glow::FRAMEBUFFER,
+            glow::DEPTH_ATTACHMENT,
+            glow::RENDERBUFFER,
+            Some(depth_buffer),
+        );
+
+        if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
+            panic!("Multisampled framebuffer creation failed!");
+        }
+
+        gl.bind_renderbuffer(glow::RENDERBUFFER, None);
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+        MultisampleFramebuffer {
+            framebuffer,
+            width,
+            height,
+        }
+    }
+
+    /// Average the samples of each pixel into the color and depth textures of the target
+    pub unsafe fn resolve(&self, gl: &glow::Context, target: &PostProcessingTexture) {
+        gl.bind_framebuffer(glow::READ_FRAMEBUFFER, Some(self.framebuffer));
+        gl.bind_framebuffer(glow::DRAW_FRAMEBUFFER, Some(target.framebuffer));
+        gl.blit_framebuffer(
+            0,
+            0,
+            self.width,
+            self.height,
+            0,
+            0,
+            target.width,
+            target.height,
+            glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT,
+            glow::NEAREST,
+        );
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+    }
+}
+
+/// Screen-sized texture without mipmaps, to be attached to a framebuffer
+unsafe fn create_attachment(
+    gl: &glow::Context,
+    width: i32,
+    height: i32,
+    internal_format: u32,
+    format: u32,
+    data_type: u32,
+) -> NativeTexture {
+    let texture = gl.create_texture().expect("Could not create texture");
+    gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+    gl.tex_image_2d(
+        glow::TEXTURE_2D,
+        0,
+        internal_format as i32,
+        width,
+        height,
+        0,
+        format,
+        data_type,
+        None,
+    );
+    gl.tex_parameter_i32(
+        glow::TEXTURE_2D,
+        glow::TEXTURE_MAG_FILTER,
+        glow::NEAREST as i32,
+    );
+    gl.tex_parameter_i32(
+        glow::TEXTURE_2D,
+        glow::TEXTURE_MIN_FILTER,
+        glow::NEAREST as i32,
+    );
+    gl.tex_parameter_i32(
+        glow::TEXTURE_2D,
+        glow::TEXTURE_WRAP_S,
+        glow::CLAMP_TO_EDGE as i32,
+    );
+    gl.tex_parameter_i32(
+        glow::TEXTURE_2D,
+        glow::TEXTURE_WRAP_T,
+        glow::CLAMP_TO_EDGE as i32,
+    );
+    texture
+}
+
+impl GBuffer {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> GBuffer {
+        let framebuffer = gl
+            .create_framebuffer()
+            .expect("Could not create framebuffer");
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+
+        let albedo_texture = create_attachment(
+            gl,
+            width,
+            height,
+            glow::RGBA8,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+        );
+        let normal_texture =
+            create_attachment(gl, width, height, glow::RGBA16F, glow::RGBA, glow::FLOAT);
+        let material_texture = create_attachment(
+            gl,
+            width,
+            height,
+            glow::RGBA8,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+        );
+        let depth_texture = create_attachment(
+            gl,
+            width,
+            height,
+            glow::DEPTH_COMPONENT24,
+            glow::DEPTH_COMPONENT,
+            glow::FLOAT,
+        );
+
+        for (attachment, texture) in [
+            (glow::COLOR_ATTACHMENT0, albedo_texture),
+            (glow::COLOR_ATTACHMENT1, normal_texture),
+            (glow::COLOR_ATTACHMENT2, material_texture),
+            (glow::DEPTH_ATTACHMENT, depth_texture),
+        ] {
+            gl.framebuffer_texture(glow::FRAMEBUFFER, attachment, Some(texture), 0);
+        }
+        gl.draw_buffers(&[
+            glow::COLOR_ATTACHMENT0,
+            glow::COLOR_ATTACHMENT1,
+            glow::COLOR_ATTACHMENT2,
+        ]);
+
+        if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
+            panic!("G-buffer creation failed!");
+        }
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+        GBuffer {
+            framebuffer,
+            albedo_texture,
+            normal_texture,
+            material_texture,
+            depth_texture,
+            width,
+            height,
+        }
+    }
+}
+
+impl OitBuffer {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> OitBuffer {
+        let framebuffer = gl
+            .create_framebuffer()
+            .expect("Could not create framebuffer");
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+
+        let accumulation_texture =
+            create_attachment(gl, width, height, glow::RGBA16F, glow::RGBA, glow::FLOAT);
+        let revealage_texture =
+            create_attachment(gl, width, height, glow::R16F, glow::RED, glow::FLOAT);
+        // Holds a copy of the opaque depth, so transparent surfaces behind walls are hidden
+        let depth_texture = create_attachment(
+            gl,
+            width,
+            height,
+            glow::DEPTH_COMPONENT24,
+            glow::DEPTH_COMPONENT,
+            glow::FLOAT,
+        );
+
+        for (attachment, texture) in [
+            (glow::COLOR_ATTACHMENT0, accumulation_texture),
+            (glow::COLOR_ATTACHMENT1, revealage_texture),
+            (glow::DEPTH_ATTACHMENT, depth_texture),
+        ] {
+            gl.framebuffer_texture(glow::FRAMEBUFFER, attachment, Some(texture), 0);
+        }
+        gl.draw_buffers(&[glow::COLOR_ATTACHMENT0, glow::COLOR_ATTACHMENT1]);
+
+        if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
+            panic!("OIT buffer creation failed!");
+        }
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+        OitBuffer {
+            framebuffer,
+            accumulation_texture,
+            revealage_texture,
             width,
             height,
         }
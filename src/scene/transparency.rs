@@ -0,0 +1,88 @@
+use glow::*;
+
+use super::{graph::SceneGraph, texture::OitBuffer, vao::VAO};
+use crate::shader::Shader;
+
+/// Draws the transparent queue without sorting, by accumulating weighted colors
+/// and compositing their average over the opaque image afterwards
+pub struct WeightedBlendedOit {
+    buffer: OitBuffer,
+    composite_shader: Shader,
+    canvas: VAO,
+}
+
+impl WeightedBlendedOit {
+    pub unsafe fn new(gl: &glow::Context, width: i32, height: i32) -> WeightedBlendedOit {
+        WeightedBlendedOit {
+            buffer: OitBuffer::new(gl, width, height),
+            composite_shader: Shader::new(gl, "res/shaders/post.vert", "res/shaders/oit.frag"),
+            canvas: VAO::square(gl),
+        }
+    }
+
+    /// Render the transparent nodes on top of the opaque scene already in the target framebuffer,
+    /// which must be the same size as this buffer
+    pub unsafe fn render(
+        &self,
+        gl: &glow::Context,
+        scene_graph: &SceneGraph,
+        target: NativeFramebuffer,
+        view_transform: &glm::Mat4,
+        camera_position: &glm::Vec3,
+    ) {
+        let (_, transparent) = scene_graph.render_queues(scene_graph.root, view_transform, 1.);
+        if transparent.is_empty() {
+            return;
+        }
+        let (width, height) = (self.buffer.width, self.buffer.height);
+
+        // Test against the opaque depth (resolving it if the target is multisampled)
+        gl.bind_framebuffer(glow::READ_FRAMEBUFFER, Some(target));
+        gl.bind_framebuffer(glow::DRAW_FRAMEBUFFER, Some(self.buffer.framebuffer));
+        gl.blit_framebuffer(
+            0,
+            0,
+            width,
+            height,
+            0,
+            0,
+            width,
+            height,
+            glow::DEPTH_BUFFER_BIT,
+            glow::NEAREST,
+        );
+
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.buffer.framebuffer));
+        gl.viewport(0, 0, width, height);
+        gl.clear_buffer_f32_slice(glow::COLOR, 0, &[0., 0., 0., 0.]);
+        gl.clear_buffer_f32_slice(glow::COLOR, 1, &[1., 1., 1., 1.]);
+        // Sum up the colors, multiply together what is let through
+        gl.blend_func_draw_buffer(0, glow::ONE, glow::ONE);
+        gl.blend_func_draw_buffer(1, glow::ZERO, glow::ONE_MINUS_SRC_COLOR);
+
+        let program = scene_graph.final_shader.unwrap();
+        gl.uniform_1_i32(gl.get_uniform_location(program, "weighted_oit").as_ref(), 1);
+        scene_graph.draw_transparent(
+            gl,
+            program,
+            &transparent,
+            view_transform,
+            camera_position,
+            true,
+        );
+        gl.uniform_1_i32(gl.get_uniform_location(program, "weighted_oit").as_ref(), 0);
+        gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+
+        // Average color, covering as much as isn't revealed
+        gl.bind_framebuffer(glow::FRAMEBUFFER, Some(target));
+        gl.disable(glow::DEPTH_TEST);
+        self.composite_shader.activate(gl);
+        gl.active_texture(glow::TEXTURE0);
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.buffer.accumulation_texture));
+        gl.active_texture(glow::TEXTURE1);
+        gl.bind_texture(glow::TEXTURE_2D, Some(self.buffer.revealage_texture));
+        self.canvas.draw(gl);
+        gl.enable(glow::DEPTH_TEST);
+        gl.use_program(Some(program));
+    }
+}
@@ -6,6 +6,27 @@ pub struct Shader {
     pub program: NativeProgram,
 }
 
+/// Paste in the contents of `#include "file"` lines, relative to the including file,
+/// so shaders can share code
+fn resolve_includes(source: &str, path: &Path) -> String {
+    source
+        .lines()
+        .map(|line| match line.trim().strip_prefix("#include") {
+            Some(include) => {
+                let include_path = path
+                    .parent()
+                    .unwrap_or(Path::new(""))
+                    .join(include.trim().trim_matches('"'));
+                let included = std::fs::read_to_string(&include_path)
+                    .expect(&format!("No shader at {}", include_path.display()));
+                resolve_includes(&included, &include_path)
+            }
+            None => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 unsafe fn read_and_compile_shader(
     gl: &glow::Context,
     shader_path: &str,
@@ -16,7 +37,7 @@ unsafe fn read_and_compile_shader(
     let shader = gl
         .create_shader(shader_type)
         .expect("Could not create shader");
-    gl.shader_source(shader, &source);
+    gl.shader_source(shader, &resolve_includes(&source, path));
     gl.compile_shader(shader);
     if !gl.get_shader_compile_status(shader) {
         panic!(